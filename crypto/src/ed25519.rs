@@ -1,17 +1,18 @@
 use base58::FromBase58;
 use ed25519_compact::{PublicKey, Signature};
 
+/// `false` for a public key or signature that isn't valid base58 of the
+/// right length.
 pub fn verify(public_key: String, signature: String, message: String) -> bool {
-    let p_key_decoded = public_key.clone().from_base58().unwrap();
-    let sign_decoded = signature.clone().from_base58().unwrap();
+    let pk: [u8; 32] = match public_key.from_base58().ok().and_then(|pk| pk.try_into().ok()) {
+        Some(pk) => pk,
+        None => return false,
+    };
 
-    let pk: [u8; 32] = p_key_decoded
-        .try_into()
-        .expect("Error: public_key with incorrect length");
-
-    let sign: [u8; 64] = sign_decoded
-        .try_into()
-        .expect("Error: Sign with incorrect length");
+    let sign: [u8; 64] = match signature.from_base58().ok().and_then(|sign| sign.try_into().ok()) {
+        Some(sign) => sign,
+        None => return false,
+    };
 
     let p_key = PublicKey::new(pk);
 
@@ -1,10 +1,11 @@
 mod ed25519;
 mod secp256k1;
-mod types;
+pub mod types;
 
+use base58::FromBase58;
 use ed25519_compact::KeyPair;
 use ed25519_compact::{Noise, SecretKey};
-use types::Ed25519KeyPair;
+use types::{Ed25519KeyPair, ThresholdResult};
 use std::ops::Deref;
 
 use ed25519::verify as verify_ed25519;
@@ -36,6 +37,63 @@ pub fn verify(public_key: String, signature: String, message: String, enc: Strin
   verify
 }
 
+/// Verify `signatures` of `message`, given as `(public_key, signature)` pairs
+/// of either key type, against a `threshold` of `public_keys`. A key counts
+/// once however many of its signatures are given.
+pub fn verify_threshold(
+  public_keys: &[String],
+  threshold: usize,
+  signatures: &[(String, String)],
+  message: &str,
+) -> ThresholdResult {
+  let mut valid: Vec<String> = Vec::new();
+  let mut invalid: Vec<String> = Vec::new();
+
+  for (signer, signature) in signatures {
+      let key = public_keys.iter().find(|key| same_key(key, signer));
+      let verified = key.is_some_and(|key| {
+          verify(key.clone(), signature.clone(), message.to_string(), get_public_key_type(key))
+      });
+
+      match key {
+          Some(key) if verified => {
+              if !valid.contains(key) {
+                  valid.push(key.clone());
+              }
+          },
+          _ => invalid.push(signer.clone()),
+      }
+  }
+
+  valid.sort_by_key(|key| public_keys.iter().position(|k| k == key));
+
+  ThresholdResult {
+      met: threshold > 0 && valid.len() >= threshold,
+      valid,
+      invalid,
+      threshold,
+  }
+}
+
+/// Whether `public_key` is a key `verify` can check signatures for: a `0x`
+/// prefixed secp256k1 address of 20 hex encoded bytes, or 32 base58 encoded
+/// ed25519 bytes.
+pub fn valid_public_key(public_key: &str) -> bool {
+  match public_key.strip_prefix("0x") {
+      Some(address) => address.len() == 40 && address.chars().all(|c| c.is_ascii_hexdigit()),
+      None => public_key.from_base58().map_or(false, |pk| pk.len() == 32),
+  }
+}
+
+/// Whether two public keys are the same, secp256k1 addresses ignoring case.
+pub fn same_key(a: &str, b: &str) -> bool {
+  if get_public_key_type(a) == DEFAULT_ENC {
+      a.eq_ignore_ascii_case(b)
+  } else {
+      a == b
+  }
+}
+
 pub fn sign(message: String, private_key: String) -> String {
   let pk_key_decoded = base64::decode(private_key).unwrap();
 
@@ -49,9 +107,102 @@ pub fn sign(message: String, private_key: String) -> String {
 }
 
 pub fn get_public_key_type(public_key: &str) -> String {
-  if &public_key[..2] == "0x" {
+  if public_key.starts_with("0x") {
       return "secp256k1".to_string();
   } else {
       return "ed25519".to_string();
   }
-}
\ No newline at end of file
+}
+
+/// Public key of the ed25519 key seeded with `[seed; 32]` and its signature
+/// of `message`.
+#[cfg(test)]
+fn test_signature(seed: u8, message: &str) -> (String, String) {
+  use base58::ToBase58;
+  use ed25519_compact::Seed;
+
+  let pair = KeyPair::from_seed(Seed::new([seed; 32]));
+  (pair.pk[..].to_base58(), pair.sk.sign(message, None)[..].to_base58())
+}
+
+#[test]
+fn test_verify_threshold() {
+  let (a, sig_a) = test_signature(1, "m");
+  let (b, sig_b) = test_signature(2, "m");
+  let (c, _) = test_signature(3, "m");
+  let keys = vec![a.clone(), b.clone(), c.clone()];
+
+  let result = verify_threshold(&keys, 2, &[(b.clone(), sig_b.clone()), (a.clone(), sig_a.clone())], "m");
+  assert!(result.met);
+  assert_eq!(result.valid, vec![a.clone(), b.clone()]);
+  assert!(result.invalid.is_empty());
+
+  let result = verify_threshold(&keys, 2, &[(a.clone(), sig_a.clone())], "m");
+  assert!(!result.met);
+  assert_eq!(result.valid, vec![a.clone()]);
+
+  // A key signing twice counts once.
+  let result = verify_threshold(&keys, 2, &[(a.clone(), sig_a.clone()), (a.clone(), sig_a.clone())], "m");
+  assert!(!result.met);
+  assert_eq!(result.valid, vec![a.clone()]);
+
+  // Signatures of another message, by keys outside the policy or by the
+  // wrong key don't count.
+  let (outsider, sig_outsider) = test_signature(4, "m");
+  let (_, sig_other) = test_signature(2, "other");
+  let result = verify_threshold(&keys, 1, &[(outsider.clone(), sig_outsider), (b.clone(), sig_other), (c.clone(), sig_a)], "m");
+  assert!(!result.met);
+  assert_eq!(result.invalid, vec![outsider, b, c]);
+
+  assert!(!verify_threshold(&keys, 0, &[], "m").met);
+}
+
+#[test]
+fn test_verify_threshold_secp256k1() {
+  let (address, signature) = secp256k1::test_signature(1, "m");
+  let keys = vec![format!("0x{}", address[2..].to_uppercase())];
+
+  let result = verify_threshold(&keys, 1, &[(address.clone(), signature)], "m");
+  assert!(result.met);
+  assert_eq!(result.valid, keys);
+}
+
+#[test]
+fn test_invalid_input() {
+  let (a, sig_a) = test_signature(1, "m");
+  let (address, sig_address) = secp256k1::test_signature(1, "m");
+
+  for (key, signature) in [
+    (a.clone(), String::new()),
+    (a.clone(), "not base58!".to_string()),
+    (a.clone(), sig_a[..10].to_string()),
+    (String::new(), sig_a.clone()),
+    ("0OIl".to_string(), sig_a.clone()),
+    (a.clone(), sig_address.clone()),
+    (address.clone(), String::new()),
+    (address.clone(), "0x".to_string()),
+    (address.clone(), "0xzz".to_string()),
+    (address.clone(), format!("0x{}", "00".repeat(65))),
+    (address.clone(), format!("{}ff", &sig_address[..sig_address.len() - 2])),
+    (address.clone(), sig_address[..20].to_string()),
+    (address.clone(), sig_a.clone()),
+  ] {
+    let enc = get_public_key_type(&key);
+    assert!(!verify(key.clone(), signature.clone(), "m".to_string(), enc), "{} {}", key, signature);
+    assert!(!verify_threshold(&[key.clone()], 1, &[(key, signature)], "m").met);
+  }
+}
+
+#[test]
+fn test_same_key() {
+  let address = "0x00000000000000000000000000000000000000aB";
+
+  assert!(same_key(address, "0x00000000000000000000000000000000000000Ab"));
+  assert!(same_key(address, &address.to_lowercase()));
+  assert!(!same_key(address, "0x00000000000000000000000000000000000000ac"));
+
+  // ed25519 keys are base58, where case matters.
+  let (key, _) = test_signature(1, "");
+  assert!(same_key(&key, &key));
+  assert!(!same_key(&key, &key.to_lowercase()));
+}
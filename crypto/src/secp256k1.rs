@@ -39,8 +39,13 @@ fn keccak256_hash(bytes: &[u8]) -> Vec<u8> {
     resp.iter().cloned().collect()
 }
 
+/// `false` for a signature that isn't `0x` followed by 65 hex encoded bytes
+/// or doesn't recover to a key.
 pub fn verify(public_key: String, signature: String, message: String) -> bool {
-    let sign_decoded = hex::decode(signature[2..].to_string()).unwrap();
+    let sign_decoded = match signature.strip_prefix("0x").and_then(|sign| hex::decode(sign).ok()) {
+        Some(sign) if sign.len() == 65 => sign,
+        _ => return false,
+    };
 
     let sign: [u8; 64] = sign_decoded[..64]
         .try_into()
@@ -49,18 +54,34 @@ pub fn verify(public_key: String, signature: String, message: String) -> bool {
     let message_decoded = eth_message(message);
 
     let ctx_message = Message::parse(&message_decoded);
-    let ctx_sig = Signature::parse_standard(&sign).expect("signature is valid");
-    let recovery_id = sign_decoded[64] as i32;
+    let ctx_sig = match Signature::parse_standard(&sign) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    let recovery_id = match RecoveryId::parse_rpc(sign_decoded[64]) {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
 
-    let pubkey = recover(
-        &ctx_message,
-        &ctx_sig,
-        &RecoveryId::parse_rpc(recovery_id as u8).unwrap(),
-    )
-    .unwrap();
+    let pubkey = match recover(&ctx_message, &ctx_sig, &recovery_id) {
+        Ok(pubkey) => pubkey,
+        Err(_) => return false,
+    };
 
     // log::info!("pubkey: {:?}", pubkey.serialize());
     log::info!("address: {:?}", public_key_to_address(pubkey.serialize()));
 
     public_key.to_lowercase() == public_key_to_address(pubkey.serialize()).to_lowercase()
 }
+
+/// Address of the secp256k1 key `[seed; 32]` and its signature of `message`.
+#[cfg(test)]
+pub fn test_signature(seed: u8, message: &str) -> (String, String) {
+    let sk = libsecp256k1::SecretKey::parse(&[seed; 32]).unwrap();
+    let pk = libsecp256k1::PublicKey::from_secret_key(&sk);
+    let (signature, recovery_id) = libsecp256k1::sign(&Message::parse(&eth_message(message.to_string())), &sk);
+
+    let mut sign = signature.serialize().to_vec();
+    sign.push(recovery_id.serialize() + 27);
+    (public_key_to_address(pk.serialize()), format!("0x{}", hex::encode(sign)))
+}
@@ -2,4 +2,14 @@
 pub struct Ed25519KeyPair {
     pub pk: String,
     pub sk: String,
-}
\ No newline at end of file
+}
+/// Outcome of `verify_threshold`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdResult {
+    /// Keys of the policy with a valid signature, in policy order.
+    pub valid: Vec<String>,
+    /// Signers whose signature didn't verify or who aren't in the policy.
+    pub invalid: Vec<String>,
+    pub threshold: usize,
+    pub met: bool,
+}
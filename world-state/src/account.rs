@@ -0,0 +1,99 @@
+use std::env;
+
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::{get_trie_results, record::Record, types::TrieResult};
+
+/// The last nonce a key used, see `Transaction::nonce`.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct Account {
+  pub public_key: String,
+  pub nonce: u64,
+}
+
+impl Record for Account {
+  const CONFIG_PREFIX: &'static str = "ACCOUNT";
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.public_key.as_bytes().to_vec()
+  }
+
+  /// Nonces only advance through `insert_tx`.
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Accounts change only through insert_tx".to_string())
+  }
+}
+
+impl Account {
+  /// `get_nonce <public_key>`
+  pub fn get_nonce() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let public_key = match args.get(2) {
+      Some(public_key) => public_key.clone(),
+      None => return TrieResult { success: false, result: Some("Missing public key".to_string()) },
+    };
+
+    let nonce = Self::find(&public_key).map_or(0, |account| account.nonce);
+
+    TrieResult {
+      success: true,
+      result: Some(json!({ "public_key": public_key, "nonce": nonce, "next_nonce": nonce + 1 }).to_string()),
+    }
+  }
+
+  /// The account of `public_key` after using `nonce`, which must be the one
+  /// after its last. Errors with `nonce_required`, `nonce_replayed` or
+  /// `nonce_gap` and the nonce expected otherwise.
+  pub fn advance(public_key: &str, nonce: u64) -> Result<Account, TrieResult> {
+    let expected = Self::find(public_key).map_or(0, |account| account.nonce) + 1;
+
+    if nonce == expected {
+      return Ok(Account { public_key: public_key.to_string(), nonce });
+    }
+
+    let (code, message) = if nonce == 0 {
+      ("nonce_required", format!("Transactions of {} need a nonce", public_key))
+    } else if nonce < expected {
+      ("nonce_replayed", format!("Nonce {} of {} was already used", nonce, public_key))
+    } else {
+      ("nonce_gap", format!("Nonce {} of {} skips nonce {}", nonce, public_key, expected))
+    };
+
+    Err(TrieResult::error(code, message, json!({ "public_key": public_key, "nonce": nonce, "expected_nonce": expected })))
+  }
+
+  pub fn find(public_key: &str) -> Option<Account> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(public_key.to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|account| account.public_key == public_key)
+  }
+}
+
+#[test]
+fn test_advance() {
+  use crate::{db, state::StateCommit};
+
+  db::scratch(|| {
+    let code = |nonce: u64| Account::advance("k", nonce).unwrap_err().code();
+
+    assert_eq!(code(0).as_deref(), Some("nonce_required"));
+    assert_eq!(code(2).as_deref(), Some("nonce_gap"));
+    let account = Account::advance("k", 1).unwrap();
+    assert_eq!((account.public_key.as_str(), account.nonce), ("k", 1));
+
+    // Nothing is stored until the caller commits the account.
+    assert!(Account::find("k").is_none());
+    let mut commit = StateCommit::new();
+    commit.put(&[account]);
+    commit.commit().unwrap();
+
+    assert_eq!(code(1).as_deref(), Some("nonce_replayed"));
+    assert_eq!(code(3).as_deref(), Some("nonce_gap"));
+    assert_eq!(Account::advance("k", 2).unwrap().nonce, 2);
+    assert_eq!(Account::advance("other", 1).unwrap().nonce, 1);
+  });
+}
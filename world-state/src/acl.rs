@@ -0,0 +1,247 @@
+use std::env;
+
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::{
+  auth::{signatures_from_args, signed_message, verify_owner},
+  db::WriteLock,
+  get_trie_results,
+  metacontract::MetaContract,
+  ownership::Owner,
+  record::{insert_records, Record},
+  types::TrieResult,
+  utils::{flag_value, now_ms},
+};
+
+/// Roles a grant can hold. `writer` may write every record of the program,
+/// the others only records of the trie they name.
+pub const ROLES: [&str; 4] = ["writer", "metadata", "cron", "tx"];
+
+/// Permission for `grantee` to write records of `program_id` on the owner's
+/// behalf, see `auth::authorize`.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct Grant {
+  pub program_id: String,
+  pub grantee: String,
+  /// One of `ROLES`, empty once revoked.
+  pub role: String,
+  /// When the grant lapses in ms, 0 for never.
+  pub expires_at: u64,
+  /// Number of changes to the grant, signed with each change so that a
+  /// signature can't be replayed.
+  pub seq: u64,
+  pub updated_at: u64,
+}
+
+impl Record for Grant {
+  const CONFIG_PREFIX: &'static str = "ACL";
+
+  fn primary_key(&self) -> Vec<u8> {
+    Self::key(&self.program_id, &self.grantee)
+  }
+
+  /// Grants are only written by `grant` and `revoke`.
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Grants change only through grant and revoke".to_string())
+  }
+}
+
+impl Grant {
+  /// `grant <program_id> <grantee> <role> [--expires-at <ms>] --signature <owner sig>`
+  ///
+  /// A multisig owner signs with `--signatures`.
+  pub fn grant() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let (program_id, grantee, role) = match (args.get(2), args.get(3), args.get(4)) {
+      (Some(program_id), Some(grantee), Some(role)) => (program_id.clone(), grantee.clone(), role.clone()),
+      _ => return TrieResult { success: false, result: Some("Usage: grant <program_id> <grantee> <role>".to_string()) },
+    };
+
+    if !ROLES.contains(&role.as_str()) {
+      return TrieResult { success: false, result: Some(format!("Unknown role {:?}, use one of {:?}", role, ROLES)) };
+    }
+
+    let expires_at = match flag_value(&args, "--expires-at").map(|at| at.parse::<u64>()) {
+      Some(Ok(at)) => at,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--expires-at expects milliseconds".to_string()) },
+      None => 0,
+    };
+
+    Self::change(&args, program_id, grantee, role, expires_at)
+  }
+
+  /// `revoke <program_id> <grantee> --signature <owner sig>`
+  pub fn revoke() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let (program_id, grantee) = match (args.get(2), args.get(3)) {
+      (Some(program_id), Some(grantee)) => (program_id.clone(), grantee.clone()),
+      _ => return TrieResult { success: false, result: Some("Usage: revoke <program_id> <grantee>".to_string()) },
+    };
+
+    match Self::find(&program_id, &grantee) {
+      Some(grant) if !grant.role.is_empty() => Self::change(&args, program_id, grantee, "".to_string(), 0),
+      _ => TrieResult { success: false, result: Some("Record not found".to_string()) },
+    }
+  }
+
+  /// `list_grants <program_id> [--all] [--now <ms>]`
+  ///
+  /// Grants in force, or every grant ever made with `--all`.
+  pub fn list_grants() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let program_id = match args.get(2) {
+      Some(program_id) => program_id.clone(),
+      None => return TrieResult { success: false, result: Some("Missing program id".to_string()) },
+    };
+
+    let now = match flag_value(&args, "--now").map(|now| now.parse::<u64>()) {
+      Some(Ok(now)) => now,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--now expects milliseconds".to_string()) },
+      None => now_ms(),
+    };
+    let all = args.iter().any(|arg| arg == "--all");
+
+    let grants: Vec<Grant> = get_trie_results(&Self::trie_name(), &Self::db_path(), Some(format!("{}/", program_id)))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .filter(|grant| grant.program_id == program_id && (all || grant.is_active(now)))
+      .collect();
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&grants).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Write the grant of `grantee` with `role`, signed by the program's owner.
+  fn change(args: &[String], program_id: String, grantee: String, role: String, expires_at: u64) -> TrieResult {
+    let owner = match MetaContract::find(&program_id) {
+      Some(contract) => Owner { program_id: program_id.clone(), public_key: contract.public_key }.resolve(),
+      None => return TrieResult { success: false, result: Some(format!("Program {} has no metacontract", program_id)) },
+    };
+
+    let _lock = WriteLock::acquire();
+
+    let seq = Self::find(&program_id, &grantee).map_or(1, |grant| grant.seq + 1);
+    let grant = Grant { program_id, grantee, role, expires_at, seq, updated_at: now_ms() };
+
+    if let Err(e) = grant.validate() {
+      return TrieResult { success: false, result: Some(e) };
+    }
+
+    let message = grant.message();
+    let authorized = signatures_from_args(args, "")
+      .and_then(|signed| verify_owner(&owner.public_key, &signed, &message, json!({ "signed_message": message })));
+
+    if let Err(e) = authorized {
+      return e;
+    }
+
+    if let Err(e) = insert_records(&[grant.clone()]) {
+      return e;
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&grant).unwrap_or("".to_string())),
+    }
+  }
+
+  /// `program_id` and `grantee` are joined by `/` in the key, so neither may
+  /// hold one: the grantee must be a public key, the role one of `ROLES` or
+  /// empty.
+  fn validate(&self) -> Result<(), String> {
+    if self.program_id.is_empty() || self.program_id.contains('/') {
+      return Err(format!("Invalid program id {:?}", self.program_id));
+    }
+    if !crypto::valid_public_key(&self.grantee) {
+      return Err(format!("Grantee {:?} is not a public key", self.grantee));
+    }
+    if !self.role.is_empty() && !ROLES.contains(&self.role.as_str()) {
+      return Err(format!("Unknown role {:?}, use one of {:?}", self.role, ROLES));
+    }
+    Ok(())
+  }
+
+  /// What the owner signs to grant or, with an empty role, revoke.
+  pub fn message(&self) -> String {
+    signed_message(&json!({
+      "method": if self.role.is_empty() { "revoke" } else { "grant" },
+      "program_id": self.program_id,
+      "grantee": self.grantee,
+      "role": self.role,
+      "expires_at": self.expires_at,
+      "seq": self.seq,
+    }))
+  }
+
+  pub fn is_active(&self, now: u64) -> bool {
+    !self.role.is_empty() && (self.expires_at == 0 || now < self.expires_at)
+  }
+
+  /// Whether the grant lets its grantee write records of `trie_key`.
+  pub fn covers(&self, trie_key: &str) -> bool {
+    self.role == trie_key || (self.role == "writer" && ROLES.contains(&trie_key))
+  }
+
+  /// Whether `grantee` may write records of `trie_key` for `program_id` now.
+  pub fn allows(program_id: &str, grantee: &str, trie_key: &str) -> bool {
+    Self::find(program_id, grantee).map_or(false, |grant| grant.is_active(now_ms()) && grant.covers(trie_key))
+  }
+
+  fn find(program_id: &str, grantee: &str) -> Option<Grant> {
+    let key = Self::key(program_id, grantee);
+
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(String::from_utf8_lossy(&key).to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|grant| grant.program_id == program_id && grant.grantee == grantee)
+  }
+
+  fn key(program_id: &str, grantee: &str) -> Vec<u8> {
+    format!("{}/{}", program_id, grantee).into_bytes()
+  }
+}
+
+#[test]
+fn test_grant() {
+  let grant = Grant {
+    program_id: "p".to_string(),
+    grantee: "k".to_string(),
+    role: "cron".to_string(),
+    expires_at: 2000,
+    seq: 1,
+    updated_at: 0,
+  };
+
+  assert!(grant.is_active(1999) && !grant.is_active(2000));
+  assert!(grant.covers("cron") && !grant.covers("metadata"));
+  assert!(Grant { role: "writer".to_string(), ..grant.clone() }.covers("tx"));
+  assert!(!Grant { role: "writer".to_string(), ..grant.clone() }.covers("metacontract"));
+  assert!(!Grant { role: "".to_string(), ..grant.clone() }.is_active(0));
+  assert_eq!(
+    Grant { role: "".to_string(), expires_at: 0, ..grant }.message(),
+    r#"{"expires_at":0,"grantee":"k","method":"revoke","program_id":"p","role":"","seq":1}"#,
+  );
+}
+
+#[test]
+fn test_grant_validate() {
+  use crate::auth::test_signature;
+
+  let (grantee, _) = test_signature(2, "");
+  let grant = Grant { program_id: "a".to_string(), grantee, role: "metadata".to_string(), expires_at: 0, seq: 1, updated_at: 0 };
+
+  assert!(grant.validate().is_ok());
+  assert!(Grant { role: String::new(), ..grant.clone() }.validate().is_ok());
+  assert!(Grant { program_id: "a/b".to_string(), ..grant.clone() }.validate().is_err());
+  assert!(Grant { program_id: String::new(), ..grant.clone() }.validate().is_err());
+  assert!(Grant { grantee: "b/c".to_string(), ..grant.clone() }.validate().is_err());
+  assert!(Grant { grantee: format!("{}/c", grant.grantee), ..grant.clone() }.validate().is_err());
+  assert!(Grant { role: "owner".to_string(), ..grant }.validate().is_err());
+}
@@ -0,0 +1,319 @@
+use std::env;
+
+use serde_json::{json, Value};
+
+use crate::{
+  auth,
+  cron::{Cron, CRON_ACTIVE},
+  db::WriteLock,
+  get_trie_results,
+  metacontract::MetaContract,
+  metadata::Metadata,
+  ownership::Owner,
+  record::Record,
+  state::StateCommit,
+  transaction::{Transaction, TX_APPLIED, TX_FAILED, TX_PENDING},
+  transaction_receipt::TransactionReceipt,
+  types::TrieResult,
+  utils::now_ms,
+};
+
+/// Stages the records `tx` writes and returns the receipt data, or why the
+/// transaction can't be applied.
+type Handler = fn(&Transaction, &mut StateCommit) -> Result<Value, String>;
+
+/// Handlers by `Transaction::method`.
+pub static HANDLERS: &[(&str, Handler)] = &[
+  ("set_metadata", set_metadata),
+  ("register_metacontract", register_metacontract),
+  ("schedule_cron", schedule_cron),
+];
+
+pub struct Apply;
+
+impl Apply {
+  /// `apply_tx <hash>`
+  pub fn apply_tx() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let tx = match args.get(2).and_then(|hash| Transaction::find(hash)) {
+      Some(tx) => tx,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
+
+    if tx.status != TX_PENDING || tx.expired(now_ms()) {
+      return TrieResult { success: false, result: Some(format!("Transaction {} is not pending", tx.hash)) };
+    }
+
+    match Self::apply(tx) {
+      Ok(receipt) => TrieResult { success: true, result: Some(serde_json::to_string(&receipt).unwrap_or("".to_string())) },
+      Err(e) => e,
+    }
+  }
+
+  /// `apply_pending`
+  ///
+  /// Applies the pending transactions in timestamp and nonce order and
+  /// returns their receipts.
+  pub fn apply_pending() -> TrieResult {
+    let now = now_ms();
+
+    let mut pending: Vec<Transaction> = get_trie_results(&Transaction::trie_name(), &Transaction::db_path(), None)
+      .iter()
+      .filter_map(|val| Transaction::decode_record(val).ok())
+      .filter(|tx| tx.status == TX_PENDING && !tx.expired(now))
+      .collect();
+    pending.sort_by_key(|tx| (tx.timestamp, tx.nonce));
+
+    let receipts: Vec<TransactionReceipt> = match pending.into_iter().map(Self::apply).collect() {
+      Ok(receipts) => receipts,
+      Err(e) => return e,
+    };
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&receipts).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Run the handler of `tx` and store what it wrote together with the new
+  /// status and receipt of `tx`, or only those when it fails. Errs when the
+  /// commit fails, leaving `tx` pending.
+  pub fn apply(tx: Transaction) -> Result<TransactionReceipt, TrieResult> {
+    let _lock = WriteLock::acquire();
+    let mut commit = StateCommit::new();
+
+    let outcome = match HANDLERS.iter().find(|(method, _)| *method == tx.method) {
+      Some((_, handler)) => handler(&tx, &mut commit),
+      None => Err(format!("Unknown method {:?}", tx.method)),
+    };
+
+    let (status, data, error_text) = match outcome {
+      Ok(data) => (TX_APPLIED, data.to_string(), String::new()),
+      Err(e) => {
+        commit = StateCommit::new();
+        (TX_FAILED, String::new(), e)
+      },
+    };
+
+    let receipt = TransactionReceipt {
+      hash: tx.hash.clone(),
+      program_id: tx.program_id.clone(),
+      status,
+      timestamp: now_ms(),
+      error_text,
+      data,
+    };
+
+    commit.put(&[Transaction { status, ..tx }]);
+    commit.put(&[receipt.clone()]);
+    commit.commit()?;
+
+    Ok(receipt)
+  }
+}
+
+/// Appends version `version` with cid `data` to the metadata of `program_id`
+/// and `data_key`. `data` may also be `{"cid": .., "loose": 1}`; loose
+/// metadata may be written by anyone while the latest version is loose too.
+fn set_metadata(tx: &Transaction, commit: &mut StateCommit) -> Result<Value, String> {
+  let chain = Metadata::chain(&tx.program_id, &tx.data_key);
+  let latest = chain.last().and_then(|m| m.owner());
+
+  let (cid, loose) = match serde_json::from_str::<Value>(&tx.data) {
+    Ok(Value::Object(fields)) => (
+      fields.get("cid").and_then(Value::as_str).ok_or("Metadata needs a cid".to_string())?.to_string(),
+      match fields.get("loose") {
+        Some(loose) => loose.as_u64().ok_or("loose must be a number".to_string())?,
+        None => 0,
+      },
+    ),
+    _ => (tx.data.clone(), 0),
+  };
+
+  let public_key = if loose != 0 && latest.is_none() {
+    signer(tx)?
+  } else {
+    owner_key(tx, latest, &Metadata::trie_name())?
+  };
+
+  let mut metadata = Metadata {
+    hash: tx.hash.clone(),
+    data_key: tx.data_key.clone(),
+    program_id: tx.program_id.clone(),
+    alias: tx.alias.clone(),
+    chain_id: tx.chain_id.clone(),
+    token_address: tx.token_address.clone(),
+    token_id: tx.token_id.clone(),
+    version: tx.version.clone(),
+    cid,
+    public_key,
+    loose,
+    seq: 0,
+    previous: String::new(),
+  };
+  metadata.append_to(&chain)?;
+
+  commit.put(&[metadata.clone()]);
+  Ok(json!(metadata))
+}
+
+/// Registers `program_id` with the metacontract cid `mcdata`, or updates the
+/// cid of a program registered by the same owner.
+fn register_metacontract(tx: &Transaction, commit: &mut StateCommit) -> Result<Value, String> {
+  let existing = MetaContract::find(&tx.program_id);
+
+  let mut contract = match existing {
+    Some(existing) => {
+      let owner = Owner { program_id: tx.program_id.clone(), public_key: existing.public_key.clone() }.resolve();
+      if !auth::may_write(&owner, &signer(tx)?, &MetaContract::trie_name()) {
+        return Err(format!("{} is already registered to {}", tx.program_id, owner.public_key));
+      }
+      MetaContract { cid: tx.mcdata.clone(), ..existing }
+    },
+    None => MetaContract {
+      program_id: tx.program_id.clone(),
+      public_key: signer(tx)?,
+      cid: tx.mcdata.clone(),
+      public_keys: Vec::new(),
+      threshold: 0,
+    },
+  };
+  contract.prepare()?;
+
+  commit.put(&[contract.clone()]);
+  Ok(json!(contract))
+}
+
+/// Schedules the job given as JSON in `data`, e.g.
+/// `{"cid": "..", "schedule": "@every 1h", "job_id": "sync"}`. Its first
+/// run follows the transaction's `timestamp`, so a replay schedules it alike.
+fn schedule_cron(tx: &Transaction, commit: &mut StateCommit) -> Result<Value, String> {
+  let mut spec: Value = serde_json::from_str(&tx.data).map_err(|e| format!("Error decoding cron: {}", e))?;
+
+  let fields = spec.as_object_mut().ok_or("Cron must be a JSON object".to_string())?;
+  fields.insert("program_id".to_string(), json!(tx.program_id));
+  fields.insert("public_key".to_string(), json!(""));
+  fields.insert("status".to_string(), json!(CRON_ACTIVE));
+  fields.entry("epoch").or_insert(json!(0));
+
+  let mut cron: Cron = serde_json::from_value(spec).map_err(|e| format!("Error decoding cron: {}", e))?;
+
+  let replaced = Cron::find(&cron.job_key()).and_then(|c| c.owner());
+  cron.public_key = owner_key(tx, replaced, &Cron::trie_name())?;
+  cron.set_first_run(tx.timestamp)?;
+  cron.prepare()?;
+
+  commit.put(&[cron.clone()]);
+  Ok(json!(cron))
+}
+
+/// The key owning what `tx` writes to `trie_key`: the owner of the record it
+/// replaces or else of its program, which the signer of `tx` must be allowed
+/// to write for. The signer for a program nobody owns yet.
+fn owner_key(tx: &Transaction, replaced: Option<Owner>, trie_key: &str) -> Result<String, String> {
+  let signer = signer(tx)?;
+  let owner = replaced.or_else(|| {
+    MetaContract::find(&tx.program_id).map(|contract| Owner { program_id: tx.program_id.clone(), public_key: contract.public_key })
+  });
+
+  match owner.map(Owner::resolve) {
+    Some(owner) if auth::may_write(&owner, &signer, trie_key) => Ok(owner.public_key),
+    Some(owner) => Err(format!("{} may not write {} for {}, owned by {}", signer, trie_key, tx.program_id, owner.public_key)),
+    None => Ok(signer),
+  }
+}
+
+/// The key `insert_tx` verified the signatures of `tx` for.
+fn signer(tx: &Transaction) -> Result<String, String> {
+  if tx.signer.is_empty() {
+    return Err(format!("Transaction {} was stored without a verified signer", tx.hash));
+  }
+  Ok(tx.signer.clone())
+}
+
+/// A pending transaction calling `method` on program `p`, signed by `signer`.
+#[cfg(test)]
+pub fn test_tx(hash: &str, method: &str, signer: &str, data: &str) -> Transaction {
+  Transaction {
+    hash: hash.to_string(),
+    method: method.to_string(),
+    program_id: "p".to_string(),
+    data_key: "d".to_string(),
+    data: data.to_string(),
+    public_key: signer.to_string(),
+    alias: String::new(),
+    timestamp: 1,
+    chain_id: String::new(),
+    token_address: String::new(),
+    token_id: String::new(),
+    version: "1".to_string(),
+    mcdata: String::new(),
+    status: TX_PENDING,
+    nonce: 0,
+    expires_at: 0,
+    signer: signer.to_string(),
+  }
+}
+
+#[test]
+fn test_apply_writes_once() {
+  use crate::{db, ROOT_WRITES};
+
+  db::scratch(|| {
+    let (owner, _) = auth::test_signature(1, "");
+
+    let writes = ROOT_WRITES.with(|writes| writes.get());
+    let receipt = Apply::apply(test_tx("h1", "set_metadata", &owner, r#"{"cid":"c1","loose":1}"#)).unwrap();
+    assert_eq!((receipt.status, ROOT_WRITES.with(|writes| writes.get()) - writes), (TX_APPLIED, 1));
+
+    let chain = Metadata::chain("p", "d");
+    assert_eq!((chain.len(), chain[0].cid.as_str(), chain[0].loose), (1, "c1", 1));
+    assert_eq!(Transaction::find("h1").unwrap().status, TX_APPLIED);
+    assert_eq!(TransactionReceipt::find("h1").unwrap().status, TX_APPLIED);
+  });
+}
+
+#[test]
+fn test_apply_rejects_other_signers() {
+  use crate::db;
+
+  db::scratch(|| {
+    let (owner, _) = auth::test_signature(1, "");
+    let (other, _) = auth::test_signature(2, "");
+
+    assert_eq!(Apply::apply(test_tx("h1", "register_metacontract", &owner, "")).unwrap().status, TX_APPLIED);
+
+    let receipt = Apply::apply(test_tx("h2", "set_metadata", &other, "c1")).unwrap();
+    assert_eq!(receipt.status, TX_FAILED);
+    assert!(receipt.error_text.contains("may not write"));
+    assert!(Metadata::chain("p", "d").is_empty());
+    assert_eq!(Transaction::find("h2").unwrap().status, TX_FAILED);
+
+    // The key a transaction names counts for nothing without its signature.
+    let forged = Transaction { public_key: owner.clone(), ..test_tx("h3", "set_metadata", &other, "c1") };
+    assert_eq!(Apply::apply(forged).unwrap().status, TX_FAILED);
+
+    let unverified = Transaction { signer: String::new(), ..test_tx("h4", "set_metadata", &owner, "c1") };
+    assert_eq!(Apply::apply(unverified).unwrap().status, TX_FAILED);
+  });
+}
+
+#[test]
+fn test_apply_handler_failure() {
+  use crate::{db, get_trie_root};
+
+  db::scratch(|| {
+    let (owner, _) = auth::test_signature(1, "");
+
+    let receipt = Apply::apply(test_tx("h1", "drop_program", &owner, "")).unwrap();
+    assert_eq!(receipt.status, TX_FAILED);
+    assert_eq!(receipt.error_text, r#"Unknown method "drop_program""#);
+
+    let receipt = Apply::apply(test_tx("h2", "schedule_cron", &owner, "not json")).unwrap();
+    assert_eq!(receipt.status, TX_FAILED);
+    assert_eq!(get_trie_root(&Cron::trie_name()).unwrap(), [0u8; 32]);
+    assert_eq!(get_trie_root(&Metadata::trie_name()).unwrap(), [0u8; 32]);
+    assert_eq!(Transaction::find("h2").unwrap().status, TX_FAILED);
+  });
+}
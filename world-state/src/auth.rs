@@ -0,0 +1,372 @@
+use keccak_hasher::keccak_256;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+  acl::Grant,
+  get_trie_value,
+  metacontract::MetaContract,
+  ownership::{Owner, Policy},
+  record::{self, PreparedRecord},
+  types::TrieResult,
+  utils::flag_value,
+};
+
+/// A signature of a privileged operation. `signer` may be left out when it
+/// is the only key that could have signed.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Signed {
+  pub signer: Option<String>,
+  pub signature: String,
+}
+
+/// What an `insert_trie_batch --signatures` entry holds for its record: a
+/// signature by the `--signers` entry at the same position, or a list.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum BatchSignatures {
+  One(String),
+  Many(Vec<Signed>),
+}
+
+impl BatchSignatures {
+  pub fn signed(self, signer: Option<String>) -> Vec<Signed> {
+    match self {
+      BatchSignatures::One(signature) => vec![Signed { signer, signature }],
+      BatchSignatures::Many(signed) => signed,
+    }
+  }
+}
+
+/// Signatures given as `--<prefix>signature <sig>` by `--<prefix>signer`, and
+/// as `--<prefix>signatures '[{"signer": .., "signature": ..}]'`.
+pub fn signatures_from_args(args: &[String], prefix: &str) -> Result<Vec<Signed>, TrieResult> {
+  let mut signed: Vec<Signed> = match flag_value(args, &format!("--{}signatures", prefix)) {
+    Some(list) => serde_json::from_str(&list).map_err(|e| TrieResult {
+      success: false,
+      result: Some(format!("Error decoding --{}signatures: {}", prefix, e)),
+    })?,
+    None => Vec::new(),
+  };
+
+  if let Some(signature) = flag_value(args, &format!("--{}signature", prefix)) {
+    signed.insert(0, Signed { signer: flag_value(args, &format!("--{}signer", prefix)), signature });
+  }
+  Ok(signed)
+}
+
+/// What the owner signs to authorise a write: the record as compact JSON with
+/// the keys of every object sorted.
+pub fn signed_message(value: &Value) -> String {
+  match value {
+    Value::Object(map) => {
+      let mut keys: Vec<&String> = map.keys().collect();
+      keys.sort();
+
+      let fields: Vec<String> = keys
+        .iter()
+        .map(|key| format!("{}:{}", Value::String(key.to_string()), signed_message(&map[*key])))
+        .collect();
+      format!("{{{}}}", fields.join(","))
+    },
+    Value::Array(items) => format!("[{}]", items.iter().map(signed_message).collect::<Vec<_>>().join(",")),
+    other => other.to_string(),
+  }
+}
+
+/// What the owner signs to authorise writing `value` over `current`, the
+/// leaf it replaces: the record with the hash of that leaf, empty when there
+/// is none, so a signature no longer applies once the leaf changed.
+pub fn write_message(value: &Value, current: Option<&[u8]>) -> String {
+  signed_message(&json!({
+    "record": value,
+    "replaces": current.map(|leaf| hex::encode(keccak_256(leaf))).unwrap_or_default(),
+  }))
+}
+
+/// Allow writing `prepared`, given as `value`, only if `signed` authorises
+/// its `write_message` for the current owner of the leaf it replaces or,
+/// when that leaf has no `Record::owner`, of its program. A record without an
+/// owner, like loose metadata, may replace one without an owner freely. See
+/// `check_signers`.
+pub fn authorize(trie_key: &str, prepared: &PreparedRecord, value: &Value, signed: &[Signed]) -> Result<(), TrieResult> {
+  let record = record::find(trie_key)
+    .ok_or(TrieResult { success: false, result: Some("Unknown trie".to_string()) })?;
+  let decode_error = |e| TrieResult { success: false, result: Some(format!("Error decoding {}: {}", trie_key, e)) };
+
+  let current = get_trie_value(trie_key, &record.db_path(), &prepared.replaces)
+    .map_err(|e| TrieResult { success: false, result: Some(e) })?;
+
+  let replaced = match &current {
+    Some(current) => record.owner(current).map_err(decode_error)?,
+    None => None,
+  };
+  let owner = match replaced {
+    Some(owner) => Some(owner.resolve()),
+    None => record
+      .owner(&prepared.value)
+      .map_err(decode_error)?
+      .and_then(|owner| program_owner(&owner.program_id)),
+  };
+
+  let owner = match owner {
+    Some(owner) => owner,
+    None => return Ok(()),
+  };
+
+  let message = write_message(value, current.as_deref());
+  let details = json!({
+    "key": String::from_utf8_lossy(&prepared.replaces),
+    "owner": owner.public_key,
+    "signed_message": message,
+  });
+  check_signers(trie_key, &owner, None, signed, &message, details).map(|_| ())
+}
+
+/// Allow `call`, an operation changing the record `trie_key` holds at `key`,
+/// only if `signed` authorises its `write_message` over that record for the
+/// record's owner, or the program's owner when the record names no key. Like
+/// `authorize`, a record nobody owns may be changed freely.
+pub fn authorize_call(trie_key: &str, key: &[u8], call: &Value, signed: &[Signed]) -> Result<(), TrieResult> {
+  let record = record::find(trie_key)
+    .ok_or(TrieResult { success: false, result: Some("Unknown trie".to_string()) })?;
+
+  let current = get_trie_value(trie_key, &record.db_path(), key)
+    .map_err(|e| TrieResult { success: false, result: Some(e) })?
+    .ok_or(TrieResult { success: false, result: Some("Record not found".to_string()) })?;
+
+  let owner = match record.owner(&current) {
+    Ok(Some(owner)) if !owner.public_key.is_empty() => Some(owner.resolve()),
+    Ok(Some(owner)) => program_owner(&owner.program_id),
+    Ok(None) => None,
+    Err(e) => return Err(TrieResult { success: false, result: Some(format!("Error decoding {}: {}", trie_key, e)) }),
+  };
+
+  let owner = match owner {
+    Some(owner) => owner,
+    None => return Ok(()),
+  };
+
+  let message = write_message(call, Some(&current));
+  let details = json!({
+    "key": String::from_utf8_lossy(key),
+    "owner": owner.public_key,
+    "signed_message": message,
+  });
+  check_signers(trie_key, &owner, None, signed, &message, details).map(|_| ())
+}
+
+/// The current owner of `program_id`, `None` while it has no metacontract.
+pub fn program_owner(program_id: &str) -> Option<Owner> {
+  MetaContract::find(program_id).map(|contract| Owner { program_id: program_id.to_string(), public_key: contract.public_key }.resolve())
+}
+
+/// Allow writing `value` for `program_id` only if `signed` authorises
+/// `signed_message(value)` for the program's owner, or for `signer` while the
+/// program has no metacontract. Signatures without a signer are by `signer`.
+/// Returns the key the signatures were verified for, see `check_signers`.
+pub fn authorize_program(
+  trie_key: &str,
+  program_id: &str,
+  value: &Value,
+  signer: &str,
+  signed: &[Signed],
+) -> Result<String, TrieResult> {
+  let message = signed_message(value);
+
+  let owner = match program_owner(program_id) {
+    Some(owner) => owner,
+    None => {
+      let details = json!({ "program_id": program_id, "signer": signer });
+      return verify_owner(signer, signed, &message, details).map(|_| signer.to_string());
+    },
+  };
+
+  let details = json!({ "program_id": program_id, "owner": owner.public_key });
+  check_signers(trie_key, &owner, Some(signer), signed, &message, details)
+}
+
+/// A grantee of `owner.program_id` allowed to write `trie_key` may sign alone,
+/// otherwise the signatures must meet the owner's policy. Returns the grantee
+/// or the owner's key.
+fn check_signers(
+  trie_key: &str,
+  owner: &Owner,
+  signer: Option<&str>,
+  signed: &[Signed],
+  message: &str,
+  details: Value,
+) -> Result<String, TrieResult> {
+  let policy = Policy::parse(&owner.public_key).map_err(|e| TrieResult { success: false, result: Some(e) })?;
+
+  let signed: Vec<Signed> = signed
+    .iter()
+    .map(|s| Signed { signer: s.signer.clone().or(signer.map(str::to_string)), signature: s.signature.clone() })
+    .collect();
+  let (grantees, owners): (Vec<Signed>, Vec<Signed>) = signed
+    .into_iter()
+    .partition(|s| s.signer.as_deref().map_or(false, |key| !policy.contains(key)));
+
+  for s in &grantees {
+    let grantee = s.signer.as_deref().unwrap_or_default();
+
+    if !Grant::allows(&owner.program_id, grantee, trie_key) {
+      return Err(TrieResult::error(
+        "unauthorized",
+        format!("{} is not an owner of {} and has no grant to write {}", grantee, owner.program_id, trie_key),
+        details,
+      ));
+    }
+    if verify_signature(grantee, &s.signature, message) {
+      return Ok(grantee.to_string());
+    }
+  }
+
+  match grantees.first() {
+    Some(s) if owners.is_empty() => Err(TrieResult::error(
+      "unauthorized",
+      format!("Signature is not {}'s", s.signer.as_deref().unwrap_or_default()),
+      details,
+    )),
+    _ => verify_owner(&owner.public_key, &owners, message, details).map(|_| owner.public_key.clone()),
+  }
+}
+
+/// Check that `signed` meets the threshold of the policy `owner_key` stands
+/// for. The error reports which signers were valid.
+pub fn verify_owner(owner_key: &str, signed: &[Signed], message: &str, details: Value) -> Result<(), TrieResult> {
+  let policy = Policy::parse(owner_key).map_err(|e| TrieResult { success: false, result: Some(e) })?;
+
+  if signed.is_empty() {
+    return Err(TrieResult::error("unauthorized", "The owner's signature is required".to_string(), details));
+  }
+
+  let mut pairs = Vec::new();
+  for s in signed {
+    match (&s.signer, policy.public_keys.as_slice()) {
+      (Some(signer), _) => pairs.push((signer.clone(), s.signature.clone())),
+      (None, [key]) => pairs.push((key.clone(), s.signature.clone())),
+      (None, _) => return Err(TrieResult::error(
+        "unauthorized",
+        "Signatures for a multisig owner must name their signer".to_string(),
+        details,
+      )),
+    }
+  }
+
+  let result = crypto::verify_threshold(&policy.public_keys, policy.threshold as usize, &pairs, message);
+  if result.met {
+    return Ok(());
+  }
+
+  let mut details = details;
+  details["valid_signers"] = json!(result.valid);
+  details["invalid_signers"] = json!(result.invalid);
+  details["threshold"] = json!(result.threshold);
+
+  Err(TrieResult::error(
+    "unauthorized",
+    format!("{} of the {} owner signatures needed are valid", result.valid.len(), result.threshold),
+    details,
+  ))
+}
+
+/// Whether `key` may write records of `trie_key` owned by `owner`, as one of
+/// its keys or a grantee. For writes whose signatures were checked before.
+pub fn may_write(owner: &Owner, key: &str, trie_key: &str) -> bool {
+  Policy::parse(&owner.public_key).map_or(false, |policy| policy.contains(key))
+    || Grant::allows(&owner.program_id, key, trie_key)
+}
+
+/// Whether `signature` is over `message` by `public_key`, of either key type.
+pub fn verify_signature(public_key: &str, signature: &str, message: &str) -> bool {
+  crypto::verify(public_key.to_string(), signature.to_string(), message.to_string(), crypto::get_public_key_type(public_key))
+}
+
+/// The key derived from `seed` and its signature of `message`.
+#[cfg(test)]
+pub fn test_signature(seed: u8, message: &str) -> (String, String) {
+  use base58::ToBase58;
+  use ed25519_compact::{KeyPair, Seed};
+
+  let pair = KeyPair::from_seed(Seed::new([seed; 32]));
+  (pair.pk[..].to_base58(), pair.sk.sign(message, None)[..].to_base58())
+}
+
+#[test]
+fn test_authorize() {
+  use crate::{build_trie_db, db, metacontract::MetaContract, metadata::Metadata, record::{insert_records, Record}};
+
+  db::scratch(|| {
+    let trie_key = Metadata::trie_name();
+    let (owner, _) = test_signature(1, "");
+    let metadata = |data_key: &str, version: &str, loose: u64| json!({
+      "hash": format!("{}{}", data_key, version), "data_key": data_key, "program_id": "p", "alias": "",
+      "chain_id": "", "token_address": "", "token_id": "", "version": version, "cid": "c",
+      "public_key": owner, "loose": loose,
+    });
+    let prepare = |value: &Value| record::find(&trie_key).unwrap().prepare_json(value.clone()).unwrap();
+    let signed = |seed: u8, value: &Value, current: Option<&[u8]>| {
+      vec![Signed { signer: None, signature: test_signature(seed, &write_message(value, current)).1 }]
+    };
+
+    let first = metadata("d", "1", 0);
+    assert!(authorize(&trie_key, &prepare(&first), &first, &[]).is_ok());
+
+    insert_records(&[MetaContract { program_id: "p".to_string(), public_key: owner.clone(), cid: "c".to_string(), public_keys: Vec::new(), threshold: 0 }]).unwrap();
+
+    let unsigned = authorize(&trie_key, &prepare(&first), &first, &[]).unwrap_err();
+    assert_eq!(unsigned.code().as_deref(), Some("unauthorized"));
+    assert!(authorize(&trie_key, &prepare(&first), &first, &signed(2, &first, None)).is_err());
+    assert!(authorize(&trie_key, &prepare(&first), &first, &signed(1, &first, None)).is_ok());
+
+    let stored = prepare(&first);
+    build_trie_db(&trie_key, &Metadata::db_path(), &[(stored.key.clone(), stored.value.clone())]).unwrap();
+
+    let second = metadata("d", "2", 0);
+    assert!(authorize(&trie_key, &prepare(&second), &second, &signed(1, &second, None)).is_err());
+    assert!(authorize(&trie_key, &prepare(&second), &second, &signed(1, &second, Some(&stored.value))).is_ok());
+
+    let loose = metadata("e", "1", 1);
+    assert!(authorize(&trie_key, &prepare(&loose), &loose, &[]).is_ok());
+  });
+}
+
+#[test]
+fn test_authorize_program() {
+  use crate::{acl::Grant, db, metacontract::MetaContract, record::{insert_records, Record}, transaction::Transaction};
+
+  db::scratch(|| {
+    let trie_key = Transaction::trie_name();
+    let value = json!({ "hash": "h", "nonce": 1 });
+    let message = signed_message(&value);
+    let ((key, signature), (other, other_signature)) = (test_signature(1, &message), test_signature(2, &message));
+    let by = |signer: Option<&String>, signature: &String| vec![Signed { signer: signer.cloned(), signature: signature.clone() }];
+
+    let unsigned = authorize_program(&trie_key, "p", &value, &key, &[]).unwrap_err();
+    assert_eq!(unsigned.code().as_deref(), Some("unauthorized"));
+    assert!(authorize_program(&trie_key, "p", &value, &key, &by(None, &other_signature)).is_err());
+    assert_eq!(authorize_program(&trie_key, "p", &value, &key, &by(None, &signature)).unwrap(), key);
+
+    insert_records(&[MetaContract { program_id: "p".to_string(), public_key: key.clone(), cid: "c".to_string(), public_keys: Vec::new(), threshold: 0 }]).unwrap();
+    assert!(authorize_program(&trie_key, "p", &value, &other, &by(None, &other_signature)).is_err());
+    assert_eq!(authorize_program(&trie_key, "p", &value, &other, &by(Some(&key), &signature)).unwrap(), key);
+
+    insert_records(&[Grant { program_id: "p".to_string(), grantee: other.clone(), role: "writer".to_string(), expires_at: 0, seq: 1, updated_at: 0 }]).unwrap();
+    assert_eq!(authorize_program(&trie_key, "p", &value, &other, &by(None, &other_signature)).unwrap(), other);
+  });
+}
+
+#[test]
+fn test_signed_message() {
+  use crate::ownership::Ownership;
+
+  let value = json!({ "b": 1, "a": { "d": [true, null], "c": "x" } });
+  assert_eq!(signed_message(&value), r#"{"a":{"c":"x","d":[true,null]},"b":1}"#);
+
+  assert_eq!(
+    Ownership::transfer_message("p", "k2", 1),
+    r#"{"method":"transfer_program","program_id":"p","public_key":"k2","seq":1}"#,
+  );
+}
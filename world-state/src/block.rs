@@ -0,0 +1,430 @@
+use std::env;
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use trie_db::{DBValue, NodeCodec, TrieDBMutBuilder, TrieLayout, TrieMut};
+
+use crate::{
+  apply::Apply,
+  appconfig::CONFIG,
+  auth::{self, signed_message},
+  commit_roots,
+  db::WriteLock,
+  defer_commits,
+  genesis::Genesis,
+  get_trie_results,
+  get_trie_root,
+  get_trie_value,
+  layout::{with_layout, LayoutKind},
+  record::{self, Record},
+  state::{StateCommit, TrieRoot},
+  transaction::{Transaction, TX_PENDING},
+  transaction_receipt::TransactionReceipt,
+  types::TrieResult,
+  utils::now_ms,
+};
+
+/// What the producer signs: the block's place in the chain and the pending
+/// transactions it applies, in order.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BlockProposal {
+  pub height: u64,
+  pub parent_hash: String,
+  pub timestamp: u64,
+  pub transactions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone, PartialEq)]
+pub struct ProducerSignature {
+  pub signer: String,
+  pub signature: String,
+}
+
+/// A batch of transactions applied together, stored under its zero padded
+/// `height`. `tx_root` and `receipt_root` are the roots of tries holding the
+/// transactions and receipts by position, `state_roots` those of every trie
+/// after the block.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct BlockHeader {
+  pub height: u64,
+  /// Hash of the header without this field.
+  pub hash: String,
+  /// Hash of the previous block, or of the genesis for the first block.
+  pub parent_hash: String,
+  pub timestamp: u64,
+  pub tx_root: String,
+  pub receipt_root: String,
+  pub state_roots: Vec<TrieRoot>,
+  /// Hashes of the transactions, in the order they were applied.
+  pub transactions: Vec<String>,
+  /// The `BLOCK_PRODUCER` that signed the block.
+  pub producer: String,
+  pub signatures: Vec<ProducerSignature>,
+}
+
+impl Record for BlockHeader {
+  const CONFIG_PREFIX: &'static str = "BLOCK";
+
+  fn primary_key(&self) -> Vec<u8> {
+    Self::height_key(self.height)
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Blocks are only written by produce_block".to_string())
+  }
+}
+
+/// The height of the block hashing to `hash`, for `get_block` by hash. Stored
+/// under the decoded hash, which is short enough for any layout.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct BlockIndex {
+  pub hash: String,
+  pub height: u64,
+}
+
+impl Record for BlockIndex {
+  const CONFIG_PREFIX: &'static str = "BLOCK_HASH";
+
+  fn primary_key(&self) -> Vec<u8> {
+    hex::decode(&self.hash).unwrap_or(self.hash.as_bytes().to_vec())
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Blocks are only indexed by produce_block".to_string())
+  }
+}
+
+/// The height and hash of the latest block, stored under a fixed key so the
+/// tip is read without scanning the headers.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct BlockTip {
+  pub height: u64,
+  pub hash: String,
+}
+
+impl Record for BlockTip {
+  const CONFIG_PREFIX: &'static str = "BLOCK_TIP";
+
+  fn primary_key(&self) -> Vec<u8> {
+    Self::KEY.to_vec()
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("The tip only moves with produce_block".to_string())
+  }
+}
+
+impl BlockTip {
+  const KEY: &'static [u8] = b"latest";
+}
+
+impl BlockProposal {
+  /// Reject a timestamp further than `max_skew`, the configured
+  /// `TX_MAX_SKEW_MS`, from `now`.
+  fn check_time(&self, now: u64, max_skew: Option<u64>) -> Result<(), TrieResult> {
+    match max_skew {
+      Some(max_skew) if self.timestamp.abs_diff(now) > max_skew => Err(TrieResult::error(
+        "timestamp_out_of_window",
+        format!("Block timestamp {} is more than {} ms from now", self.timestamp, max_skew),
+        json!({ "timestamp": self.timestamp, "now": now, "max_skew": max_skew }),
+      )),
+      _ => Ok(()),
+    }
+  }
+}
+
+impl BlockHeader {
+  /// `produce_block '<proposal>' --signature <sig> [--signer <key>]`
+  ///
+  /// Applies the transactions of the signed `BlockProposal` in their order
+  /// and stores the header of the block, all in one commit. Each transaction
+  /// reads what the ones before it wrote. Refused unless `BLOCK_PRODUCER` is
+  /// configured and signed the proposal. The timestamp must not be before
+  /// the parent's nor further than `TX_MAX_SKEW_MS` from now.
+  pub fn produce_block() -> TrieResult {
+    Self::produce(&env::args().collect::<Vec<String>>(), CONFIG.get::<String>("BLOCK_PRODUCER").ok(), now_ms())
+  }
+
+  fn produce(args: &[String], producer: Option<String>, now: u64) -> TrieResult {
+    let value: Value = match args.get(2).map(|arg| serde_json::from_str(arg)) {
+      Some(Ok(value)) => value,
+      Some(Err(e)) => return TrieResult { success: false, result: Some(format!("Error decoding proposal: {}", e)) },
+      None => return TrieResult { success: false, result: Some("Missing block proposal".to_string()) },
+    };
+    let proposal: BlockProposal = match serde_json::from_value(value.clone()) {
+      Ok(proposal) => proposal,
+      Err(e) => return TrieResult { success: false, result: Some(format!("Error decoding proposal: {}", e)) },
+    };
+
+    let signed = match auth::signatures_from_args(args, "") {
+      Ok(signed) => signed,
+      Err(e) => return e,
+    };
+    let producer = match producer {
+      Some(producer) => producer,
+      None => return TrieResult::error("no_producer", "BLOCK_PRODUCER is not configured".to_string(), json!({})),
+    };
+    if let Err(e) = auth::verify_owner(&producer, &signed, &signed_message(&value), json!({ "producer": producer })) {
+      return e;
+    }
+
+    let _lock = WriteLock::acquire();
+
+    let latest = Self::latest();
+    let (height, parent_hash) = match &latest {
+      Some(latest) => (latest.height + 1, latest.hash.clone()),
+      None => (1, Genesis::find().map(|genesis| genesis.hash).unwrap_or_default()),
+    };
+    if proposal.height != height || proposal.parent_hash != parent_hash {
+      return TrieResult::error(
+        "wrong_parent",
+        format!("The next block is {} on {:?}", height, parent_hash),
+        json!({ "expected_height": height, "expected_parent_hash": parent_hash }),
+      );
+    }
+    if let Some(latest) = latest.filter(|latest| latest.timestamp > proposal.timestamp) {
+      return TrieResult::error(
+        "timestamp_out_of_window",
+        format!("Block timestamp {} is before its parent's {}", proposal.timestamp, latest.timestamp),
+        json!({ "timestamp": proposal.timestamp, "parent_timestamp": latest.timestamp }),
+      );
+    }
+    if let Err(e) = proposal.check_time(now, CONFIG.get::<u64>("TX_MAX_SKEW_MS").ok()) {
+      return e;
+    }
+
+    let mut txs = Vec::new();
+    for hash in proposal.transactions.iter() {
+      match Transaction::find(hash) {
+        Some(tx) if tx.status == TX_PENDING && !tx.expired(now) && !txs.iter().any(|t: &Transaction| t.hash == tx.hash) => txs.push(tx),
+        _ => return TrieResult::error("not_pending", format!("Transaction {} is not pending", hash), json!({ "hash": hash })),
+      }
+    }
+
+    let (block, roots) = defer_commits(|| Self::apply_block(proposal, height, parent_hash, txs, signed, producer));
+    let (header, receipts) = match block {
+      Ok(block) => block,
+      Err(e) => return e,
+    };
+    if let Err(e) = commit_roots(roots) {
+      return e;
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(json!({ "block": header, "receipts": receipts }).to_string()),
+    }
+  }
+
+  /// Apply `txs` and stage the header of the block holding them, see
+  /// `produce_block`.
+  fn apply_block(
+    proposal: BlockProposal,
+    height: u64,
+    parent_hash: String,
+    txs: Vec<Transaction>,
+    signed: Vec<auth::Signed>,
+    producer: String,
+  ) -> Result<(BlockHeader, Vec<TransactionReceipt>), TrieResult> {
+    let mut applied = Vec::new();
+    let mut receipts = Vec::new();
+    for tx in txs {
+      let receipt = Apply::apply(tx.clone())?;
+      applied.push(Transaction { status: receipt.status, ..tx });
+      receipts.push(receipt);
+    }
+
+    let state_roots = record::all()
+      .into_iter()
+      .map(|record| record.trie_name())
+      .filter(|trie_key| ![Self::trie_name(), BlockIndex::trie_name(), BlockTip::trie_name()].contains(trie_key))
+      .map(|trie_key| get_trie_root(&trie_key).map(|root| TrieRoot { root: hex::encode(root), trie_key }))
+      .collect::<Result<Vec<_>, String>>()
+      .map_err(|e| TrieResult { success: false, result: Some(e) })?;
+
+    let mut header = BlockHeader {
+      height,
+      hash: String::new(),
+      parent_hash,
+      timestamp: proposal.timestamp,
+      tx_root: Self::list_root(applied.iter().map(Record::encode_record).collect()),
+      receipt_root: Self::list_root(receipts.iter().map(Record::encode_record).collect()),
+      state_roots,
+      transactions: proposal.transactions,
+      signatures: signed
+        .into_iter()
+        .map(|s| ProducerSignature { signer: s.signer.unwrap_or(producer.clone()), signature: s.signature })
+        .collect(),
+      producer,
+    };
+    header.hash = header.compute_hash();
+
+    let mut commit = StateCommit::new();
+    commit.put(&[header.clone()]);
+    commit.put(&[BlockIndex { hash: header.hash.clone(), height }]);
+    commit.put(&[BlockTip { height, hash: header.hash.clone() }]);
+    commit.commit()?;
+
+    Ok((header, receipts))
+  }
+
+  /// `get_block <height|hash>`
+  pub fn get_block() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let block = match args.get(2) {
+      Some(id) => Self::lookup(id),
+      None => return TrieResult { success: false, result: Some("Missing block height or hash".to_string()) },
+    };
+
+    match block {
+      Some(block) => TrieResult { success: true, result: Some(serde_json::to_string(&block).unwrap_or("".to_string())) },
+      None => TrieResult { success: false, result: Some("Record not found".to_string()) },
+    }
+  }
+
+  /// `get_latest_block`
+  pub fn get_latest_block() -> TrieResult {
+    match Self::latest() {
+      Some(block) => TrieResult { success: true, result: Some(serde_json::to_string(&block).unwrap_or("".to_string())) },
+      None => TrieResult { success: false, result: Some("Record not found".to_string()) },
+    }
+  }
+
+  pub fn find(height: u64) -> Option<BlockHeader> {
+    get_trie_value(&Self::trie_name(), &Self::db_path(), &Self::height_key(height))
+      .ok()
+      .flatten()
+      .and_then(|val| Self::decode_record(&val).ok())
+  }
+
+  /// The block at height `id`, or hashing to it.
+  pub fn lookup(id: &str) -> Option<BlockHeader> {
+    match id.parse::<u64>() {
+      Ok(height) => Self::find(height),
+      Err(_) => {
+        let key = BlockIndex { hash: id.to_string(), height: 0 }.primary_key();
+        get_trie_value(&BlockIndex::trie_name(), &BlockIndex::db_path(), &key)
+          .ok()
+          .flatten()
+          .and_then(|val| BlockIndex::decode_record(&val).ok())
+          .and_then(|index| Self::find(index.height))
+          .filter(|block| block.hash == id)
+      },
+    }
+  }
+
+  /// The block at the tip, see `BlockTip`.
+  pub fn latest() -> Option<BlockHeader> {
+    let tip = get_trie_value(&BlockTip::trie_name(), &BlockTip::db_path(), BlockTip::KEY)
+      .ok()
+      .flatten()
+      .and_then(|val| BlockTip::decode_record(&val).ok());
+
+    match tip {
+      Some(tip) => Self::find(tip.height),
+      // Blocks produced before the tip was stored.
+      None => Self::all().into_iter().max_by_key(|block| block.height),
+    }
+  }
+
+  pub fn all() -> Vec<BlockHeader> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .collect()
+  }
+
+  fn compute_hash(&self) -> String {
+    let mut value = json!(self);
+    if let Value::Object(fields) = &mut value {
+      fields.remove("hash");
+    }
+    hex::encode(KeccakHasher::hash(signed_message(&value).as_bytes()))
+  }
+
+  fn height_key(height: u64) -> Vec<u8> {
+    format!("{:020}", height).into_bytes()
+  }
+
+  /// Root of a trie holding `values` under their RLP encoded position, built
+  /// with the layout of the block trie.
+  fn list_root(values: Vec<Vec<u8>>) -> String {
+    hex::encode(with_layout!(LayoutKind::for_trie(&Self::trie_name()), L => list_root::<L>(&values)))
+  }
+}
+
+fn list_root<L: TrieLayout<Hash = KeccakHasher>>(values: &[Vec<u8>]) -> <KeccakHasher as Hasher>::Out {
+  let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::new(L::Codec::empty_node());
+  let mut root = Default::default();
+  {
+    let mut trie = TrieDBMutBuilder::<L>::new(&mut memdb, &mut root).build();
+    for (i, value) in values.iter().enumerate() {
+      trie.insert(&rlp::encode(&(i as u64)), value).expect("trie insertion failed");
+    }
+  }
+  root
+}
+
+#[test]
+fn test_produce_block() {
+  use crate::{apply::test_tx, auth::test_signature, db, metadata::Metadata, transaction::TX_APPLIED, ROOT_WRITES};
+
+  db::scratch(|| {
+    let (signer, _) = test_signature(1, "");
+    let mut pending = StateCommit::new();
+    pending.put(&[test_tx("h1", "set_metadata", &signer, "c1"), Transaction { version: "2".to_string(), ..test_tx("h2", "set_metadata", &signer, "c2") }]);
+    pending.commit().unwrap();
+
+    let proposal = |height: u64, parent_hash: &str, transactions: Value| {
+      json!({ "height": height, "parent_hash": parent_hash, "timestamp": 10, "transactions": transactions })
+    };
+    let args = |proposal: &Value, seed: u8| -> Vec<String> {
+      let (_, signature) = test_signature(seed, &signed_message(proposal));
+      ["world-state", "produce_block", &proposal.to_string(), "--signature", &signature].iter().map(|s| s.to_string()).collect()
+    };
+    let first = proposal(1, "", json!(["h1", "h2"]));
+    let (producer, _) = test_signature(9, "");
+
+    assert_eq!(BlockHeader::produce(&args(&first, 9), None, 10).code().as_deref(), Some("no_producer"));
+    assert_eq!(BlockHeader::produce(&args(&first, 8), Some(producer.clone()), 10).code().as_deref(), Some("unauthorized"));
+
+    let writes = ROOT_WRITES.with(|writes| writes.get());
+    let result = BlockHeader::produce(&args(&first, 9), Some(producer.clone()), 10);
+    assert!(result.success, "{:?}", result.result);
+    assert_eq!(ROOT_WRITES.with(|writes| writes.get()) - writes, 1);
+
+    // The second transaction extends the chain the first one started.
+    assert_eq!(Metadata::chain("p", "d").iter().map(|m| m.cid.as_str()).collect::<Vec<_>>(), vec!["c1", "c2"]);
+    assert_eq!(Transaction::find("h2").unwrap().status, TX_APPLIED);
+
+    let block = BlockHeader::lookup("1").unwrap();
+    assert_eq!((block.producer.as_str(), block.transactions.len()), (producer.as_str(), 2));
+    assert_eq!(BlockHeader::lookup(&block.hash).unwrap().height, 1);
+    assert!(BlockHeader::lookup("ab").is_none());
+    assert_eq!(BlockHeader::latest().unwrap().hash, block.hash);
+
+    assert_eq!(BlockHeader::produce(&args(&first, 9), Some(producer.clone()), 10).code().as_deref(), Some("wrong_parent"));
+
+    // The tip moves with each block.
+    let second = proposal(2, &block.hash, json!([]));
+    assert!(BlockHeader::produce(&args(&second, 9), Some(producer), 10).success);
+    assert_eq!(BlockHeader::latest().unwrap().parent_hash, block.hash);
+  });
+}
+
+#[test]
+fn test_proposal_check_time() {
+  let proposal = BlockProposal { height: 1, parent_hash: String::new(), timestamp: 1_000, transactions: Vec::new() };
+
+  assert!(proposal.check_time(1_500, Some(500)).is_ok());
+  assert!(proposal.check_time(500, Some(500)).is_ok());
+  assert!(proposal.check_time(u64::MAX, None).is_ok());
+  assert_eq!(proposal.check_time(1_501, Some(500)).unwrap_err().code().as_deref(), Some("timestamp_out_of_window"));
+  assert_eq!(proposal.check_time(499, Some(500)).unwrap_err().code().as_deref(), Some("timestamp_out_of_window"));
+}
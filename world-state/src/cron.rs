@@ -1,27 +1,580 @@
-use std::collections::HashMap;
+use std::env;
 
+use rlp::{Decodable, DecoderError, Rlp};
 use rlp_derive::{RlpEncodable, RlpDecodable};
 use serde::{Serialize, Deserialize};
-use serde_json::{Value, Number};
+
+use serde_json::json;
+
+use crate::{
+  auth::{authorize_call, signatures_from_args},
+  cron_run::CronRun,
+  db::WriteLock,
+  get_trie_results,
+  ownership::Owner,
+  precondition,
+  record::{field_or_default, insert_records, Record},
+  schedule::{format_utc, Schedule},
+  state::StateCommit,
+  types::TrieResult,
+  utils::{flag_value, now_ms},
+};
+
+/// Scheduled and run by `get_due_cron`/`complete_cron`.
+pub const CRON_ACTIVE: u64 = 0;
+/// A one-shot job that has run.
+pub const CRON_COMPLETED: u64 = 1;
+/// Kept but not scheduled until `resume_cron`.
+pub const CRON_PAUSED: u64 = 2;
+/// Stopped for good by `cancel_cron`.
+pub const CRON_CANCELLED: u64 = 3;
+
+/// What `get_due_cron` and `complete_cron` do with the runs a job missed while
+/// nothing was polling. `run_once` when empty.
+pub const MISFIRE_POLICIES: [&str; 3] = ["skip", "run_once", "catch_up"];
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
 pub struct Cron {
   pub program_id: String,
   pub public_key: String,
   pub cid: String,
+  /// Interval between runs in ms when `schedule` is empty, 0 for a job that
+  /// runs once.
   pub epoch: u64,
   pub status: u64,
+  /// When the job is next due in ms, 0 when it never ran.
+  #[serde(default)]
+  pub next_run: u64,
+  #[serde(default)]
+  pub last_run: u64,
+  /// Cron expression, `@every <interval>` or `@at <time>`, see `Schedule`.
+  /// Empty runs every `epoch` ms.
+  #[serde(default)]
+  pub schedule: String,
+  /// Tells a program's jobs apart, empty for its default job.
+  #[serde(default)]
+  pub job_id: String,
+  /// One of `MISFIRE_POLICIES`: `skip` runs only the latest of the missed
+  /// runs and drops the others, `run_once` runs once for all of them and
+  /// `catch_up` runs each one in turn.
+  #[serde(default)]
+  pub misfire: String,
 }
 
-impl From<Cron> for HashMap<String, Value> {
-  fn from(cron: Cron) -> Self {
-    let mut map = HashMap::new();
-    map.insert("program_id".to_string(), Value::String(cron.program_id));
-    map.insert("public_key".to_string(), Value::String(cron.public_key));
-    map.insert("cid".to_string(), Value::String(cron.cid));
-    map.insert("epoch".to_string(), Value::Number(Number::from(cron.epoch)));
-    map.insert("status".to_string(), Value::Number(Number::from(cron.status)));
+impl Record for Cron {
+  const CONFIG_PREFIX: &'static str = "CRON";
+  const VERSION: u64 = 4;
 
-    map
+  fn primary_key(&self) -> Vec<u8> {
+    self.job_key().into_bytes()
   }
-}
\ No newline at end of file
+
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    match version {
+      0..=3 => Ok(Cron {
+        program_id: rlp.val_at(0)?,
+        public_key: rlp.val_at(1)?,
+        cid: rlp.val_at(2)?,
+        epoch: rlp.val_at(3)?,
+        status: rlp.val_at(4)?,
+        next_run: field_or_default(rlp, 5)?,
+        last_run: field_or_default(rlp, 6)?,
+        schedule: field_or_default(rlp, 7)?,
+        job_id: field_or_default(rlp, 8)?,
+        misfire: field_or_default(rlp, 9)?,
+      }),
+      Self::VERSION => Self::decode(rlp),
+      _ => Err(DecoderError::Custom("unsupported record version")),
+    }
+  }
+
+  /// Reject schedules that don't parse. Jobs on a cron expression or a
+  /// one-shot time get their first run, interval and `epoch` jobs are due
+  /// right away.
+  fn prepare(&mut self) -> Result<(), String> {
+    if self.job_id.contains('/') {
+      return Err(format!("Job id {:?} cannot contain /", self.job_id));
+    }
+    if !self.misfire.is_empty() && !MISFIRE_POLICIES.contains(&self.misfire.as_str()) {
+      return Err(format!("Unknown misfire policy {:?}, use one of {:?}", self.misfire, MISFIRE_POLICIES));
+    }
+
+    self.set_first_run(now_ms())
+  }
+
+  fn owner(&self) -> Option<Owner> {
+    Some(Owner { program_id: self.program_id.clone(), public_key: self.public_key.clone() })
+  }
+}
+
+impl Cron {
+  /// `get_due_cron [--now <ms>]`
+  ///
+  /// Only reads the jobs, `complete_cron` moves them on. Jobs with the `skip`
+  /// misfire policy that missed runs are given at the latest of them.
+  pub fn get_due_cron() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let now = match flag_value(&args, "--now").map(|now| now.parse::<u64>()) {
+      Some(Ok(now)) => now,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--now expects milliseconds".to_string()) },
+      None => now_ms(),
+    };
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&Self::due(now)).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Active jobs due by `now`, the earliest first, with `next_run` set to the
+  /// run they are due for, see `pending_run`.
+  fn due(now: u64) -> Vec<Cron> {
+    let mut due: Vec<Cron> = Self::all()
+      .into_iter()
+      .filter(|cron| cron.is_due(now))
+      .filter_map(|cron| match cron.schedule() {
+        Ok(schedule) => Some(Cron { next_run: cron.pending_run(&schedule, now), ..cron }),
+        Err(e) => {
+          eprintln!("Skipping cron {}: {}", cron.job_key(), e);
+          None
+        },
+      })
+      .collect();
+
+    due.sort_by_key(|cron| cron.next_run);
+    due
+  }
+
+  /// `complete_cron <program_id>[/<job_id>] <result> [error_text] [--started <ms>] [--now <ms>] [--expected-value-hash <hash>] --signature <sig> [--signer <key>]`
+  ///
+  /// Records the run and moves `next_run` to the first scheduled time after now,
+  /// or to the next missed one under the `catch_up` misfire policy, in one
+  /// commit. Under `skip` the run is for the latest time missed by its start
+  /// and the earlier ones are dropped. `--started` defaults to the time the
+  /// run was due. Signed like the other job changes, see `authorize`.
+  pub fn complete_cron() -> TrieResult {
+    Self::complete(&env::args().collect::<Vec<String>>())
+  }
+
+  fn complete(args: &[String]) -> TrieResult {
+    let (job_key, status) = match (args.get(2), args.get(3).map(|s| s.parse::<u64>())) {
+      (Some(job_key), Some(Ok(status))) => (job_key.clone(), status),
+      _ => return TrieResult { success: false, result: Some("Usage: complete_cron <program_id>[/<job_id>] <result>".to_string()) },
+    };
+
+    let error_text = args.get(4).filter(|arg| !arg.starts_with("--")).cloned().unwrap_or_default();
+
+    let end = match flag_value(args, "--now").map(|now| now.parse::<u64>()) {
+      Some(Ok(now)) => now,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--now expects milliseconds".to_string()) },
+      None => now_ms(),
+    };
+
+    let _lock = WriteLock::acquire();
+
+    let mut cron = match Self::find(&job_key) {
+      Some(cron) => cron,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
+
+    if let Err(e) = precondition::check_args(args, &Self::trie_name(), &cron.primary_key()) {
+      return e;
+    }
+
+    let call = json!({ "method": "complete_cron", "job": job_key, "result": status, "error_text": error_text });
+    if let Err(e) = cron.authorize(args, &call) {
+      return e;
+    }
+
+    if cron.status != CRON_ACTIVE {
+      return TrieResult { success: false, result: Some(format!("Cron {} is {}", job_key, status_name(cron.status))) };
+    }
+
+    let schedule = match cron.schedule() {
+      Ok(schedule) => schedule,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let started = match flag_value(args, "--started").map(|start| start.parse::<u64>()) {
+      Some(Ok(start)) => Some(start),
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--started expects milliseconds".to_string()) },
+      None => None,
+    };
+    let scheduled = if cron.next_run == 0 { end } else { cron.pending_run(&schedule, started.unwrap_or(end)) };
+    let start = started.unwrap_or(scheduled.min(end));
+
+    let run = CronRun {
+      program_id: cron.program_id.clone(),
+      job_id: cron.job_id.clone(),
+      start,
+      end,
+      status,
+      error_text,
+    };
+
+    let after = if cron.misfire == "catch_up" { scheduled } else { end };
+
+    cron.last_run = end;
+    match schedule.next_run(scheduled, after) {
+      Some(next_run) => cron.next_run = next_run,
+      None => cron.status = CRON_COMPLETED,
+    }
+
+    let mut commit = StateCommit::new();
+    commit.put(&[run]);
+    commit.put(&[cron.clone()]);
+    if let Err(e) = commit.commit() {
+      return e;
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&cron).unwrap_or("".to_string())),
+    }
+  }
+
+  /// `pause_cron <program_id>[/<job_id>] --signature <sig> [--signer <key>]`
+  pub fn pause_cron() -> TrieResult {
+    Self::transition(&env::args().collect::<Vec<String>>(), "pause_cron", &[CRON_ACTIVE], CRON_PAUSED)
+  }
+
+  /// `resume_cron <program_id>[/<job_id>] --signature <sig> [--signer <key>]`
+  pub fn resume_cron() -> TrieResult {
+    Self::transition(&env::args().collect::<Vec<String>>(), "resume_cron", &[CRON_PAUSED], CRON_ACTIVE)
+  }
+
+  /// `cancel_cron <program_id>[/<job_id>] --signature <sig> [--signer <key>]`
+  pub fn cancel_cron() -> TrieResult {
+    Self::transition(&env::args().collect::<Vec<String>>(), "cancel_cron", &[CRON_ACTIVE, CRON_PAUSED], CRON_CANCELLED)
+  }
+
+  /// Move the job named by the first argument to `to` if it is in one of the
+  /// `from` states. Takes `--expected-value-hash` like `insert_trie`.
+  fn transition(args: &[String], method: &str, from: &[u64], to: u64) -> TrieResult {
+    let job_key = match args.get(2) {
+      Some(job_key) => job_key.clone(),
+      None => return TrieResult { success: false, result: Some("Missing program id".to_string()) },
+    };
+
+    let _lock = WriteLock::acquire();
+
+    let mut cron = match Self::find(&job_key) {
+      Some(cron) => cron,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
+
+    if let Err(e) = precondition::check_args(args, &Self::trie_name(), &cron.primary_key()) {
+      return e;
+    }
+
+    if let Err(e) = cron.authorize(args, &json!({ "method": method, "job": job_key })) {
+      return e;
+    }
+
+    if !from.contains(&cron.status) {
+      return TrieResult {
+        success: false,
+        result: Some(format!("Cron {} is {}, cannot become {}", job_key, status_name(cron.status), status_name(to))),
+      };
+    }
+
+    cron.status = to;
+    if let Err(e) = insert_records(&[cron.clone()]) {
+      return e;
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&cron).unwrap_or("".to_string())),
+    }
+  }
+
+  /// `next_runs <program_id>[/<job_id>] [--count N] [--now <ms>]`
+  pub fn next_runs() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let job_key = match args.get(2) {
+      Some(job_key) => job_key.clone(),
+      None => return TrieResult { success: false, result: Some("Missing program id".to_string()) },
+    };
+
+    let count = match flag_value(&args, "--count").map(|count| count.parse::<usize>()) {
+      Some(Ok(count)) => count,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--count expects a number".to_string()) },
+      None => 5,
+    };
+
+    let now = match flag_value(&args, "--now").map(|now| now.parse::<u64>()) {
+      Some(Ok(now)) => now,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--now expects milliseconds".to_string()) },
+      None => now_ms(),
+    };
+
+    let cron = match Self::find(&job_key) {
+      Some(cron) => cron,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
+
+    let schedule = match cron.schedule() {
+      Ok(schedule) => schedule,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let mut runs = Vec::new();
+    if cron.status == CRON_ACTIVE {
+      let mut next = Some(if cron.next_run == 0 { now } else { cron.next_run });
+      while let Some(at) = next.filter(|_| runs.len() < count) {
+        runs.push(serde_json::json!({ "at": at, "utc": format_utc(at) }));
+        next = schedule.next_run(at, at);
+      }
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&runs).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Allow `call` on the job only if the signatures in `args` authorise it
+  /// for the job's owner or a grantee with the `cron` role, the way
+  /// `schedule_cron` is authorised. The owner signs
+  /// `write_message(call, <stored job>)`, so a signature is spent once the
+  /// job changed.
+  fn authorize(&self, args: &[String], call: &serde_json::Value) -> Result<(), TrieResult> {
+    let signed = signatures_from_args(args, "")?;
+    authorize_call(&Self::trie_name(), &self.primary_key(), call, &signed)
+  }
+
+  /// The parsed `schedule`, or every `epoch` ms when it is empty. An empty
+  /// schedule with no epoch runs once.
+  pub fn schedule(&self) -> Result<Schedule, String> {
+    if !self.schedule.is_empty() {
+      Schedule::parse(&self.schedule)
+    } else if self.epoch > 0 {
+      Ok(Schedule::Interval(self.epoch))
+    } else {
+      Ok(Schedule::Once(self.next_run))
+    }
+  }
+
+  /// `<program_id>` for the default job, `<program_id>/<job_id>` otherwise.
+  /// Give a job on a cron expression or a one-shot time without a `next_run`
+  /// its first run after `now`.
+  pub fn set_first_run(&mut self, now: u64) -> Result<(), String> {
+    let schedule = self.schedule()?;
+
+    if self.next_run == 0 && !self.schedule.is_empty() && !matches!(schedule, Schedule::Interval(_)) {
+      self.next_run = schedule.next_run(0, now).unwrap_or_default();
+      if self.next_run == 0 {
+        return Err(format!("Schedule {:?} never runs after now", self.schedule));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn job_key(&self) -> String {
+    if self.job_id.is_empty() {
+      self.program_id.clone()
+    } else {
+      format!("{}/{}", self.program_id, self.job_id)
+    }
+  }
+
+  pub fn is_due(&self, now: u64) -> bool {
+    self.status == CRON_ACTIVE && self.next_run <= now
+  }
+
+  /// The scheduled time the job's next run is for: `next_run`, or under the
+  /// `skip` misfire policy the latest of the runs due by `now`.
+  pub fn pending_run(&self, schedule: &Schedule, now: u64) -> u64 {
+    if self.misfire != "skip" || !self.misfired(schedule, now) {
+      return self.next_run;
+    }
+    if let Schedule::Interval(interval) = schedule {
+      return self.next_run + (now - self.next_run) / interval * interval;
+    }
+
+    let mut run = self.next_run;
+    while let Some(next) = schedule.next_run(run, run).filter(|next| *next <= now) {
+      run = next;
+    }
+    run
+  }
+
+  /// Whether the run after the pending one is due as well.
+  pub fn misfired(&self, schedule: &Schedule, now: u64) -> bool {
+    self.next_run > 0 && schedule.next_run(self.next_run, self.next_run).map_or(false, |next| next <= now)
+  }
+
+  fn all() -> Vec<Cron> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .collect()
+  }
+
+  pub fn find(job_key: &str) -> Option<Cron> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(job_key.to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|cron| cron.job_key() == job_key)
+  }
+}
+
+fn status_name(status: u64) -> &'static str {
+  match status {
+    CRON_ACTIVE => "active",
+    CRON_COMPLETED => "completed",
+    CRON_PAUSED => "paused",
+    CRON_CANCELLED => "cancelled",
+    _ => "unknown",
+  }
+}
+
+#[test]
+fn test_cron_legacy_decode() {
+  let mut stream = rlp::RlpStream::new_list(5);
+  stream.append(&"p1").append(&"k").append(&"c").append(&1000u64).append(&CRON_ACTIVE);
+  let legacy = stream.out().to_vec();
+
+  let cron = Cron::decode_record(&legacy).unwrap();
+  assert_eq!((cron.epoch, cron.next_run), (1000, 0));
+  assert!(cron.is_due(0));
+  assert_eq!(Cron::decode_record(&cron.encode_record()).unwrap().epoch, 1000);
+}
+
+#[test]
+fn test_cron_misfire() {
+  let cron: Cron = serde_json::from_value(serde_json::json!({
+    "program_id": "p1", "job_id": "nightly", "public_key": "k", "cid": "c",
+    "epoch": 0, "status": CRON_ACTIVE, "next_run": 1000, "schedule": "@every 1s", "misfire": "skip"
+  })).unwrap();
+  let schedule = cron.schedule().unwrap();
+
+  assert_eq!(cron.job_key(), "p1/nightly");
+  assert!(!cron.misfired(&schedule, 1999));
+  assert!(cron.misfired(&schedule, 2000));
+  assert_eq!(schedule.next_run(cron.next_run, 5500), Some(6000));
+}
+
+/// Jobs of program `p` stored as given, without an owner.
+#[cfg(test)]
+fn store_test_jobs(jobs: &[serde_json::Value]) {
+  let crons: Vec<Cron> = jobs
+    .iter()
+    .map(|job| {
+      let mut fields = json!({ "program_id": "p", "public_key": "", "cid": "c", "epoch": 0, "status": CRON_ACTIVE });
+      fields.as_object_mut().unwrap().extend(job.as_object().unwrap().clone());
+      serde_json::from_value(fields).unwrap()
+    })
+    .collect();
+
+  let mut commit = StateCommit::new();
+  commit.put(&crons);
+  commit.commit().unwrap();
+}
+
+#[test]
+fn test_cron_due() {
+  use crate::db;
+
+  db::scratch(|| {
+    store_test_jobs(&[
+      json!({ "job_id": "late", "next_run": 3000, "schedule": "@every 1s" }),
+      json!({ "job_id": "skip", "next_run": 1000, "schedule": "@every 1s", "misfire": "skip" }),
+      json!({ "job_id": "hourly", "next_run": 1000, "schedule": "0 * * * *", "misfire": "skip" }),
+      json!({ "job_id": "paused", "next_run": 1000, "epoch": 1000, "status": CRON_PAUSED }),
+      json!({ "job_id": "later", "next_run": 5000, "epoch": 1000 }),
+    ]);
+
+    let due: Vec<(String, u64)> = Cron::due(3500).into_iter().map(|cron| (cron.job_id, cron.next_run)).collect();
+    assert_eq!(due, vec![("hourly".to_string(), 1000), ("late".to_string(), 3000), ("skip".to_string(), 3000)]);
+
+    // Picking the run of a misfired job writes nothing.
+    assert_eq!(Cron::find("p/skip").unwrap().next_run, 1000);
+  });
+}
+
+#[test]
+fn test_cron_complete() {
+  use crate::{db, record};
+
+  db::scratch(|| {
+    store_test_jobs(&[
+      json!({ "job_id": "skip", "next_run": 1000, "schedule": "@every 1s", "misfire": "skip" }),
+      json!({ "job_id": "catch", "next_run": 1000, "schedule": "@every 1s", "misfire": "catch_up" }),
+      json!({ "job_id": "once", "next_run": 1000, "schedule": "@at 1970-01-01T00:00:01Z" }),
+    ]);
+    let complete = |job_key: &str, now: &str| -> Cron {
+      let args: Vec<String> = ["world-state", "complete_cron", job_key, "1", "failed", "--now", now].iter().map(|s| s.to_string()).collect();
+      let result = Cron::complete(&args);
+      assert!(result.success, "{:?}", result.result);
+      Cron::find(job_key).unwrap()
+    };
+
+    let skip = complete("p/skip", "3600");
+    assert_eq!((skip.last_run, skip.next_run), (3600, 4000));
+    let catch = complete("p/catch", "3600");
+    assert_eq!(catch.next_run, 2000);
+    assert!(Cron::due(3600).iter().any(|cron| cron.job_id == "catch"));
+    assert_eq!(complete("p/once", "3600").status, CRON_COMPLETED);
+
+    let mut runs: Vec<CronRun> = get_trie_results(&CronRun::trie_name(), &CronRun::db_path(), None)
+      .iter()
+      .filter_map(|val| CronRun::decode_record(val).ok())
+      .collect();
+    runs.sort_by(|a, b| a.job_id.cmp(&b.job_id));
+    let runs: Vec<(&str, u64, u64, u64, &str)> = runs
+      .iter()
+      .map(|run| (run.job_id.as_str(), run.start, run.end, run.status, run.error_text.as_str()))
+      .collect();
+    assert_eq!(runs, vec![("catch", 1000, 3600, 1, "failed"), ("once", 1000, 3600, 1, "failed"), ("skip", 3000, 3600, 1, "failed")]);
+
+    // Runs are only recorded by completing them.
+    assert!(record::find(&CronRun::trie_name()).unwrap().prepare_json(json!({
+      "program_id": "p", "job_id": "skip", "start": 1, "end": 2, "status": 0, "error_text": ""
+    })).is_err());
+  });
+}
+
+#[test]
+fn test_cron_calls_need_owner() {
+  use crate::{auth::{test_signature, write_message}, db};
+
+  db::scratch(|| {
+    let (owner, _) = test_signature(1, "");
+    let cron: Cron = serde_json::from_value(json!({
+      "program_id": "p1", "public_key": owner, "cid": "c", "epoch": 1000, "status": CRON_ACTIVE, "next_run": 1000
+    })).unwrap();
+    let mut commit = StateCommit::new();
+    commit.put(&[cron.clone()]);
+    commit.commit().unwrap();
+
+    let args = |call: &[&str], signature: &str| -> Vec<String> {
+      ["world-state"].iter().chain(call).chain(&["--signature", signature]).map(|s| s.to_string()).collect()
+    };
+
+    let message = write_message(&json!({ "method": "complete_cron", "job": "p1", "result": 0, "error_text": "" }), Some(&cron.encode_record()));
+    let (_, forged) = test_signature(2, &message);
+    assert_eq!(Cron::complete(&args(&["complete_cron", "p1", "0", "--now", "1500"], &forged)).code().as_deref(), Some("unauthorized"));
+
+    let (_, signature) = test_signature(1, &message);
+    assert!(Cron::complete(&args(&["complete_cron", "p1", "0", "--now", "1500"], &signature)).success);
+    let completed = Cron::find("p1").unwrap();
+    assert_eq!((completed.last_run, completed.next_run), (1500, 2000));
+    let runs: Vec<CronRun> = get_trie_results(&CronRun::trie_name(), &CronRun::db_path(), None)
+      .iter()
+      .filter_map(|val| CronRun::decode_record(val).ok())
+      .collect();
+    assert_eq!(runs.iter().map(|run| (run.start, run.end)).collect::<Vec<_>>(), vec![(1000, 1500)]);
+
+    // The signature was over the job as it was before the run.
+    assert_eq!(Cron::complete(&args(&["complete_cron", "p1", "0", "--now", "2500"], &signature)).code().as_deref(), Some("unauthorized"));
+
+    let message = write_message(&json!({ "method": "pause_cron", "job": "p1" }), Some(&completed.encode_record()));
+    let (_, signature) = test_signature(1, &message);
+    assert!(Cron::transition(&args(&["pause_cron", "p1"], &signature), "pause_cron", &[CRON_ACTIVE], CRON_PAUSED).success);
+    assert_eq!(Cron::find("p1").unwrap().status, CRON_PAUSED);
+  });
+}
@@ -0,0 +1,51 @@
+use rlp::{Decodable, DecoderError, Rlp};
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+
+use crate::record::{field_or_default, Record};
+
+/// One execution of a cron job, written by `complete_cron`.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct CronRun {
+  pub program_id: String,
+  pub start: u64,
+  pub end: u64,
+  pub status: u64,
+  pub error_text: String,
+  /// Empty for the program's default job.
+  #[serde(default)]
+  pub job_id: String,
+}
+
+impl Record for CronRun {
+  const CONFIG_PREFIX: &'static str = "CRON_RUN";
+  const VERSION: u64 = 2;
+
+  /// `<program_id>[/<job_id>]/<start>`, zero padded so a job's runs sort by
+  /// time.
+  fn primary_key(&self) -> Vec<u8> {
+    match self.job_id.as_str() {
+      "" => format!("{}/{:020}", self.program_id, self.start).into_bytes(),
+      job_id => format!("{}/{}/{:020}", self.program_id, job_id, self.start).into_bytes(),
+    }
+  }
+
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    match version {
+      0 | 1 => Ok(CronRun {
+        program_id: rlp.val_at(0)?,
+        start: rlp.val_at(1)?,
+        end: rlp.val_at(2)?,
+        status: rlp.val_at(3)?,
+        error_text: rlp.val_at(4)?,
+        job_id: field_or_default(rlp, 5)?,
+      }),
+      Self::VERSION => Self::decode(rlp),
+      _ => Err(DecoderError::Custom("unsupported record version")),
+    }
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Cron runs are only written by complete_cron".to_string())
+  }
+}
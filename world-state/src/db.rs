@@ -1,4 +1,4 @@
-use std::{sync::Arc};
+use std::{cell::{Cell, RefCell}, fs::{self, OpenOptions}, io::{ErrorKind, Write}, path::Path, sync::Arc, thread, time::Duration};
 
 use hash_db::{HashDB, AsHashDB, Hasher, Prefix, HashDBRef};
 use keccak_hasher::KeccakHasher;
@@ -6,8 +6,116 @@ use kvdb::KeyValueDB;
 use kvdb_rocksdb::{DatabaseConfig, Database};
 use trie_db::DBValue;
 
+use crate::appconfig::CONFIG;
+
 pub type KVDB = Arc<dyn KeyValueDB>;
 
+/// Opens dbs under `dir` instead of their configured path, except the
+/// `shared` ones, see `Replay`.
+pub struct Redirect {
+  pub dir: String,
+  pub shared: Vec<String>,
+}
+
+thread_local! {
+  static REDIRECT: RefCell<Option<Redirect>> = RefCell::new(None);
+}
+
+/// Redirect `KVDatabase::open` on this thread, or stop redirecting with `None`.
+/// Returns the redirect it replaces.
+pub fn redirect(redirect: Option<Redirect>) -> Option<Redirect> {
+  REDIRECT.with(|current| current.replace(redirect))
+}
+
+pub fn redirected() -> bool {
+  REDIRECT.with(|current| current.borrow().is_some())
+}
+
+fn resolve_path(db_path: &str) -> String {
+  REDIRECT.with(|current| match current.borrow().as_ref() {
+    Some(redirect) if !redirect.shared.iter().any(|shared| shared == db_path) => {
+      format!("{}/{}", redirect.dir, db_path.trim_start_matches("./").replace('/', "_"))
+    },
+    _ => db_path.to_string(),
+  })
+}
+
+/// Run `test` against empty dbs in a directory of its own.
+#[cfg(test)]
+pub fn scratch<T>(test: impl FnOnce() -> T) -> T {
+  let dir = tempfile::tempdir().unwrap();
+  redirect(Some(Redirect { dir: dir.path().to_string_lossy().to_string(), shared: Vec::new() }));
+
+  let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(test));
+  redirect(None);
+  result.unwrap_or_else(|e| std::panic::resume_unwind(e))
+}
+
+/// A lock file left by a process that died is taken over after this long.
+const STALE_LOCK: Duration = Duration::from_secs(30);
+
+thread_local! {
+  static LOCK_HELD: Cell<bool> = Cell::new(false);
+}
+
+/// Exclusive right to commit, shared by every process using the root db.
+/// `commit_roots` takes it for its own write, a command that checks what it
+/// read, like a precondition, holds it from the check to the commit so no
+/// other commit lands in between. Taking it again on the same thread is a
+/// no-op.
+pub struct WriteLock {
+  path: Option<String>,
+}
+
+impl WriteLock {
+  pub fn acquire() -> WriteLock {
+    if LOCK_HELD.with(|held| held.get()) {
+      return WriteLock { path: None };
+    }
+
+    let path = format!("{}.lock", resolve_path(&CONFIG.get::<String>("ROOT_DB_PATH").unwrap()));
+    if let Some(dir) = Path::new(&path).parent() {
+      fs::create_dir_all(dir).ok();
+    }
+
+    loop {
+      match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+          write!(file, "{}", std::process::id()).ok();
+          break;
+        },
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+          let stale = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(false, |age| age > STALE_LOCK);
+
+          if stale {
+            eprintln!("Taking over the stale write lock {}", path);
+            fs::remove_file(&path).ok();
+          } else {
+            thread::sleep(Duration::from_millis(5));
+          }
+        },
+        Err(e) => panic!("Failed to create the write lock {}: {}", path, e),
+      }
+    }
+
+    LOCK_HELD.with(|held| held.set(true));
+    WriteLock { path: Some(path) }
+  }
+}
+
+impl Drop for WriteLock {
+  fn drop(&mut self) {
+    if let Some(path) = &self.path {
+      fs::remove_file(path).ok();
+      LOCK_HELD.with(|held| held.set(false));
+    }
+  }
+}
+
 // #[derive(Debug)]
 pub struct KVDatabase {
   pub db: KVDB,
@@ -18,7 +126,7 @@ pub struct KVDatabase {
 impl KVDatabase {
   pub fn open(db_path: &str) -> Self {
     let cfg = DatabaseConfig::with_columns(1);
-    let db = Database::open(&cfg, db_path).expect("rocksdb works");
+    let db = Database::open(&cfg, &resolve_path(db_path)).expect("rocksdb works");
     KVDatabase {
       db: Arc::new(db),
       hashed_null_node: KeccakHasher::hash(&[0u8]),
@@ -0,0 +1,262 @@
+use rlp::{DecoderError, Prototype, Rlp, RlpStream};
+use std::{borrow::Borrow, marker::PhantomData, ops::Range};
+use trie_db::{
+	node::{NibbleSlicePlan, NodeHandlePlan, NodePlan, Value, ValuePlan},
+	triedbmut::ChildReference,
+	NodeCodec, TrieConfiguration, TrieLayout,
+};
+use hash_db::Hasher;
+
+use crate::node_codec::RefHasher;
+
+/// Hex-prefix flag of an extension node, `+ 1` when the path is odd.
+const HP_EXTENSION: u8 = 0;
+/// Hex-prefix flag of a leaf node, `+ 1` when the path is odd.
+const HP_LEAF: u8 = 2;
+/// Encoding of the empty trie, the RLP empty string.
+const EMPTY_NODE: [u8; 1] = [0x80];
+
+/// Ethereum Merkle-Patricia trie: hex-prefix paths and RLP nodes, as used by
+/// the state, transaction and receipt tries.
+#[derive(Default, Clone)]
+pub struct EthereumLayout;
+
+impl TrieLayout for EthereumLayout {
+	const USE_EXTENSION: bool = true;
+	const ALLOW_EMPTY: bool = false;
+	const MAX_INLINE_VALUE: Option<u32> = None;
+	type Hash = RefHasher;
+	type Codec = RlpNodeCodec<RefHasher>;
+}
+
+impl TrieConfiguration for EthereumLayout {}
+
+#[derive(Default, Clone)]
+pub struct RlpNodeCodec<H>(PhantomData<H>);
+
+/// Byte range of `item`'s payload inside `data`, `item` being a view into `data`.
+fn payload_range(data: &[u8], item: &Rlp) -> Result<Range<usize>, DecoderError> {
+	let info = item.payload_info()?;
+	let start = item.as_raw().as_ptr() as usize - data.as_ptr() as usize + info.header_len;
+	Ok(start..start + info.value_len)
+}
+
+/// Byte range of the whole encoding of `item` inside `data`.
+fn raw_range(data: &[u8], item: &Rlp) -> Range<usize> {
+	let start = item.as_raw().as_ptr() as usize - data.as_ptr() as usize;
+	start..start + item.as_raw().len()
+}
+
+/// A child reference is either a 32 byte hash or a short node embedded as is.
+fn decode_child<H: Hasher>(data: &[u8], item: &Rlp) -> Result<Option<NodeHandlePlan>, DecoderError> {
+	match item.prototype()? {
+		Prototype::Data(0) => Ok(None),
+		Prototype::Data(len) if len == H::LENGTH => Ok(Some(NodeHandlePlan::Hash(payload_range(data, item)?))),
+		Prototype::List(_) => Ok(Some(NodeHandlePlan::Inline(raw_range(data, item)))),
+		_ => Err(DecoderError::Custom("invalid child reference")),
+	}
+}
+
+fn append_child<H: AsRef<[u8]>>(stream: &mut RlpStream, child: &ChildReference<H>) {
+	match child {
+		ChildReference::Hash(h) => {
+			stream.append(&h.as_ref());
+		},
+		ChildReference::Inline(inline_data, len) => {
+			stream.append_raw(&inline_data.as_ref()[..*len], 1);
+		},
+	}
+}
+
+/// Hex-prefix encoding of a partial path given as trie-db's right aligned bytes.
+fn hex_prefix(mut partial: impl Iterator<Item = u8>, number_nibble: usize, flag: u8) -> Vec<u8> {
+	let mut output = Vec::with_capacity(1 + number_nibble / 2);
+	if number_nibble % 2 == 1 {
+		let first = partial.next().unwrap_or_default();
+		output.push(((flag + 1) << 4) | (first & 0x0f));
+	} else {
+		output.push(flag << 4);
+	}
+	output.extend(partial);
+	output
+}
+
+impl<H: Hasher> NodeCodec for RlpNodeCodec<H> {
+	type Error = DecoderError;
+	type HashOut = H::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		let r = Rlp::new(data);
+		match r.prototype()? {
+			Prototype::Data(0) => Ok(NodePlan::Empty),
+			Prototype::List(2) => {
+				let path = r.at(0)?;
+				let path_range = payload_range(data, &path)?;
+				let first = *data.get(path_range.start).ok_or(DecoderError::RlpIsTooShort)?;
+				let odd = first & 0x10 != 0;
+				let partial = if odd {
+					NibbleSlicePlan::new(path_range.start..path_range.end, 1)
+				} else {
+					NibbleSlicePlan::new(path_range.start + 1..path_range.end, 0)
+				};
+
+				match first >> 4 {
+					0 | 1 => {
+						let child = decode_child::<H>(data, &r.at(1)?)?
+							.ok_or(DecoderError::Custom("extension without child"))?;
+						Ok(NodePlan::Extension { partial, child })
+					},
+					2 | 3 => {
+						let value = payload_range(data, &r.at(1)?)?;
+						Ok(NodePlan::Leaf { partial, value: ValuePlan::Inline(value) })
+					},
+					_ => Err(DecoderError::Custom("invalid hex-prefix flag")),
+				}
+			},
+			Prototype::List(17) => {
+				let mut children = [
+					None, None, None, None, None, None, None, None, None, None, None, None, None,
+					None, None, None,
+				];
+				for (i, child) in children.iter_mut().enumerate() {
+					*child = decode_child::<H>(data, &r.at(i)?)?;
+				}
+				let value = r.at(16)?;
+				let value = if value.is_empty() {
+					None
+				} else {
+					Some(ValuePlan::Inline(payload_range(data, &value)?))
+				};
+				Ok(NodePlan::Branch { value, children })
+			},
+			_ => Err(DecoderError::Custom("invalid node")),
+		}
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		data == <Self as NodeCodec>::empty_node()
+	}
+
+	fn empty_node() -> &'static [u8] {
+		&EMPTY_NODE
+	}
+
+	fn leaf_node(partial: impl Iterator<Item = u8>, number_nibble: usize, value: Value) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(2);
+		stream.append(&&hex_prefix(partial, number_nibble, HP_LEAF)[..]);
+		match value {
+			Value::Inline(value) => {
+				stream.append(&value);
+			},
+			Value::Node(_) => unreachable!("ethereum layout keeps values inline"),
+		}
+		stream.out().to_vec()
+	}
+
+	fn extension_node(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		child: ChildReference<Self::HashOut>,
+	) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(2);
+		stream.append(&&hex_prefix(partial, number_nibble, HP_EXTENSION)[..]);
+		append_child(&mut stream, &child);
+		stream.out().to_vec()
+	}
+
+	fn branch_node(
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<Value>,
+	) -> Vec<u8> {
+		let mut stream = RlpStream::new_list(17);
+		for child in children {
+			match child.borrow() {
+				Some(child) => append_child(&mut stream, child),
+				None => {
+					stream.append_empty_data();
+				},
+			}
+		}
+		match maybe_value {
+			Some(Value::Inline(value)) => {
+				stream.append(&value);
+			},
+			None => {
+				stream.append_empty_data();
+			},
+			Some(Value::Node(_)) => unreachable!("ethereum layout keeps values inline"),
+		}
+		stream.out().to_vec()
+	}
+
+	fn branch_node_nibbled(
+		_partial: impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		_maybe_value: Option<Value>,
+	) -> Vec<u8> {
+		unreachable!("codec with extension branch")
+	}
+}
+
+#[cfg(test)]
+fn ethereum_root(pairs: &[(&[u8], &[u8])]) -> [u8; 32] {
+	use keccak_hasher::KeccakHasher;
+	use memory_db::{HashKey, MemoryDB};
+	use trie_db::{Trie, TrieDBBuilder, TrieDBMutBuilder, TrieMut};
+
+	let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::from_null_node(&EMPTY_NODE, EMPTY_NODE.to_vec());
+	let mut root = Default::default();
+	{
+		let mut trie = TrieDBMutBuilder::<EthereumLayout>::new(&mut db, &mut root).build();
+		for (key, value) in pairs {
+			trie.insert(key, value).expect("trie insertion failed");
+		}
+	}
+
+	let trie = TrieDBBuilder::<EthereumLayout>::new(&db, &root).build();
+	for (key, value) in pairs {
+		assert_eq!(trie.get(key).unwrap().as_deref(), Some(*value));
+	}
+	root
+}
+
+/// Vectors from ethereum/tests `TrieTests/trieanyorder.json` and `trietest.json`.
+#[test]
+fn test_ethereum_trie_vectors() {
+	assert_eq!(
+		hex::encode(ethereum_root(&[])),
+		"56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[(b"foo", b"bar"), (b"food", b"bass")])),
+		"17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[(b"doe", b"reindeer"), (b"dog", b"puppy"), (b"dogglesworth", b"cat")])),
+		"8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[(b"do", b"verb"), (b"horse", b"stallion"), (b"doge", b"coin"), (b"dog", b"puppy")])),
+		"5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[(b"be", b"e"), (b"dog", b"puppy"), (b"bed", b"d")])),
+		"3f67c7a47520f79faa29255d2d3c084a7a6df0453116ed7232ff10277a8be68b",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[(b"test", b"test"), (b"te", b"testy")])),
+		"8452568af70d8d140f58d941338542f645fcca50094b20f3c3d8c3df49337928",
+	);
+	assert_eq!(
+		hex::encode(ethereum_root(&[
+			(&hex::decode("0045").unwrap(), &hex::decode("0123456789").unwrap()),
+			(&hex::decode("4500").unwrap(), &hex::decode("9876543210").unwrap()),
+		])),
+		"285505fcabe84badc8aa310e2aae17eddc7d120aabec8a476902c8184b3a3503",
+	);
+}
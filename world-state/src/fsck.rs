@@ -0,0 +1,189 @@
+use std::env;
+
+use serde::Serialize;
+
+use crate::{
+  db::KVDatabase,
+  get_db_path,
+  layout::{LayoutKind, with_layout},
+  read_layout_tag,
+  read_trie_root,
+  record,
+  trie_walk::{walk_trie, NodeIssue},
+  types::TrieResult,
+};
+
+/// Result of checking every node reachable from a trie root.
+#[derive(Serialize, Debug, Default)]
+pub struct FsckReport {
+  pub trie_key: String,
+  pub root: String,
+  pub layout: String,
+  pub ok: bool,
+  pub nodes: usize,
+  pub values: usize,
+  pub root_error: Option<String>,
+  pub missing: Vec<String>,
+  pub hash_mismatches: Vec<String>,
+  pub undecodable: Vec<String>,
+  pub bad_values: Vec<String>,
+}
+
+pub struct Fsck;
+
+impl Fsck {
+  /// `verify_db <trie_key>`
+  pub fn verify_db() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let trie_key = match args.get(2) {
+      Some(key) => key.clone(),
+      None => return TrieResult { success: false, result: Some("Missing trie key".to_string()) },
+    };
+
+    let db_path = match get_db_path(&trie_key) {
+      Some(path) => path,
+      None => return TrieResult { success: false, result: Some("Unknown trie".to_string()) },
+    };
+
+    let report = Self::check(&trie_key, &db_path);
+
+    TrieResult {
+      success: report.ok,
+      result: Some(serde_json::to_string(&report).unwrap_or("".to_string())),
+    }
+  }
+
+  pub fn check(trie_key: &str, db_path: &str) -> FsckReport {
+    let mut report = FsckReport {
+      trie_key: trie_key.to_string(),
+      ..Default::default()
+    };
+
+    let root = match read_trie_root(trie_key) {
+      Ok(root) => root,
+      Err(len) => {
+        report.root_error = Some(format!("root entry is {} bytes, expected a 32 byte root and its layout tag", len));
+        return report;
+      },
+    };
+    report.root = hex::encode(root);
+
+    let layout = match read_layout_tag(trie_key) {
+      Some(tag) => match LayoutKind::from_tag(tag) {
+        Some(layout) => layout,
+        None => {
+          report.root_error = Some(format!("unknown layout tag {}", tag));
+          return report;
+        },
+      },
+      None => LayoutKind::for_trie(trie_key),
+    };
+    report.layout = format!("{:?}", layout);
+
+    let KVDatabase { db, .. } = KVDatabase::open(db_path);
+
+    let walk = with_layout!(
+      layout,
+      L => walk_trie::<L, _>(&root, |key| db.get(0, key).ok().flatten())
+    );
+
+    report.nodes = walk.nodes.len();
+    report.values = walk.values.len();
+
+    for issue in walk.issues.iter() {
+      match issue {
+        NodeIssue::Missing { .. } => report.missing.push(issue.to_string()),
+        NodeIssue::HashMismatch { .. } => report.hash_mismatches.push(issue.to_string()),
+        NodeIssue::Undecodable { .. } => report.undecodable.push(issue.to_string()),
+      }
+    }
+
+    for (key, value) in walk.values.iter() {
+      if let Some(Err(e)) = record::find(trie_key).map(|record| record.decode_json(value)) {
+        report.bad_values.push(format!("{}: {}", String::from_utf8_lossy(key), e));
+      }
+    }
+
+    report.ok = report.missing.is_empty()
+      && report.hash_mismatches.is_empty()
+      && report.undecodable.is_empty()
+      && report.bad_values.is_empty();
+
+    report
+  }
+}
+
+#[test]
+fn test_check() {
+  use crate::{appconfig::CONFIG, apply::test_tx, auth::test_signature, db, record::Record, state::StateCommit, transaction::Transaction, get_trie_root};
+
+  db::scratch(|| {
+    let (signer, _) = test_signature(1, "");
+    let mut commit = StateCommit::new();
+    commit.put(&[test_tx("h1", "set_metadata", &signer, "c1"), test_tx("h2", "set_metadata", &signer, "c2")]);
+    commit.commit().unwrap();
+
+    let (trie_key, db_path) = (Transaction::trie_name(), Transaction::db_path());
+    let report = Fsck::check(&trie_key, &db_path);
+    assert!(report.ok && report.root_error.is_none());
+    assert_eq!(report.values, 2);
+
+    let root = get_trie_root(&trie_key).unwrap();
+    let (leaf, stored) = KVDatabase::open(&db_path)
+      .db
+      .iter(0)
+      .filter_map(|item| item.ok())
+      .map(|(key, value)| (key.to_vec(), value))
+      .find(|(key, _)| key[..] != root[..])
+      .unwrap();
+    let write = |value: Option<&[u8]>| {
+      let KVDatabase { db, .. } = KVDatabase::open(&db_path);
+      let mut transaction = db.transaction();
+      match value {
+        Some(value) => transaction.put(0, &leaf, value),
+        None => transaction.delete(0, &leaf),
+      }
+      db.write(transaction).unwrap();
+    };
+
+    write(None);
+    let report = Fsck::check(&trie_key, &db_path);
+    assert!(!report.ok);
+    assert_eq!(report.missing.len(), 1);
+    assert!(report.missing[0].contains(&hex::encode(&leaf)));
+
+    let mut corrupted = stored.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    write(Some(&corrupted));
+    let report = Fsck::check(&trie_key, &db_path);
+    assert!(!report.ok);
+    assert_eq!(report.hash_mismatches.len(), 1);
+    assert!(report.hash_mismatches[0].contains(&hex::encode(&leaf)));
+
+    write(Some(&[0xff, 0xff, 0xff]));
+    let report = Fsck::check(&trie_key, &db_path);
+    assert!(!report.ok);
+    assert_eq!(report.undecodable.len(), 1);
+
+    write(Some(&stored));
+    assert!(Fsck::check(&trie_key, &db_path).ok);
+
+    {
+      let KVDatabase { db: root_db, .. } = KVDatabase::open(&CONFIG.get::<String>("ROOT_DB_PATH").unwrap());
+      let mut transaction = root_db.transaction();
+      transaction.put(0, trie_key.as_bytes(), &root[..5]);
+      root_db.write(transaction).unwrap();
+    }
+    let report = Fsck::check(&trie_key, &db_path);
+    assert!(!report.ok);
+    assert_eq!(report.root_error.as_deref(), Some("root entry is 5 bytes, expected a 32 byte root and its layout tag"));
+
+    // Reads and writes of the trie fail instead of panicking.
+    assert_eq!(get_trie_root(&trie_key).unwrap_err(), format!("Root entry of {} is 5 bytes long, run verify_db", trie_key));
+    assert!(Transaction::find("h1").is_none());
+    let mut commit = StateCommit::new();
+    commit.put(&[test_tx("h3", "set_metadata", &signer, "c3")]);
+    assert!(!commit.commit().unwrap_err().success);
+  });
+}
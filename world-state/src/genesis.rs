@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fs};
+
+use hash_db::Hasher;
+use keccak_hasher::KeccakHasher;
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+  appconfig::CONFIG,
+  auth::signed_message,
+  commit_roots,
+  cron::Cron,
+  db::WriteLock,
+  get_trie_results,
+  get_trie_root,
+  metacontract::MetaContract,
+  metadata::Metadata,
+  record::Record,
+  state::{StagedRoot, StateCommit, TrieRoot},
+  transaction::Transaction,
+  types::TrieResult,
+};
+
+/// The genesis file `init` reads. Crons get their first run after
+/// `timestamp`, metadata versions are chained in the order given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisSpec {
+  pub chain_id: String,
+  pub timestamp: u64,
+  /// Network settings, which the `Config.toml` of every node must agree with.
+  #[serde(default)]
+  pub config: Map<String, Value>,
+  #[serde(default)]
+  pub metacontracts: Vec<MetaContract>,
+  #[serde(default)]
+  pub metadata: Vec<Metadata>,
+  #[serde(default)]
+  pub crons: Vec<Cron>,
+}
+
+/// The initial state of the chain, written once by `init`. `hash` commits to
+/// the genesis file and the roots built from it.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct Genesis {
+  pub hash: String,
+  pub chain_id: String,
+  pub timestamp: u64,
+  pub roots: Vec<TrieRoot>,
+  /// The genesis file as canonical JSON.
+  pub spec: String,
+}
+
+impl Record for Genesis {
+  const CONFIG_PREFIX: &'static str = "GENESIS";
+
+  fn primary_key(&self) -> Vec<u8> {
+    b"genesis".to_vec()
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("The genesis is only written by init".to_string())
+  }
+}
+
+impl Genesis {
+  /// `init <genesis file>`
+  ///
+  /// Builds the tries of the genesis file and stores them with the genesis
+  /// record in one commit. Initialising again with the same file returns
+  /// the stored genesis, any other file or a database already holding state
+  /// is refused.
+  pub fn init(path: &str) -> TrieResult {
+    let spec: GenesisSpec = match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| {
+      serde_json::from_str(&text).map_err(|e| e.to_string())
+    }) {
+      Ok(spec) => spec,
+      Err(e) => return TrieResult { success: false, result: Some(format!("Error reading genesis {}: {}", path, e)) },
+    };
+    let canonical = signed_message(&json!(spec));
+
+    let _lock = WriteLock::acquire();
+
+    if let Some(genesis) = Self::find() {
+      if genesis.spec == canonical {
+        return TrieResult { success: true, result: Some(serde_json::to_string(&genesis).unwrap_or("".to_string())) };
+      }
+      return TrieResult::error(
+        "genesis_mismatch",
+        format!("The database was initialised with genesis {}", genesis.hash),
+        json!({ "stored_hash": genesis.hash }),
+      );
+    }
+
+    let mut occupied: Vec<String> = Vec::new();
+    for trie_key in [Transaction::trie_name(), Metadata::trie_name(), MetaContract::trie_name(), Cron::trie_name()] {
+      match get_trie_root(&trie_key) {
+        Ok(root) if root == [0u8; 32] => (),
+        Ok(_) => occupied.push(trie_key),
+        Err(e) => return TrieResult { success: false, result: Some(e) },
+      }
+    }
+    if !occupied.is_empty() {
+      return TrieResult::error("not_empty", "The database already holds state".to_string(), json!({ "tries": occupied }));
+    }
+
+    let conflicts = spec.config_conflicts();
+    if !conflicts.is_empty() {
+      return TrieResult::error("config_mismatch", "Config.toml disagrees with the genesis".to_string(), json!({ "settings": conflicts }));
+    }
+
+    let mut roots = match spec.stage() {
+      Ok(roots) => roots,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let trie_roots: Vec<TrieRoot> = roots
+      .iter()
+      .map(|staged| TrieRoot { trie_key: staged.trie_key.clone(), root: hex::encode(staged.root) })
+      .collect();
+    let genesis = Genesis {
+      hash: Self::hash_of(&canonical, &trie_roots),
+      chain_id: spec.chain_id.clone(),
+      timestamp: spec.timestamp,
+      roots: trie_roots,
+      spec: canonical,
+    };
+
+    if let Ok(expected) = CONFIG.get::<String>("GENESIS_HASH") {
+      if genesis.hash != expected {
+        return TrieResult::error(
+          "genesis_mismatch",
+          format!("The genesis hashes to {}, not the configured {}", genesis.hash, expected),
+          json!({ "hash": genesis.hash, "expected_hash": expected }),
+        );
+      }
+    }
+
+    let mut commit = StateCommit::new();
+    commit.put(&[genesis.clone()]);
+    match commit.stage() {
+      Ok(staged) => roots.extend(staged),
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    }
+    if let Err(e) = commit_roots(roots) {
+      return e;
+    }
+
+    TrieResult { success: true, result: Some(serde_json::to_string(&genesis).unwrap_or("".to_string())) }
+  }
+
+  /// Refuses to run against a database whose genesis isn't the configured
+  /// `GENESIS_HASH`. Only `init` may run before there is a genesis.
+  pub fn check(method: &str) -> Result<(), TrieResult> {
+    let expected = match CONFIG.get::<String>("GENESIS_HASH") {
+      Ok(expected) => expected,
+      Err(_) => return Ok(()),
+    };
+
+    match Self::find() {
+      Some(genesis) if genesis.hash != expected => Err(TrieResult::error(
+        "genesis_mismatch",
+        format!("The database was initialised with genesis {}, not the configured {}", genesis.hash, expected),
+        json!({ "stored_hash": genesis.hash, "expected_hash": expected }),
+      )),
+      None if method != "init" => Err(TrieResult::error(
+        "genesis_missing",
+        "The database has no genesis, run init with the genesis file".to_string(),
+        json!({ "expected_hash": expected }),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn find() -> Option<Genesis> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .find_map(|val| Self::decode_record(val).ok())
+  }
+
+  /// Build the genesis tries again in the current databases, see `replay`.
+  pub fn restore(&self) -> Result<(), TrieResult> {
+    let error = |e: String| TrieResult { success: false, result: Some(e) };
+
+    let spec: GenesisSpec = serde_json::from_str(&self.spec).map_err(|e| error(format!("Error decoding genesis: {}", e)))?;
+    commit_roots(spec.stage().map_err(error)?)
+  }
+
+  fn hash_of(spec: &str, roots: &[TrieRoot]) -> String {
+    let message = format!("{}{}", spec, signed_message(&json!(roots)));
+    hex::encode(KeccakHasher::hash(message.as_bytes()))
+  }
+}
+
+impl GenesisSpec {
+  /// Settings of `config` and the chain id that `Config.toml` sets to
+  /// something else.
+  fn config_conflicts(&self) -> Vec<Value> {
+    let mut settings = self.config.clone();
+    settings.insert("CHAIN_ID".to_string(), json!(self.chain_id));
+
+    settings
+      .into_iter()
+      .filter_map(|(key, value)| {
+        let genesis = value.as_str().map(str::to_string).unwrap_or(value.to_string());
+        match CONFIG.get::<String>(&key) {
+          Ok(configured) if configured != genesis => Some(json!({ "key": key, "configured": configured, "genesis": genesis })),
+          _ => None,
+        }
+      })
+      .collect()
+  }
+
+  /// Stage the records of the genesis and return the roots to commit.
+  fn stage(&self) -> Result<Vec<StagedRoot>, String> {
+    let mut commit = StateCommit::new();
+
+    let mut contracts = Vec::new();
+    for contract in self.metacontracts.iter() {
+      if contracts.iter().any(|c: &MetaContract| c.program_id == contract.program_id) {
+        return Err(format!("{} is registered twice", contract.program_id));
+      }
+      let mut contract = contract.clone();
+      contract.prepare()?;
+      contracts.push(contract);
+    }
+    commit.put(&contracts);
+
+    let mut chains: HashMap<(String, String), Vec<Metadata>> = HashMap::new();
+    for metadata in self.metadata.iter() {
+      let mut metadata = Metadata { seq: 0, previous: String::new(), ..metadata.clone() };
+      metadata.prepare()?;
+
+      let chain = chains.entry((metadata.program_id.clone(), metadata.data_key.clone())).or_default();
+      if chain.iter().any(|m| m.version == metadata.version) {
+        return Err(format!("Version {} of {}/{} is given twice", metadata.version, metadata.program_id, metadata.data_key));
+      }
+      metadata.seq = chain.len() as u64 + 1;
+      metadata.previous = chain.last().map(|last| last.hash.clone()).unwrap_or_default();
+      chain.push(metadata);
+    }
+    for chain in chains.values() {
+      commit.put(chain);
+    }
+
+    let mut crons = Vec::new();
+    for cron in self.crons.iter() {
+      let mut cron = cron.clone();
+      cron.set_first_run(self.timestamp)?;
+      cron.prepare()?;
+      crons.push(cron);
+    }
+    commit.put(&crons);
+
+    commit.stage()
+  }
+}
+
+#[test]
+fn test_genesis_record() {
+  let genesis = Genesis {
+    hash: "h".into(),
+    chain_id: "7".into(),
+    timestamp: 1,
+    roots: vec![TrieRoot { trie_key: "metadata".into(), root: "ab".into() }, TrieRoot { trie_key: "cron".into(), root: "cd".into() }],
+    spec: "{}".into(),
+  };
+
+  let decoded = Genesis::decode_record(&genesis.encode_record()).unwrap();
+  assert_eq!((decoded.chain_id.as_str(), decoded.roots), ("7", genesis.roots));
+}
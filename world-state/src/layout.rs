@@ -0,0 +1,97 @@
+use crate::{appconfig::CONFIG, read_layout_tag, record};
+
+/// Inline value thresholds a `HashedValueLayout` can be configured with.
+pub const INLINE_THRESHOLDS: [u32; 8] = [32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Trie layout selected for a trie in `Config.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutKind {
+  Extension,
+  HashedValue(u32),
+  NoExtension,
+  Ethereum,
+}
+
+impl LayoutKind {
+  /// Layout of `trie_key` as set by `<TRIE>_LAYOUT` (`extension` by default,
+  /// `no_extension` or `ethereum`). With the extension layout, values of `<TRIE>_MAX_INLINE_VALUE`
+  /// bytes or more are stored by hash, without the setting every value is inline.
+  pub fn for_trie(trie_key: &str) -> Self {
+    let prefix = record::find(trie_key).map(|record| record.config_prefix().to_string()).unwrap_or_default();
+
+    let layout = CONFIG.get::<String>(&format!("{}_LAYOUT", prefix)).unwrap_or("extension".to_string());
+    let threshold = CONFIG.get::<u32>(&format!("{}_MAX_INLINE_VALUE", prefix)).ok();
+
+    match (layout.as_str(), threshold) {
+      ("extension", None) => LayoutKind::Extension,
+      ("extension", Some(t)) if INLINE_THRESHOLDS.contains(&t) => LayoutKind::HashedValue(t),
+      ("extension", Some(t)) => panic!(
+        "{}_MAX_INLINE_VALUE = {} is not supported, use one of {:?}",
+        prefix, t, INLINE_THRESHOLDS,
+      ),
+      ("no_extension", None) => LayoutKind::NoExtension,
+      ("ethereum", None) => LayoutKind::Ethereum,
+      ("no_extension" | "ethereum", Some(_)) => panic!("{}_MAX_INLINE_VALUE is not supported by the {} layout", prefix, layout),
+      (other, _) => panic!("{}_LAYOUT = {:?} is not a known layout", prefix, other),
+    }
+  }
+
+  /// Layout `trie_key` was built with, as recorded next to its root. Roots
+  /// written before layouts were recorded use the configured layout.
+  pub fn of_trie(trie_key: &str) -> Self {
+    match read_layout_tag(trie_key) {
+      Some(tag) => Self::from_tag(tag)
+        .unwrap_or_else(|| panic!("unknown layout tag {} recorded for {}, run verify_db", tag, trie_key)),
+      None => Self::for_trie(trie_key),
+    }
+  }
+
+  /// Byte stored after the root in the root db.
+  pub fn tag(&self) -> u8 {
+    match self {
+      LayoutKind::Extension => 0,
+      LayoutKind::NoExtension => 1,
+      LayoutKind::Ethereum => 2,
+      LayoutKind::HashedValue(t) => 0x10 + INLINE_THRESHOLDS.iter().position(|x| x == t).unwrap_or_default() as u8,
+    }
+  }
+
+  pub fn from_tag(tag: u8) -> Option<Self> {
+    match tag {
+      0 => Some(LayoutKind::Extension),
+      1 => Some(LayoutKind::NoExtension),
+      2 => Some(LayoutKind::Ethereum),
+      t if t >= 0x10 => INLINE_THRESHOLDS.get((t - 0x10) as usize).map(|t| LayoutKind::HashedValue(*t)),
+      _ => None,
+    }
+  }
+
+  /// Root db entry for `root` built with this layout.
+  pub fn root_entry(&self, root: &[u8; 32]) -> Vec<u8> {
+    let mut entry = root.to_vec();
+    entry.push(self.tag());
+    entry
+  }
+}
+
+/// Evaluate `$body` with `$L` bound to the `TrieLayout` type of `$kind`.
+macro_rules! with_layout {
+  ($kind:expr, $L:ident => $body:expr) => {
+    match $kind {
+      $crate::layout::LayoutKind::Extension => { type $L = $crate::node_codec::ExtensionLayout; $body },
+      $crate::layout::LayoutKind::HashedValue(32) => { type $L = $crate::node_codec::HashedValueLayout<32>; $body },
+      $crate::layout::LayoutKind::HashedValue(64) => { type $L = $crate::node_codec::HashedValueLayout<64>; $body },
+      $crate::layout::LayoutKind::HashedValue(128) => { type $L = $crate::node_codec::HashedValueLayout<128>; $body },
+      $crate::layout::LayoutKind::HashedValue(256) => { type $L = $crate::node_codec::HashedValueLayout<256>; $body },
+      $crate::layout::LayoutKind::HashedValue(512) => { type $L = $crate::node_codec::HashedValueLayout<512>; $body },
+      $crate::layout::LayoutKind::HashedValue(1024) => { type $L = $crate::node_codec::HashedValueLayout<1024>; $body },
+      $crate::layout::LayoutKind::HashedValue(2048) => { type $L = $crate::node_codec::HashedValueLayout<2048>; $body },
+      $crate::layout::LayoutKind::HashedValue(4096) => { type $L = $crate::node_codec::HashedValueLayout<4096>; $body },
+      $crate::layout::LayoutKind::HashedValue(t) => panic!("unsupported inline value threshold {}", t),
+      $crate::layout::LayoutKind::NoExtension => { type $L = $crate::node_codec::NoExtensionLayout; $body },
+      $crate::layout::LayoutKind::Ethereum => { type $L = $crate::ethereum_codec::EthereumLayout; $body },
+    }
+  };
+}
+
+pub(crate) use with_layout;
@@ -1,42 +1,75 @@
 extern crate alloc;
 
 use crate::{
-  node_codec::ExtensionLayout, transaction::Transaction, simple_trie::SimpleTrie
+  node_codec::{ExtensionLayout, HashedValueLayout}, transaction::Transaction, simple_trie::SimpleTrie
 };
+use account::Account;
+use acl::Grant;
+use apply::Apply;
+use block::BlockHeader;
 use anyhow::{Result, anyhow, Error};
 use appconfig::CONFIG;
 use cron::Cron;
-use db::{KVDB, KVDatabase};
+use db::{KVDB, KVDatabase, WriteLock};
+use fsck::Fsck;
+use genesis::Genesis;
 use keccak_hasher::{keccak_256, KeccakHasher};
+use layout::{LayoutKind, with_layout};
+use metadata::Metadata;
+use migrate::Migrate;
+use ownership::Ownership;
 use kvdb::{KeyValueDB, DBValue};
 use kvdb_rocksdb::{Database, DatabaseConfig};
 use memory_db::{MemoryDB, HashKey};
 use hash_db::{Hasher, AsHashDB, HashDB, HashDBRef, Prefix};
-use metacontract::MetaContract;
-use metadata::Metadata;
+use precondition::Precondition;
+use proof::Proof;
+use reconcile::Reconcile;
+use replay::Replay;
 use rqlite::RQLite;
-use transaction_receipt::TransactionReceipt;
-use trie_db::{TrieDBMutBuilder, TrieMut, TrieDBNodeIterator, TrieDBBuilder, TrieLayout, node::{NodePlan, ValuePlan, Node, Value}, TrieDB, Trie, TrieDBMut};
+use schema::Schema;
+use state::{StagedRoot, TrieRoot};
+use trie_db::{TrieDBMutBuilder, TrieMut, TrieDBNodeIterator, TrieDBBuilder, TrieLayout, NodeCodec, node::{NodePlan, ValuePlan, Node, Value}, TrieDB, Trie, TrieDBMut};
 use hex_literal::hex;
 use rlp::{encode, decode, Decodable, Rlp, DecoderError};
-use types::{TrieResult, DecodableEnum};
+use types::TrieResult;
 use serde_json::{Value as SerdeValue};
-use serde::Serialize;
 
 use std::{env, sync::Arc, collections::HashMap, ops::Deref, any::Any};
 
+mod account;
+mod acl;
+mod apply;
+mod block;
 mod appconfig;
+mod auth;
+mod layout;
 mod node_codec;
+mod ethereum_codec;
 mod transaction;
 mod transaction_receipt;
 mod cron;
+mod cron_run;
 mod metadata;
 mod metacontract;
+mod ownership;
 mod simple_trie;
 mod db;
 mod types;
 mod utils;
 mod rqlite;
+mod schedule;
+mod migrate;
+mod precondition;
+mod proof;
+mod record;
+mod schema;
+mod state;
+mod trie_walk;
+mod reconcile;
+mod replay;
+mod fsck;
+mod genesis;
 
 fn main() -> Result<()> {
   let args: Vec<String> = env::args().collect();
@@ -44,23 +77,73 @@ fn main() -> Result<()> {
 
   // println!("method: {:?}", method);
 
+  if let Err(e) = Genesis::check(method) {
+    println!("{:?}", serde_json::to_string(&e).unwrap_or("".to_string()));
+    return Ok(());
+  }
+
   let result = match method.as_str() {
     "init" => init(),
     "insert_tx" => Transaction::insert_tx(),
     "get_pending_tx" => Transaction::get_pending_tx(),
     "filter_trie" => filter_trie(),
     "update_tx_status" => Transaction::update_tx_status(),
+    "expire_pending" => Transaction::expire_pending(),
+    "apply_tx" => Apply::apply_tx(),
+    "apply_pending" => Apply::apply_pending(),
+    "produce_block" => BlockHeader::produce_block(),
+    "get_block" => BlockHeader::get_block(),
+    "get_latest_block" => BlockHeader::get_latest_block(),
+    "get_due_cron" => Cron::get_due_cron(),
+    "complete_cron" => Cron::complete_cron(),
+    "next_runs" => Cron::next_runs(),
+    "pause_cron" => Cron::pause_cron(),
+    "resume_cron" => Cron::resume_cron(),
+    "cancel_cron" => Cron::cancel_cron(),
+    "reconcile" => Reconcile::reconcile(),
+    "replay" => Replay::replay(),
+    "verify_db" => Fsck::verify_db(),
+    "register_schema" => Schema::register_schema(),
+    "migrate" => Migrate::migrate(),
+    "get_metadata" => Metadata::get_metadata(),
+    "get_metadata_history" => Metadata::get_metadata_history(),
+    "get_metadata_latest" => Metadata::get_metadata_latest(),
+    "verify_proof" => Proof::verify_proof(),
+    "get_state_root" => TrieRoot::get_state_root(),
+    "transfer_program" => Ownership::transfer_program(),
+    "grant" => Grant::grant(),
+    "revoke" => Grant::revoke(),
+    "list_grants" => Grant::list_grants(),
+    "get_nonce" => Account::get_nonce(),
     "insert_trie" => {
       let trie_key =  args[2].as_str();
       let trie_value =  args[3].as_str();
 
-      insert_trie(trie_key, trie_value)
+      match auth::signatures_from_args(&args, "") {
+        Ok(signed) => insert_trie(trie_key, trie_value, Precondition::from_args(&args), &signed),
+        Err(e) => e,
+      }
     },
     "insert_trie_batch" => {
       let trie_key =  args[2].as_str();
       let trie_value =  args[3].as_str();
 
-      insert_trie_batch(trie_key, trie_value)
+      let preconditions = utils::flag_value(&args, "--preconditions").map(|arg| serde_json::from_str(&arg)).transpose();
+      let signers = utils::flag_value(&args, "--signers").map(|arg| serde_json::from_str(&arg)).transpose();
+      let signatures = utils::flag_value(&args, "--signatures").map(|arg| serde_json::from_str(&arg)).transpose();
+
+      match (preconditions, signers, signatures) {
+        (Ok(preconditions), Ok(signers), Ok(signatures)) => insert_trie_batch(
+          trie_key,
+          trie_value,
+          preconditions.unwrap_or_default(),
+          signers.unwrap_or_default(),
+          signatures.unwrap_or_default(),
+        ),
+        (Err(e), _, _) => TrieResult { success: false, result: Some(format!("Error decoding preconditions: {}", e)) },
+        (_, Err(e), _) => TrieResult { success: false, result: Some(format!("Error decoding signers: {}", e)) },
+        (_, _, Err(e)) => TrieResult { success: false, result: Some(format!("Error decoding signatures: {}", e)) },
+      }
     },
     _ => TrieResult { success: false, result: None },
   };
@@ -70,288 +153,210 @@ fn main() -> Result<()> {
   Ok(())
 }
 
+/// `init [<genesis file>]`, see `Genesis::init`.
 fn init() -> TrieResult {
   RQLite::create_tables();
-  TrieResult { success: true, result: None }
+
+  match env::args().nth(2) {
+    Some(path) => Genesis::init(&path),
+    None => TrieResult { success: true, result: None },
+  }
 }
 
+/// Insert every record of the JSON array `trie_value` in one write.
+/// `preconditions`, `signers` and `signatures` are matched to the records by position,
+/// `null` or a missing entry for none. A `signatures` entry may also be a list
+/// of `{"signer", "signature"}` for records owned by a multisig policy. Nothing is written unless every record
+/// decodes, every precondition holds and every record of an owner is signed
+/// for.
 fn insert_trie_batch(
   trie_key: &str,
   trie_value: &str,
+  preconditions: Vec<Option<Precondition>>,
+  signers: Vec<Option<String>>,
+  signatures: Vec<Option<auth::BatchSignatures>>,
 ) -> TrieResult {
-  let mut success = false;
-  // let mut result = None;
-
-  let mut values: Vec<String> = Vec::new();
-
-  match trie_key {
-    "tx" => {
-      let t: Vec<Transaction> = serde_json::from_str(trie_value).unwrap();
-      values = t
-              .into_iter()
-              .map(|f| serde_json::to_string(&f).unwrap())
-              .collect();
-     
-    },
-    "cron" => {
-      let t: Vec<Cron> = serde_json::from_str(trie_value).unwrap();
-      values = t
-        .into_iter()
-        .map(|f| serde_json::to_string(&f).unwrap())
-        .collect();
-      
-    },
-    "receipt" => {
-      let t: Vec<TransactionReceipt> = serde_json::from_str(trie_value).unwrap();
-      values = t
-        .into_iter()
-        .map(|f| serde_json::to_string(&f).unwrap())
-        .collect();
-    },
-    "metadata" => {
-      let t: Vec<Metadata> = serde_json::from_str(trie_value).unwrap();
-      values = t
-        .into_iter()
-        .map(|f| serde_json::to_string(&f).unwrap())
-        .collect();
-    },
-    "metacontract" => {
-      let t: Vec<MetaContract> = serde_json::from_str(trie_value).unwrap();
-      values = t
-        .into_iter()
-        .map(|f| serde_json::to_string(&f).unwrap())
-        .collect();
-    },
-    _ => {
-      success = false;
-    },
+  let record = match record::find(trie_key) {
+    Some(record) => record,
+    None => return TrieResult { success: false, result: None },
+  };
+
+  let values: Vec<SerdeValue> = match serde_json::from_str(trie_value) {
+    Ok(values) => values,
+    Err(e) => return TrieResult { success: false, result: Some(format!("Error decoding {}: {}", trie_key, e)) },
   };
 
-  if values.len() > 0 {
-    for val in values {
-      insert_trie(trie_key, &val);
+  if preconditions.len() > values.len() || signers.len() > values.len() || signatures.len() > values.len() {
+    return TrieResult { success: false, result: Some("More preconditions, signers or signatures than records".to_string()) };
+  }
+
+  let _lock = WriteLock::acquire();
+
+  let mut pairs = Vec::new();
+  for (i, value) in values.into_iter().enumerate() {
+    let prepared = match record.prepare_json(value.clone()) {
+      Ok(prepared) => prepared,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    if let Some(Some(precondition)) = preconditions.get(i) {
+      if let Err(e) = precondition.check(trie_key, &prepared.replaces) {
+        return e;
+      }
+    }
+
+    let signed = match signatures.get(i).cloned().flatten() {
+      Some(signatures) => signatures.signed(signers.get(i).cloned().flatten()),
+      None => Vec::new(),
+    };
+    if let Err(e) = auth::authorize(trie_key, &prepared, &value, &signed) {
+      return e;
+    }
+    pairs.push((prepared.key, prepared.value));
+  }
+
+  if pairs.len() > 0 {
+    if let Err(e) = build_trie_db(trie_key, &record.db_path(), &pairs) {
+      return e;
     }
   }
 
   TrieResult { 
-    success, 
+    success: true, 
     result: Some(trie_value.to_string()), 
   }
 }
 
+/// Insert one record. Replacing an owned record, or writing one for a program
+/// with a metacontract, needs signatures of `auth::write_message` by the
+/// owner or by a signer granted access, see `auth::authorize`.
 fn insert_trie(
   trie_key: &str,
   trie_value: &str,
+  precondition: Option<Precondition>,
+  signed: &[auth::Signed],
 ) -> TrieResult {
-  println!("trie_key: {:?}, trie_value: {:?}", trie_key, trie_value);
-  // let args: Vec<String> = env::args().collect();
-
-  // let trie_key = args[2].as_str();
-  // let trie_value = args[3].as_str();
-
-  let db_path;
-  let new_key;
-  let new_value;
+  let record = match record::find(trie_key) {
+    Some(record) => record,
+    None => return TrieResult { success: false, result: None },
+  };
 
-  let mut success = false;
-  let mut result = None;
+  let prepared = serde_json::from_str::<SerdeValue>(trie_value)
+    .map_err(|e| format!("Error decoding {}: {}", trie_key, e))
+    .and_then(|value| record.prepare_json(value.clone()).map(|prepared| (value, prepared)));
 
-  // println!("trie_key: {:?}, trie_value: {:?}", trie_key, trie_value);
+  match prepared {
+    Ok((value, prepared)) => {
+      let _lock = WriteLock::acquire();
 
-  match trie_key {
-    "tx" => {
-      db_path = CONFIG.get::<String>("TX_DB_PATH").unwrap();
-      let p: Transaction = serde_json::from_str(trie_value).unwrap();
-      new_key = Some(p.hash.as_bytes().to_vec());
-      new_value = Some(encode(&p).to_vec());
-    },
-    "cron" => {
-      db_path = CONFIG.get::<String>("CRON_DB_PATH").unwrap();
-      let p: Cron = serde_json::from_str(trie_value).unwrap();
-      new_key = Some(p.program_id.as_bytes().to_vec());
-      new_value = Some(encode(&p).to_vec());
-    },
-    "receipt" => {
-      db_path = CONFIG.get::<String>("TX_RECEIPT_DB_PATH").unwrap();
-      let p: TransactionReceipt = serde_json::from_str(trie_value).unwrap();
-      new_key = Some(p.hash.as_bytes().to_vec());
-      new_value = Some(encode(&p).to_vec());
-    },
-    "metadata" => {
-      db_path = CONFIG.get::<String>("METADATA_DB_PATH").unwrap();
-      let p: Metadata = serde_json::from_str(trie_value).unwrap();
-      new_key = Some(p.hash.as_bytes().to_vec());
-      println!("new_key: {:?} {:?}", p.hash, hex::encode(new_key.clone().unwrap()));
-      new_value = Some(encode(&p).to_vec());
-    },
-    "metacontract" => {
-      db_path = CONFIG.get::<String>("METACONTRACT_DB_PATH").unwrap();
-      let p: MetaContract = serde_json::from_str(trie_value).unwrap();
-      new_key = Some(p.program_id.as_bytes().to_vec());
-      new_value = Some(encode(&p).to_vec());
-    },
-    _ => {
-      db_path = "".to_string();
-      new_key = None;
-      new_value = None;
-    },
-  };
+      if let Some(Err(e)) = precondition.map(|precondition| precondition.check(trie_key, &prepared.replaces)) {
+        return e;
+      }
 
-  if new_key.is_some() {
-    let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
-      (new_key.unwrap(), new_value.unwrap()),
-    ];
+      if let Err(e) = auth::authorize(trie_key, &prepared, &value, signed) {
+        return e;
+      }
 
-    build_trie_db::<ExtensionLayout>(
-      trie_key, 
-      &db_path, 
-      &pairs,
-    );
-    success = true;
-    result = Some(trie_value.to_string());
-  }
+      if let Err(e) = build_trie_db(
+        trie_key, 
+        &record.db_path(), 
+        &[(prepared.key, prepared.value)],
+      ) {
+        return e;
+      }
 
-  TrieResult { 
-    success, 
-    result, 
+      TrieResult { success: true, result: Some(trie_value.to_string()) }
+    },
+    Err(e) => TrieResult { success: false, result: Some(e) },
   }
 }
 
-fn child_filter<T>(items: Result<T, DecoderError>, filter_array: Vec<SerdeValue>) -> Option<SerdeValue> 
-where 
-  T: Clone + Into<HashMap<String, SerdeValue>> + Serialize,
-{
-  match items {
-    Ok(tx) => {
-
-      let new_tx = tx.clone();
-      let mut tx_map: HashMap<String, SerdeValue> = tx.into();
-
-      let mut found = 0;
-      for (index, item) in filter_array.iter().enumerate() {
-
-        if let SerdeValue::Object(obj) = item {
-          for (k, v) in obj {
-            if tx_map.get(k) == Some(v) {
-             found = found+1;
-             if found == filter_array.len() {
-                return Some(serde_json::to_value(&new_tx).unwrap());
-             }  
-            }
-          }
+/// Whether every `{field: value}` object of `filter_array` matches `fields`.
+fn child_filter(fields: &HashMap<String, SerdeValue>, filter_array: &[SerdeValue]) -> bool {
+  let mut found = 0;
+  for item in filter_array.iter() {
+    if let SerdeValue::Object(obj) = item {
+      for (k, v) in obj {
+        if fields.get(k) == Some(v) {
+          found = found+1;
         }
       }
-    },
-    _ => return None,
+    }
   }
-  None
+  found == filter_array.len()
 }
+
 fn filter_trie() -> TrieResult {
   let args: Vec<String> = env::args().collect();
 
   let trie_key = args[2].as_str();
   let filter_key = args[3].clone();
-  
-  // let t = CONFIG.get("TX_DB_PATH").unwrap();
-
-  let db_path = match trie_key {
-    "tx" => CONFIG.get::<String>("TX_DB_PATH").unwrap(),
-    "cron" => CONFIG.get::<String>("CRON_DB_PATH").unwrap(),
-    "receipt" => CONFIG.get::<String>("TX_RECEIPT_DB_PATH").unwrap(),
-    "metadata" => CONFIG.get::<String>("METADATA_DB_PATH").unwrap(),
-    "metacontract" => CONFIG.get::<String>("METACONTRACT_DB_PATH").unwrap(),
-    _ => "".to_string(),
+
+  let record = match record::find(trie_key) {
+    Some(record) => record,
+    None => return TrieResult { success: false, result: Some("Unknown trie".to_string()) },
   };
 
-  let mut filters = "".to_string();
+  let mut filter_array: Vec<SerdeValue> = Vec::new();
   
-  if args.len() > 4 {
-    filters = args[4].clone();
+  if args.len() > 4 && args[4] != "" {
+    filter_array = match serde_json::from_str(&args[4]) {
+      Ok(filters) => filters,
+      Err(e) => return TrieResult { success: false, result: Some(format!("Error decoding filters: {}", e)) },
+    };
   }
 
-  let trie_results = get_trie_results(trie_key, &db_path, Some(filter_key));
+  let trie_results = match read_trie_results(trie_key, &record.db_path(), Some(filter_key)) {
+    Ok(results) => results,
+    Err(e) => return TrieResult { success: false, result: Some(e) },
+  };
 
-  // println!("filter_array: {:?}", filter_array);
   let mut new_results: Vec<SerdeValue> = Vec::new();
   for val in trie_results.iter() {
-    // let mut dec_val: Box<dyn Decodable>;
-
-    if let Some(dec_val) = DecodableEnum::decode(trie_key, &Rlp::new(&val)){
-      match dec_val {
-        DecodableEnum::Transaction(transaction) => {
-          if filters == "".to_string() {
-            new_results.push(serde_json::to_value(&transaction.unwrap()).unwrap());
-          } else {
-            let mut filter_array: Vec<SerdeValue> = serde_json::from_str(&filters).unwrap();
-            let item = child_filter(transaction, filter_array.clone());
-            if !item.is_none() {
-              new_results.push(item.unwrap());
-            }
-          }
-        },
-        DecodableEnum::Cron(cron) => {
-          if filters == "".to_string() {
-            new_results.push(serde_json::to_value(&cron.unwrap()).unwrap());
-          } else {
-            let mut filter_array: Vec<SerdeValue> = serde_json::from_str(&filters).unwrap();
-            let item = child_filter(cron, filter_array.clone());
-            if !item.is_none() {
-              new_results.push(item.unwrap());
-            }
-          }
-        },
-        DecodableEnum::Metadata(metadata) => {
-          if filters == "".to_string() {
-            new_results.push(serde_json::to_value(&metadata.unwrap()).unwrap());
-          } else {
-            let mut filter_array: Vec<SerdeValue> = serde_json::from_str(&filters).unwrap();
-            let item = child_filter(metadata, filter_array.clone());
-            if !item.is_none() {
-              new_results.push(item.unwrap());
-            }
-          }
-        },
-        DecodableEnum::MetaContract(metacontract) => {
-          if filters == "".to_string() {
-            new_results.push(serde_json::to_value(&metacontract.unwrap()).unwrap());
-          } else {
-            let mut filter_array: Vec<SerdeValue> = serde_json::from_str(&filters).unwrap();
-            let item = child_filter(metacontract, filter_array.clone());
-            if !item.is_none() {
-              new_results.push(item.unwrap());
-            }
-          }
-        },
-        DecodableEnum::Receipt(receipt) => {
-          if filters == "".to_string() {
-            new_results.push(serde_json::to_value(&receipt.unwrap()).unwrap());
-          } else {
-            let mut filter_array: Vec<SerdeValue> = serde_json::from_str(&filters).unwrap();
-            let item = child_filter(receipt, filter_array.clone());
-            if !item.is_none() {
-              new_results.push(item.unwrap());
-            }
-          }
-        },
-        _ => (),
+    let matches = filter_array.is_empty()
+      || record.decode_fields(val).map_or(false, |fields| child_filter(&fields, &filter_array));
+
+    if matches {
+      match record.decode_json(val) {
+        Ok(value) => new_results.push(value),
+        Err(e) => eprintln!("Skipping undecodable {} record, run migrate or verify_db: {}", trie_key, e),
       }
     }
   }
 
-  let mut success = false;
-  let mut result = Some("".to_string());
+  let success;
+  let result;
 
   if new_results.len() > 0 {
     success = true;
     result = Some(serde_json::to_string(&new_results).unwrap_or("".to_string()));
   } else {
+    success = false;
     result = Some("Record not found".to_string());
   }
 
   TrieResult { success, result }
 }
 
-fn get_trie_root(key: &str) -> [u8; 32] {
+fn get_db_path(trie_key: &str) -> Option<String> {
+  record::find(trie_key).map(|record| record.db_path())
+}
+
+/// Current root of `key`, failing on a root entry too short to hold a root.
+fn get_trie_root(key: &str) -> Result<[u8; 32], String> {
+  let deferred = DEFERRED.with(|deferred| {
+    deferred.borrow().as_ref().and_then(|roots| roots.iter().find(|staged| staged.trie_key == key).map(|staged| staged.root))
+  });
+  if let Some(root) = deferred {
+    return Ok(root);
+  }
+
+  read_trie_root(key).map_err(|len| format!("Root entry of {} is {} bytes long, run verify_db", key, len))
+}
+
+/// Read a root from the root db, failing with the stored length when the
+/// entry is too short to hold a root.
+fn read_trie_root(key: &str) -> Result<[u8; 32], usize> {
   let KVDatabase {db, ..} = KVDatabase::open(&CONFIG.get::<String>("ROOT_DB_PATH").unwrap());
   let root = db.get(0, key.as_bytes());
 
@@ -361,26 +366,69 @@ fn get_trie_root(key: &str) -> [u8; 32] {
     Ok(val) => {
       match val {
           Some(value) => {
+            if value.len() < 32 {
+              return Err(value.len());
+            }
             array.copy_from_slice(&value[..32]);
             
-            array
+            Ok(array)
           },
-          _ => array,
+          _ => Ok(array),
       }
       
     },
-    _ => array,
+    _ => Ok(array),
   }
 }
 
+/// Layout tag recorded after the root, `None` for entries holding only a root.
+fn read_layout_tag(key: &str) -> Option<u8> {
+  let KVDatabase {db, ..} = KVDatabase::open(&CONFIG.get::<String>("ROOT_DB_PATH").unwrap());
+
+  db.get(0, key.as_bytes()).ok().flatten().and_then(|value| value.get(32).copied())
+}
+
+/// Value stored under `key`, `None` when there is none.
+fn get_trie_value(root_key: &str, db_path: &str, key: &[u8]) -> std::result::Result<Option<Vec<u8>>, String> {
+  let root = get_trie_root(root_key)?;
+  if root == [0u8; 32] {
+    return Ok(None);
+  }
+
+  let memdb = KVDatabase::open(db_path);
+  let db = &memdb.as_hash_db();
+
+  with_layout!(
+    LayoutKind::of_trie(root_key),
+    L => TrieDBBuilder::<L>::new(db, &root).build().get(key).map_err(|e| format!("Error reading {}: {}", root_key, e))
+  )
+}
+
+/// Values under `prefix`, none when the root of `root_key` can't be read,
+/// see `read_trie_results`.
 fn get_trie_results(root_key: &str, db_path: &str, prefix: Option<String> ) -> Vec<Vec<u8>> {
+  read_trie_results(root_key, db_path, prefix).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    Vec::new()
+  })
+}
+
+fn read_trie_results(root_key: &str, db_path: &str, prefix: Option<String> ) -> std::result::Result<Vec<Vec<u8>>, String> {
+  let root = get_trie_root(root_key)?;
+
   let memdb = KVDatabase::open(db_path);
 
-  let root = get_trie_root(root_key);
+  Ok(with_layout!(LayoutKind::of_trie(root_key), L => collect_trie_values::<L>(&memdb, &root, prefix)))
+}
 
+fn collect_trie_values<L: TrieLayout<Hash = KeccakHasher>>(
+  memdb: &KVDatabase,
+  root: &<KeccakHasher as Hasher>::Out,
+  prefix: Option<String>,
+) -> Vec<Vec<u8>> {
   let db = &memdb.as_hash_db();
 
-  let trie = TrieDBBuilder::<ExtensionLayout>::new(db, &root).build();
+  let trie = TrieDBBuilder::<L>::new(db, root).build();
 
   let iter = TrieDBNodeIterator::new(&trie);
 
@@ -393,25 +441,39 @@ fn get_trie_results(root_key: &str, db_path: &str, prefix: Option<String> ) -> V
       }
     
     
-      for node in it {
+      while let Some(node) = it.next() {
         // println!("node: {:?}", node);
-        let node = node.map_err(|e| format!("TrieDB node iterator error: {}", e)).unwrap();
+        let node = match node {
+          Ok(node) => node,
+          Err(e) => {
+            eprintln!("TrieDB node iterator error: {}", e);
+            continue;
+          },
+        };
         match node.2.node_plan() {
-          NodePlan::Leaf { value, .. } | NodePlan::NibbledBranch { value: Some(value), .. } => {
-            if let ValuePlan::Inline(_) = value {
-              // match node.1.to_owned()
-              match node.2.node() {
-                Node::Leaf(_, val) => {
-                  match val {
-                    Value::Inline(bytes) => {
-                      // println!("val: {:?}", hex::encode(bytes));
-                      results.push(bytes.to_vec());
-                    }
-                    Value::Node(_) => todo!(),
-                  }
-                },
-                _ => (),
+          NodePlan::Leaf { .. } | NodePlan::Branch { value: Some(_), .. } | NodePlan::NibbledBranch { value: Some(_), .. } => {
+            // match node.1.to_owned()
+            let (partial, val) = match node.2.node() {
+              Node::Leaf(partial, val) | Node::NibbledBranch(partial, _, Some(val)) => (Some(partial), val),
+              Node::Branch(_, Some(val)) => (None, val),
+              _ => continue,
+            };
+            match val {
+              Value::Inline(bytes) => {
+                // println!("val: {:?}", hex::encode(bytes));
+                results.push(bytes.to_vec());
               }
+              Value::Node(hash) => {
+                let mut key = node.0.clone();
+                if let Some(partial) = partial {
+                  key.append_partial(partial.right());
+                }
+
+                match it.fetch_value(hash, (key.as_prefix().0, None)) {
+                  Ok(bytes) => results.push(bytes),
+                  Err(e) => eprintln!("TrieDB value fetch error: {}", e),
+                }
+              },
             }
           },
           _ => (),
@@ -428,53 +490,51 @@ fn get_trie_results(root_key: &str, db_path: &str, prefix: Option<String> ) -> V
   results
 }
 
-fn build_trie_db<T: TrieLayout>(
+fn build_trie_db(
   root_key: &str,
   db_path: &str,
 	pairs: &[(Vec<u8>, Vec<u8>)],
-) -> (Arc<dyn KeyValueDB>, <KeccakHasher as Hasher>::Out) {
+) -> Result<(Arc<dyn KeyValueDB>, <KeccakHasher as Hasher>::Out), TrieResult> {
+  let staged = stage_trie_db(root_key, db_path, pairs).map_err(|e| TrieResult { success: false, result: Some(e) })?;
+  let root = staged.root;
+  commit_roots(vec![staged])?;
 
-  let KVDatabase {db: memdb, ..} = KVDatabase::open(db_path);
-
-  let root_tx = get_trie_root(root_key);
-
-  let (db, overlay, root) = {
-    let mut overlay = HashMap::new();
-    let mut root: <KeccakHasher as Hasher>::Out = Default::default();
+  let KVDatabase {db, ..} = KVDatabase::open(db_path);
+  Ok((db, root))
+}
 
-    let mut trie = SimpleTrie::new(memdb, &mut overlay);
-    {
-      if root_tx == [0u8; 32] {
-        root = Default::default();
-  
-        let mut trie_db = trie_db::TrieDBMutBuilder::<ExtensionLayout>::new(&mut trie, &mut root).build();
-  
-        for (x, y) in pairs.iter() {
-          trie_db.insert(x, y).expect("trie insertion failed");
-        }
-        trie_db.commit();
-      } else {
-        root = root_tx;
-        let mut trie_db = trie_db::TrieDBMutBuilder::<ExtensionLayout>::from_existing(&mut trie, &mut root).build();
-  
-          for (x, y) in pairs.iter() {
-            println!("trie insert: {:?}", hex::encode(x));
-            trie_db.insert(x, y).expect("trie insertion failed");
-          }
-          trie_db.commit();
-      }
-    }
+/// Write the new nodes of `root_key` with `pairs` inserted and return its new
+/// root without making it current. Nodes are stored by hash, so until
+/// `commit_roots` the trie reads as before. Nodes the new root no longer
+/// references are left in place and returned for `commit_roots` to delete.
+fn stage_trie_db(
+  root_key: &str,
+  db_path: &str,
+	pairs: &[(Vec<u8>, Vec<u8>)],
+) -> std::result::Result<StagedRoot, String> {
+  let root_tx = get_trie_root(root_key)?;
 
+  let KVDatabase {db: memdb, ..} = KVDatabase::open(db_path);
 
-    (trie.db, overlay, root)
+  let configured = LayoutKind::for_trie(root_key);
+  let layout = if root_tx == [0u8; 32] { configured } else { LayoutKind::of_trie(root_key) };
+  if layout != configured {
+    eprintln!("{} was built with the {:?} layout, keeping it instead of {:?}", root_key, layout, configured);
+  }
 
-  };
+  let (db, overlay, root) = with_layout!(
+    layout,
+    L => insert_pairs::<L>(memdb, root_tx, pairs)
+  );
 
+  let mut obsolete = Vec::new();
+  let mut written = Vec::new();
   let mut transaction = db.transaction();
   for (key, value) in overlay.into_iter() {
     match value {
       Some(value) => {
         transaction.put(0, &key[..], &value[..]);
+        written.push(key.clone());
 
         let stmt = format!("INSERT OR REPLACE INTO {} (trie_key, trie_value) VALUES ('{}', '{}')", 
           root_key,
@@ -483,38 +543,193 @@ fn build_trie_db<T: TrieLayout>(
         );
         RQLite::execute(stmt.as_str());
       },
-      None => {
-        transaction.delete(0, &key[..]);
+      None => obsolete.push(key),
+    }
+  }
+  db.write(transaction).expect("Failed to write transaction");
 
-        let stmt = format!("DELETE FROM {} WHERE trie_key = '{}'", 
-          root_key,
-          hex::encode(&key[..]),
-        );
-        RQLite::execute(stmt.as_str());
+  Ok(StagedRoot {
+    trie_key: root_key.to_string(),
+    db_path: db_path.to_string(),
+    layout,
+    base: root_tx,
+    root,
+    obsolete,
+    written,
+  })
+}
 
-      },
+thread_local! {
+  /// Roots `commit_roots` held back inside `defer_commits`, one per trie.
+  /// `get_trie_root` reads them as current.
+  static DEFERRED: std::cell::RefCell<Option<Vec<StagedRoot>>> = std::cell::RefCell::new(None);
+}
+
+/// Run `stage` with the commits it makes on this thread held back: each one
+/// is staged and read as current by what follows, and their roots are
+/// returned merged, one per trie, for a single `commit_roots`. Dropping the
+/// roots leaves every trie as it was.
+fn defer_commits<T>(stage: impl FnOnce() -> T) -> (T, Vec<StagedRoot>) {
+  DEFERRED.with(|deferred| *deferred.borrow_mut() = Some(Vec::new()));
+  let result = stage();
+  let roots = DEFERRED.with(|deferred| deferred.borrow_mut().take()).unwrap_or_default();
+  (result, roots)
+}
+
+#[cfg(test)]
+thread_local! {
+  /// Root db writes of `commit_roots` on this thread.
+  static ROOT_WRITES: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// Make the staged `roots` current in a single write of the root db, together
+/// with the state trie holding them. Only once that write succeeded are the
+/// nodes the new roots no longer reference deleted, so a failed commit leaves
+/// the previous roots readable. Inside `defer_commits` the roots are only
+/// held back.
+///
+/// Fails with a `conflict` when another commit changed one of the tries since
+/// it was staged, which would otherwise be lost.
+fn commit_roots(mut roots: Vec<StagedRoot>) -> Result<(), TrieResult> {
+  use record::Record;
+
+  let _lock = WriteLock::acquire();
+
+  let mut moved: Vec<&str> = Vec::new();
+  for staged in roots.iter() {
+    let current = get_trie_root(&staged.trie_key).map_err(|e| TrieResult { success: false, result: Some(e) })?;
+    if current != staged.base {
+      moved.push(staged.trie_key.as_str());
     }
   }
-  db.write(transaction).expect("Failed to write transaction");
+  if !moved.is_empty() {
+    return Err(TrieResult::error(
+      "conflict",
+      format!("{} changed while the write was prepared", moved.join(", ")),
+      serde_json::json!({ "tries": moved }),
+    ));
+  }
+
+  let state_key = TrieRoot::trie_name();
+
+  if !roots.iter().any(|staged| staged.trie_key == state_key) {
+    let state = stage_state(&roots).map_err(|e| TrieResult { success: false, result: Some(e) })?;
+    roots.push(state);
+  }
+
+  let held = DEFERRED.with(|deferred| match deferred.borrow_mut().as_mut() {
+    Some(held) => {
+      for staged in roots.drain(..) {
+        match held.iter_mut().find(|h| h.trie_key == staged.trie_key) {
+          Some(h) => {
+            h.obsolete.retain(|key| !staged.written.contains(key));
+            h.obsolete.extend(staged.obsolete);
+            h.written.extend(staged.written);
+            h.root = staged.root;
+          },
+          None => held.push(staged),
+        }
+      }
+      true
+    },
+    None => false,
+  });
+  if held {
+    return Ok(());
+  }
 
   let KVDatabase {db: root_db, ..} = KVDatabase::open(&CONFIG.get::<String>("ROOT_DB_PATH").unwrap());
 
   let mut root_tx = root_db.transaction();
-  root_tx.put(0, root_key.as_bytes() ,&root);
-  let root_result = root_db.write(root_tx);
+  for staged in roots.iter() {
+    root_tx.put(0, staged.trie_key.as_bytes() ,&staged.layout.root_entry(&staged.root));
+  }
+  root_db.write(root_tx).map_err(|e| TrieResult { success: false, result: Some(format!("Error writing roots: {}", e)) })?;
+  #[cfg(test)]
+  ROOT_WRITES.with(|writes| writes.set(writes.get() + 1));
+
+  for staged in roots.iter() {
+    let stmt = format!("INSERT OR REPLACE INTO roots (root_key, root_value) VALUES ('{}', '{}')", 
+        staged.trie_key,
+        hex::encode(staged.layout.root_entry(&staged.root))
+      );
+    RQLite::execute(stmt.as_str());
+  }
 
-  match root_result {
-    Ok(_) => {
-      let stmt = format!("INSERT OR REPLACE INTO roots (root_key, root_value) VALUES ('{}', '{}')", 
-          root_key,
-          hex::encode(&root)
-        );
+  for staged in roots.iter().filter(|staged| !staged.obsolete.is_empty()) {
+    let KVDatabase {db, ..} = KVDatabase::open(&staged.db_path);
+    let mut transaction = db.transaction();
+    for key in staged.obsolete.iter() {
+      transaction.delete(0, key);
+
+      let stmt = format!("DELETE FROM {} WHERE trie_key = '{}'", 
+        staged.trie_key,
+        hex::encode(key),
+      );
       RQLite::execute(stmt.as_str());
-    },
-    _ => (),
+    }
+    db.write(transaction).expect("Failed to write transaction");
+  }
+
+  Ok(())
+}
+
+/// Stage the state trie with the new `roots`. A new state trie starts with
+/// the roots of every trie.
+fn stage_state(roots: &[StagedRoot]) -> std::result::Result<StagedRoot, String> {
+  use record::Record;
+
+  let state_key = TrieRoot::trie_name();
+
+  let mut state: Vec<TrieRoot> = roots
+    .iter()
+    .filter(|staged| staged.trie_key != state_key)
+    .map(|staged| TrieRoot { trie_key: staged.trie_key.clone(), root: hex::encode(staged.root) })
+    .collect();
+  if get_trie_root(&state_key)? == [0u8; 32] {
+    for trie_key in record::all().into_iter().map(|record| record.trie_name()) {
+      let root = get_trie_root(&trie_key)?;
+      if trie_key != state_key && root != [0u8; 32] && !state.iter().any(|r| r.trie_key == trie_key) {
+        state.push(TrieRoot { trie_key, root: hex::encode(root) });
+      }
+    }
+  }
+
+  let pairs: Vec<_> = state.iter().map(|r| (r.primary_key(), r.encode_record())).collect();
+  stage_trie_db(&state_key, &TrieRoot::db_path(), &pairs)
+}
+
+fn insert_pairs<L: TrieLayout<Hash = KeccakHasher>>(
+  memdb: KVDB,
+  root_tx: <KeccakHasher as Hasher>::Out,
+	pairs: &[(Vec<u8>, Vec<u8>)],
+) -> (KVDB, HashMap<Vec<u8>, Option<Vec<u8>>>, <KeccakHasher as Hasher>::Out) {
+  let mut overlay = HashMap::new();
+  let mut root: <KeccakHasher as Hasher>::Out = Default::default();
+
+  let mut trie = SimpleTrie::new(memdb, &mut overlay, L::Codec::empty_node());
+  {
+    if root_tx == [0u8; 32] {
+      root = Default::default();
+
+      let mut trie_db = trie_db::TrieDBMutBuilder::<L>::new(&mut trie, &mut root).build();
+
+      for (x, y) in pairs.iter() {
+        trie_db.insert(x, y).expect("trie insertion failed");
+      }
+      trie_db.commit();
+    } else {
+      root = root_tx;
+      let mut trie_db = trie_db::TrieDBMutBuilder::<L>::from_existing(&mut trie, &mut root).build();
+
+      for (x, y) in pairs.iter() {
+        trie_db.insert(x, y).expect("trie insertion failed");
+      }
+      trie_db.commit();
+    }
   }
 
-  (db, root)
+  (trie.db, overlay, root)
 }
 
 #[test]
@@ -524,7 +739,7 @@ fn test_iter() {
   //get status = 1
   let mut new_results = Vec::new();
   for val in results.iter() {
-    let dec_tx = Transaction::decode(&Rlp::new(&val));
+    let dec_tx = <Transaction as record::Record>::decode_record(&val);
 
     match dec_tx {
       Ok(tx) => {
@@ -544,7 +759,7 @@ fn test_iter() {
 fn test_get_trie() {
   let memdb = KVDatabase::open(&CONFIG.get::<String>("METADATA_DB_PATH").unwrap());
 
-  let root = get_trie_root("metadata");
+  let root = get_trie_root("metadata").unwrap();
 
   let db = &memdb.as_hash_db();
 
@@ -572,6 +787,9 @@ fn test_simple_trie() {
     version: "version".into(),
     mcdata: "".into(),
     status: 0,
+    nonce: 0,
+    expires_at: 0,
+    signer: "".into(),
   };
 
   let tx2 = Transaction {
@@ -589,6 +807,9 @@ fn test_simple_trie() {
     version: "version2".into(),
     mcdata: "".into(),
     status: 0,
+    nonce: 0,
+    expires_at: 0,
+    signer: "".into(),
   };
 
   let tx3 = Transaction {
@@ -606,6 +827,9 @@ fn test_simple_trie() {
     version: "version2".into(),
     mcdata: "".into(),
     status: 0,
+    nonce: 0,
+    expires_at: 0,
+    signer: "".into(),
   };
 
   let tx4 = Transaction {
@@ -623,6 +847,9 @@ fn test_simple_trie() {
     version: "version3".into(),
     mcdata: "".into(),
     status: 1,
+    nonce: 0,
+    expires_at: 0,
+    signer: "".into(),
   };
   
   let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
@@ -636,7 +863,7 @@ fn test_simple_trie() {
   let dec_tx = Transaction::decode(&Rlp::new(&enc_tx)).unwrap();
   println!("status: {:?}", dec_tx.status);
 
-  let (db, root) = build_trie_db::<ExtensionLayout>("tx", &CONFIG.get::<String>("TX_DB_PATH").unwrap(), &pairs);
+  let (db, root) = build_trie_db("tx", &CONFIG.get::<String>("TX_DB_PATH").unwrap(), &pairs).unwrap();
   
 }
 
@@ -645,7 +872,7 @@ fn test_gen_trie() {
   // let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
   let KVDatabase {db, ..} = KVDatabase::open(&CONFIG.get::<String>("TX_TEST_DB_PATH").unwrap());
   let mut overlay: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
-  let mut t = SimpleTrie::new(db, &mut overlay);
+  let mut t = SimpleTrie::new(db, &mut overlay, &[0u8]);
 
   let mut root: <KeccakHasher as Hasher>::Out = Default::default();
 
@@ -669,7 +896,56 @@ fn test_remove_root() {
 
 #[test]
 fn test_get_trie_root() {
-  let root = get_trie_root(&CONFIG.get::<String>("METADATA_KEY").unwrap());
+  let root = get_trie_root(&CONFIG.get::<String>("METADATA_KEY").unwrap()).unwrap();
 
   println!("root: {:?} {:?}", hex::encode(root), [0u8;32]);
-}
\ No newline at end of file
+}
+#[test]
+fn test_hashed_value_layout() {
+  let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+  let mut root: <KeccakHasher as Hasher>::Out = Default::default();
+
+  let big = vec![7u8; 100];
+  {
+    let mut trie = TrieDBMutBuilder::<HashedValueLayout<32>>::new(&mut db, &mut root).build();
+    trie.insert(b"small", b"value").expect("trie insertion failed");
+    trie.insert(b"big", &big).expect("trie insertion failed");
+  }
+
+  assert!(HashDB::contains(&db, &KeccakHasher::hash(&big), hash_db::EMPTY_PREFIX));
+
+  let trie = TrieDBBuilder::<HashedValueLayout<32>>::new(&db, &root).build();
+  assert_eq!(trie.get(b"big").unwrap(), Some(big));
+  assert_eq!(trie.get(b"small").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_no_extension_layout() {
+  let prefix = "3kmAHv8M8zN8A3ofG1jygVmGeMohiRhdVBCDHwzoxJgH7TTGKXuhDL4XHeo2J2ZfKijhY4J8wYhPMHagzdUh6ZSQEXgsTeuKfMVALwuVLnW6jJp1cUXxd5uGXGuijA9UGstf0xee1f0084514f12e6f";
+  let keys: Vec<String> = vec![prefix.to_string(), format!("{}a1", prefix), format!("{}b2", prefix), format!("{}b2c3", prefix)];
+
+  let mut db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+  let mut root: <KeccakHasher as Hasher>::Out = Default::default();
+  {
+    let mut trie = TrieDBMutBuilder::<node_codec::NoExtensionLayout>::new(&mut db, &mut root).build();
+    for key in keys.iter() {
+      trie.insert(key.as_bytes(), key.as_bytes()).expect("trie insertion failed");
+    }
+  }
+
+  let trie = TrieDBBuilder::<node_codec::NoExtensionLayout>::new(&db, &root).build();
+  for key in keys.iter() {
+    assert_eq!(trie.get(key.as_bytes()).unwrap(), Some(key.as_bytes().to_vec()));
+  }
+
+  let mut ext_db = MemoryDB::<KeccakHasher, HashKey<_>, Vec<u8>>::default();
+  let mut ext_root: <KeccakHasher as Hasher>::Out = Default::default();
+  {
+    let mut trie = TrieDBMutBuilder::<ExtensionLayout>::new(&mut ext_db, &mut ext_root).build();
+    for key in keys.iter() {
+      trie.insert(key.as_bytes(), key.as_bytes()).expect("trie insertion failed");
+    }
+  }
+
+  assert!(db.keys().len() < ext_db.keys().len());
+}
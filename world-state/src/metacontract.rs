@@ -1,23 +1,100 @@
-use std::collections::HashMap;
-
+use rlp::{Decodable, DecoderError, Rlp};
 use rlp_derive::{RlpEncodable, RlpDecodable};
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
+
+use crate::{
+  get_trie_results,
+  ownership::{Owner, Policy},
+  record::{field_or_default, list_or_default, Record},
+};
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
 pub struct MetaContract {
   pub program_id: String,
+  /// The owner's key, or the `Policy::id` of `public_keys` and `threshold`.
+  #[serde(default)]
   pub public_key: String,
   pub cid: String,
+  /// Keys owning the program together, empty for a single owner.
+  #[serde(default)]
+  pub public_keys: Vec<String>,
+  /// How many of `public_keys` must sign for the owner.
+  #[serde(default)]
+  pub threshold: u64,
+}
+
+impl Record for MetaContract {
+  const CONFIG_PREFIX: &'static str = "METACONTRACT";
+  const VERSION: u64 = 2;
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.program_id.as_bytes().to_vec()
+  }
+
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    match version {
+      0 | 1 => Ok(MetaContract {
+        program_id: rlp.val_at(0)?,
+        public_key: rlp.val_at(1)?,
+        cid: rlp.val_at(2)?,
+        public_keys: list_or_default(rlp, 3)?,
+        threshold: field_or_default(rlp, 4)?,
+      }),
+      Self::VERSION => Self::decode(rlp),
+      _ => Err(DecoderError::Custom("unsupported record version")),
+    }
+  }
+
+  /// A contract given `public_keys` is owned by their policy. The owner
+  /// changes only through `transfer_program`, which keeps the ownership
+  /// history.
+  fn prepare(&mut self) -> Result<(), String> {
+    let policy = if self.public_keys.is_empty() {
+      Policy::parse(&self.public_key)?
+    } else {
+      let policy = Policy { public_keys: self.public_keys.clone(), threshold: self.threshold };
+      policy.validate()?;
+      policy
+    };
+
+    if !self.public_key.is_empty() && self.public_key != policy.id() {
+      return Err(format!("public_key must be empty or {} for the given public_keys", policy.id()));
+    }
+    self.set_policy(policy);
+
+    match Self::find(&self.program_id) {
+      Some(existing) if existing.public_key != self.public_key => {
+        Err(format!("Use transfer_program to change the owner of {}", self.program_id))
+      },
+      _ => Ok(()),
+    }
+  }
+
+  fn owner(&self) -> Option<Owner> {
+    Some(Owner { program_id: self.program_id.clone(), public_key: self.public_key.clone() })
+  }
 }
 
-impl From<MetaContract> for HashMap<String, Value> {
-  fn from(m: MetaContract) -> Self {
-    let mut map = HashMap::new();
-    map.insert("program_id".to_string(), Value::String(m.program_id));
-    map.insert("cid".to_string(), Value::String(m.cid));
-    map.insert("public_key".to_string(), Value::String(m.public_key));
+impl MetaContract {
+  /// Make `policy` the owner, keeping single keys in `public_key` alone.
+  pub fn set_policy(&mut self, policy: Policy) {
+    self.public_key = policy.id();
+
+    if policy.public_keys.len() == 1 && policy.threshold == 1 {
+      self.public_keys = Vec::new();
+      self.threshold = 0;
+    } else {
+      let mut keys = policy.public_keys;
+      keys.sort();
+      self.public_keys = keys;
+      self.threshold = policy.threshold;
+    }
+  }
 
-    map
+  pub fn find(program_id: &str) -> Option<MetaContract> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(program_id.to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|contract| contract.program_id == program_id)
   }
 }
\ No newline at end of file
@@ -1,9 +1,22 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, env};
 
+use rlp::{Decodable, DecoderError, Rlp};
 use rlp_derive::{RlpEncodable, RlpDecodable};
 use serde::{Serialize, Deserialize};
-use serde_json::{Value, Number};
+use serde_json::json;
 
+use crate::{
+  get_trie_results,
+  ownership::Owner,
+  proof::Proof,
+  record::{field_or_default, Record},
+  types::TrieResult,
+  utils::flag_value,
+};
+
+/// A version of the metadata identified by `program_id` and `data_key`. Its
+/// versions form a chain in insertion order, each stored under
+/// `<program_id>/<data_key>/<seq>` so a prefix lookup returns them in order.
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
 pub struct Metadata {
     pub hash: String,
@@ -17,23 +30,285 @@ pub struct Metadata {
     pub cid: String,
     pub public_key: String,
     pub loose: u64,
+    /// Position in the chain from 1, set on insert. 0 for metadata stored
+    /// before versions were chained, which stays keyed by `hash`.
+    #[serde(default)]
+    pub seq: u64,
+    /// `hash` of the previous version, empty for the first one.
+    #[serde(default)]
+    pub previous: String,
+}
+
+impl Record for Metadata {
+  const CONFIG_PREFIX: &'static str = "METADATA";
+  const VERSION: u64 = 2;
+  const HAS_REVISION: bool = true;
+
+  fn primary_key(&self) -> Vec<u8> {
+    match self.seq {
+      0 => self.hash.as_bytes().to_vec(),
+      seq => Self::version_key(&self.program_id, &self.data_key, seq),
+    }
+  }
+
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    match version {
+      0 | 1 => Ok(Metadata {
+        hash: rlp.val_at(0)?,
+        data_key: rlp.val_at(1)?,
+        program_id: rlp.val_at(2)?,
+        alias: rlp.val_at(3)?,
+        chain_id: rlp.val_at(4)?,
+        token_address: rlp.val_at(5)?,
+        token_id: rlp.val_at(6)?,
+        version: rlp.val_at(7)?,
+        cid: rlp.val_at(8)?,
+        public_key: rlp.val_at(9)?,
+        loose: rlp.val_at(10)?,
+        seq: field_or_default(rlp, 11)?,
+        previous: field_or_default(rlp, 12)?,
+      }),
+      Self::VERSION => Self::decode(rlp),
+      _ => Err(DecoderError::Custom("unsupported record version")),
+    }
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    self.prepare_replacing().map(|_| ())
+  }
+
+  /// Append the metadata to its chain, see `append_to`.
+  fn prepare_replacing(&mut self) -> Result<Vec<u8>, String> {
+    let chain = Self::chain(&self.program_id, &self.data_key);
+    self.append_to(&chain)
+  }
+
+  fn revision(&self) -> Option<String> {
+    Some(self.version.clone())
+  }
+
+  /// Loose metadata can be written by anyone.
+  fn owner(&self) -> Option<Owner> {
+    if self.loose == 0 {
+      Some(Owner { program_id: self.program_id.clone(), public_key: self.public_key.clone() })
+    } else {
+      None
+    }
+  }
 }
 
-impl From<Metadata> for HashMap<String, Value> {
-  fn from(m: Metadata) -> Self {
-    let mut map = HashMap::new();
-    map.insert("hash".to_string(), Value::String(m.hash));
-    map.insert("data_key".to_string(), Value::String(m.data_key));
-    map.insert("program_id".to_string(), Value::String(m.program_id));
-    map.insert("alias".to_string(), Value::String(m.alias));
-    map.insert("chain_id".to_string(), Value::String(m.chain_id));
-    map.insert("token_address".to_string(), Value::String(m.token_address));
-    map.insert("token_id".to_string(), Value::String(m.token_id));
-    map.insert("version".to_string(), Value::String(m.version));
-    map.insert("cid".to_string(), Value::String(m.cid));
-    map.insert("public_key".to_string(), Value::String(m.public_key));
-    map.insert("loose".to_string(), Value::Number(Number::from(m.loose)));
-
-    map
-  }
-}
\ No newline at end of file
+impl Metadata {
+  /// `get_metadata <program_id> <data_key> [--version v]`
+  ///
+  /// The latest version without `--version`.
+  pub fn get_metadata() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let version = match flag_value(&args, "--version") {
+      Some(version) => version,
+      None => return Self::get_metadata_latest(),
+    };
+
+    Self::proven(|chain| match chain.iter().position(|m| m.version == version) {
+      Some(i) => Ok((chain[i..=i].to_vec(), None)),
+      None => Err(format!("Version {} not found", version)),
+    })
+  }
+
+  /// `get_metadata_history <program_id> <data_key>`
+  ///
+  /// Every version, oldest first. The proof also shows there is no version
+  /// after the last.
+  pub fn get_metadata_history() -> TrieResult {
+    Self::proven(|chain| Ok((chain.to_vec(), chain.last().map(|last| last.seq + 1))))
+  }
+
+  /// `get_metadata_latest <program_id> <data_key>`
+  pub fn get_metadata_latest() -> TrieResult {
+    Self::proven(|chain| {
+      let last = chain.last().unwrap();
+      Ok((vec![last.clone()], Some(last.seq + 1)))
+    })
+  }
+
+  /// Run `select` on the chain named by the arguments and return the
+  /// versions it picks with a proof against the metadata root. The proof
+  /// covers the absence of the version at the returned seq, if any.
+  fn proven<F>(select: F) -> TrieResult
+  where
+    F: FnOnce(&[Metadata]) -> Result<(Vec<Metadata>, Option<u64>), String>,
+  {
+    let args: Vec<String> = env::args().collect();
+
+    let (program_id, data_key) = match (args.get(2), args.get(3)) {
+      (Some(program_id), Some(data_key)) => (program_id.clone(), data_key.clone()),
+      _ => return TrieResult { success: false, result: Some("Missing program id or data key".to_string()) },
+    };
+
+    let chain = Self::chain(&program_id, &data_key);
+    if chain.is_empty() {
+      return TrieResult { success: false, result: Some("Record not found".to_string()) };
+    }
+
+    let (versions, absent) = match select(&chain) {
+      Ok(selected) => selected,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let mut keys: Vec<Vec<u8>> = versions.iter().map(|m| m.primary_key()).collect();
+    keys.extend(absent.map(|seq| Self::version_key(&program_id, &data_key, seq)));
+
+    match Proof::generate(&Self::trie_name(), &keys) {
+      Ok(proof) => TrieResult {
+        success: true,
+        result: Some(serde_json::to_string(&json!({ "metadata": versions, "proof": proof })).unwrap_or("".to_string())),
+      },
+      Err(e) => TrieResult { success: false, result: Some(e) },
+    }
+  }
+
+  /// Every version of `program_id`/`data_key`, oldest first. Versions stored
+  /// before they were chained have no seq and come first, in version order.
+  pub fn chain(program_id: &str, data_key: &str) -> Vec<Metadata> {
+    let prefix = format!("{}/{}/", program_id, data_key);
+
+    let mut chain: Vec<Metadata> = get_trie_results(&Self::trie_name(), &Self::db_path(), Some(prefix))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .filter(|m| m.seq > 0 && m.program_id == program_id && m.data_key == data_key)
+      .collect();
+    chain.sort_by_key(|m| m.seq);
+
+    // Versions before chaining are keyed by hash, so finding them scans the
+    // whole trie. The first chained version links to the last of them.
+    if chain.first().map_or(false, |first| first.previous.is_empty()) {
+      return chain;
+    }
+
+    let mut legacy: Vec<Metadata> = get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .filter(|m| m.seq == 0 && m.program_id == program_id && m.data_key == data_key)
+      .collect();
+    legacy.sort_by(|a, b| compare_versions(&a.version, &b.version));
+
+    legacy.extend(chain);
+    legacy
+  }
+
+  /// Append the metadata to `chain`, its program's and data key's, and
+  /// return the key of the latest version, which a new version is checked
+  /// against as what its writer read with `get_metadata_latest`. Inserting a
+  /// version again is only accepted with the same content, which rewrites it
+  /// in place.
+  pub fn append_to(&mut self, chain: &[Metadata]) -> Result<Vec<u8>, String> {
+    if self.program_id.is_empty() || self.program_id.contains('/') {
+      return Err(format!("Program id {:?} must be non-empty and cannot contain /", self.program_id));
+    }
+    if self.version.is_empty() {
+      return Err("Metadata version cannot be empty".to_string());
+    }
+
+    match chain.iter().find(|m| m.version == self.version) {
+      Some(existing) if Metadata { seq: existing.seq, previous: existing.previous.clone(), ..self.clone() }.encode_record() == existing.encode_record() => {
+        self.seq = existing.seq;
+        self.previous = existing.previous.clone();
+      },
+      Some(_) => return Err(format!("Version {} of {}/{} already exists", self.version, self.program_id, self.data_key)),
+      None => {
+        self.seq = chain.last().map_or(1, |last| last.seq + 1);
+        self.previous = chain.last().map(|last| last.hash.clone()).unwrap_or_default();
+      },
+    }
+
+    Ok(chain.last().map_or(self.primary_key(), |latest| latest.primary_key()))
+  }
+
+  fn version_key(program_id: &str, data_key: &str, seq: u64) -> Vec<u8> {
+    format!("{}/{}/{:020}", program_id, data_key, seq).into_bytes()
+  }
+}
+
+/// Orders versions by their dot separated parts, numerically where both
+/// parts are numbers, so 1.9 comes before 1.10.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+  let parts = |version: &str| -> Vec<String> { version.split('.').map(str::to_string).collect() };
+
+  for (x, y) in parts(a).iter().zip(parts(b).iter()) {
+    let order = match (x.parse::<u64>(), y.parse::<u64>()) {
+      (Ok(x), Ok(y)) => x.cmp(&y),
+      _ => x.cmp(y),
+    };
+    if order != Ordering::Equal {
+      return order;
+    }
+  }
+  a.split('.').count().cmp(&b.split('.').count())
+}
+
+#[test]
+fn test_legacy_chain() {
+  use crate::{build_trie_db, db, state::StateCommit};
+
+  db::scratch(|| {
+    let legacy = |hash: &str, version: &str| Metadata {
+      hash: hash.to_string(),
+      data_key: "d".to_string(),
+      program_id: "p".to_string(),
+      alias: String::new(),
+      chain_id: String::new(),
+      token_address: String::new(),
+      token_id: String::new(),
+      version: version.to_string(),
+      cid: "c".to_string(),
+      public_key: "k".to_string(),
+      loose: 0,
+      seq: 0,
+      previous: String::new(),
+    };
+    let pairs: Vec<_> = [legacy("h2", "1.10"), legacy("h1", "1.9")]
+      .iter()
+      .map(|m| (m.primary_key(), m.encode_record()))
+      .collect();
+    build_trie_db(&Metadata::trie_name(), &Metadata::db_path(), &pairs).unwrap();
+
+    let chain = Metadata::chain("p", "d");
+    assert_eq!(chain.iter().map(|m| m.hash.as_str()).collect::<Vec<_>>(), ["h1", "h2"]);
+
+    let mut next = Metadata { hash: "h3".to_string(), version: "2".to_string(), ..legacy("", "") };
+    assert_eq!(next.prepare_replacing().unwrap(), b"h2".to_vec());
+    assert_eq!((next.seq, next.previous.as_str()), (1, "h2"));
+    assert!(next.owner().is_some());
+
+    let mut again = Metadata { hash: "h4".to_string(), ..legacy("", "1.9") };
+    assert!(again.prepare().is_err());
+
+    // A version is only written again with the same content.
+    let mut commit = StateCommit::new();
+    commit.put(&[next.clone()]);
+    commit.commit().unwrap();
+    assert_eq!(Metadata::chain("p", "d").len(), 3);
+
+    let mut same = Metadata { seq: 0, previous: String::new(), ..next.clone() };
+    assert_eq!(same.prepare_replacing().unwrap(), next.primary_key());
+    assert_eq!((same.seq, same.previous.as_str()), (1, "h2"));
+    assert!(Metadata { cid: "other".to_string(), ..next.clone() }.prepare().is_err());
+    assert!(Metadata { public_key: "other".to_string(), ..next }.prepare().is_err());
+  });
+}
+
+#[test]
+fn test_metadata_keys() {
+  let mut stream = rlp::RlpStream::new_list(11);
+  for field in ["h1", "d", "p", "", "1", "", "", "v1", "c", "k"] {
+    stream.append(&field);
+  }
+  stream.append(&0u64);
+
+  let legacy = Metadata::decode_record(&stream.out()).unwrap();
+  assert_eq!((legacy.seq, legacy.primary_key()), (0, b"h1".to_vec()));
+
+  let chained = Metadata { seq: 2, previous: "h1".to_string(), ..legacy };
+  assert_eq!(chained.primary_key(), b"p/d/00000000000000000002".to_vec());
+  assert_eq!(Metadata::decode_record(&chained.encode_record()).unwrap().previous, "h1");
+}
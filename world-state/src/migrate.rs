@@ -0,0 +1,107 @@
+use std::{collections::BTreeMap, env};
+
+use serde::Serialize;
+
+use crate::{
+  build_trie_db,
+  db::KVDatabase,
+  get_trie_root,
+  layout::{LayoutKind, with_layout},
+  record::{self, record_version},
+  trie_walk::walk_trie,
+  types::TrieResult,
+};
+
+/// Outcome of re-encoding a trie's records with their current version.
+#[derive(Serialize, Debug, Default)]
+pub struct MigrationReport {
+  pub trie_key: String,
+  pub old_root: String,
+  pub new_root: String,
+  pub leaves: usize,
+  pub migrated: usize,
+  /// Number of leaves per stored version before the migration.
+  pub versions: BTreeMap<u64, usize>,
+  pub errors: Vec<String>,
+}
+
+pub struct Migrate;
+
+impl Migrate {
+  /// `migrate <trie_key>`
+  ///
+  /// Nothing is written unless every leaf decodes.
+  pub fn migrate() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let trie_key = match args.get(2) {
+      Some(key) => key.clone(),
+      None => return TrieResult { success: false, result: Some("Missing trie key".to_string()) },
+    };
+
+    let record = match record::find(&trie_key) {
+      Some(record) => record,
+      None => return TrieResult { success: false, result: Some("Unknown trie".to_string()) },
+    };
+
+    let root = match get_trie_root(&trie_key) {
+      Ok(root) => root,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+    let mut report = MigrationReport {
+      trie_key: trie_key.clone(),
+      old_root: hex::encode(root),
+      new_root: hex::encode(root),
+      ..Default::default()
+    };
+
+    let walk = {
+      let KVDatabase { db, .. } = KVDatabase::open(&record.db_path());
+      with_layout!(
+        LayoutKind::of_trie(&trie_key),
+        L => walk_trie::<L, _>(&root, |key| db.get(0, key).ok().flatten())
+      )
+    };
+
+    report.errors = walk.issues.iter().map(|issue| issue.to_string()).collect();
+    report.leaves = walk.values.len();
+
+    let mut pairs = Vec::new();
+    for (key, value) in walk.values.iter() {
+      match record_version(value) {
+        Ok(version) => *report.versions.entry(version).or_default() += 1,
+        Err(e) => {
+          report.errors.push(format!("{}: {}", String::from_utf8_lossy(key), e));
+          continue;
+        },
+      }
+
+      match record.reencode(value) {
+        Ok(encoded) if &encoded != value => pairs.push((key.clone(), encoded)),
+        Ok(_) => (),
+        Err(e) => report.errors.push(format!("{}: {}", String::from_utf8_lossy(key), e)),
+      }
+    }
+
+    if !report.errors.is_empty() {
+      return TrieResult {
+        success: false,
+        result: Some(serde_json::to_string(&report).unwrap_or("".to_string())),
+      };
+    }
+
+    if !pairs.is_empty() {
+      let new_root = match build_trie_db(&trie_key, &record.db_path(), &pairs) {
+        Ok((_, new_root)) => new_root,
+        Err(e) => return e,
+      };
+      report.new_root = hex::encode(new_root);
+      report.migrated = pairs.len();
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&report).unwrap_or("".to_string())),
+    }
+  }
+}
@@ -18,6 +18,15 @@ const LEAF_NODE_OVER: u8 = EXTENSION_NODE_OFFSET - LEAF_NODE_OFFSET;
 const EXTENSION_NODE_OVER: u8 = BRANCH_NODE_NO_VALUE - EXTENSION_NODE_OFFSET;
 const LEAF_NODE_LAST: u8 = EXTENSION_NODE_OFFSET - 1;
 const EXTENSION_NODE_LAST: u8 = BRANCH_NODE_NO_VALUE - 1;
+/// Marks a value stored by hash. Never a valid `Compact<u32>` prefix, so it
+/// cannot be confused with the length of an inline value.
+const HASHED_VALUE: u8 = 0xff;
+/// Two high bits of a no-extension node header, the low six bits hold the
+/// partial length, continued in following bytes when it is 63 or more.
+const NIBBLED_LEAF: u8 = 0b01 << 6;
+const NIBBLED_BRANCH_NO_VALUE: u8 = 0b10 << 6;
+const NIBBLED_BRANCH_WITH_VALUE: u8 = 0b11 << 6;
+const NIBBLED_SIZE_MASK: u8 = 0b0011_1111;
 
 /// Reference hasher is a keccak hasher.
 pub type RefHasher = keccak_hasher::KeccakHasher;
@@ -35,6 +44,36 @@ impl TrieLayout for ExtensionLayout {
 
 impl TrieConfiguration for ExtensionLayout {}
 
+/// Extension layout that stores values of `MAX_INLINE` bytes or more by hash
+/// in the node db instead of inline in the node.
+#[derive(Default, Clone)]
+pub struct HashedValueLayout<const MAX_INLINE: u32>;
+
+impl<const MAX_INLINE: u32> TrieLayout for HashedValueLayout<MAX_INLINE> {
+	const USE_EXTENSION: bool = true;
+	const ALLOW_EMPTY: bool = false;
+	const MAX_INLINE_VALUE: Option<u32> = Some(MAX_INLINE);
+	type Hash = RefHasher;
+	type Codec = ReferenceNodeCodec<RefHasher>;
+}
+
+impl<const MAX_INLINE: u32> TrieConfiguration for HashedValueLayout<MAX_INLINE> {}
+
+/// Layout without extension nodes: branches carry the partial key themselves,
+/// which keeps long shared key prefixes in a single node.
+#[derive(Default, Clone)]
+pub struct NoExtensionLayout;
+
+impl TrieLayout for NoExtensionLayout {
+	const USE_EXTENSION: bool = false;
+	const ALLOW_EMPTY: bool = false;
+	const MAX_INLINE_VALUE: Option<u32> = None;
+	type Hash = RefHasher;
+	type Codec = NoExtensionNodeCodec<RefHasher>;
+}
+
+impl TrieConfiguration for NoExtensionLayout {}
+
 pub struct Bitmap(u16);
 
 const BITMAP_LENGTH: usize = 2;
@@ -170,6 +209,28 @@ fn partial_from_iterator_to_key<I: Iterator<Item = u8>>(
 	output
 }
 
+fn decode_value<H: Hasher>(data: &[u8], input: &mut ByteSliceInput) -> Result<ValuePlan, CodecError> {
+	if data.get(input.offset) == Some(&HASHED_VALUE) {
+		input.take(1)?;
+		return Ok(ValuePlan::Node(input.take(H::LENGTH)?))
+	}
+	let count = <Compact<u32>>::decode(input)?.0 as usize;
+	Ok(ValuePlan::Inline(input.take(count)?))
+}
+
+fn encode_value(value: Value, output: &mut Vec<u8>) {
+	match value {
+		Value::Inline(value) => {
+			Compact(value.len() as u32).encode_to(output);
+			output.extend_from_slice(value);
+		},
+		Value::Node(hash) => {
+			output.push(HASHED_VALUE);
+			output.extend_from_slice(hash);
+		},
+	}
+}
+
 /// Encoding of branch header and children bitmap for any radix.
 /// For codec/stream variant with extension.
 fn branch_node_buffered<I: Iterator<Item = bool>>(
@@ -204,8 +265,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 				let bitmap = Bitmap::decode(&data[bitmap_range])?;
 
 				let value = if has_value {
-					let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
-					Some(ValuePlan::Inline(input.take(count)?))
+					Some(decode_value::<H>(data, &mut input)?)
 				} else {
 					None
 				};
@@ -250,11 +310,10 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 						nibble_ops::NIBBLE_PER_BYTE,
 				)?;
 				let partial_padding = nibble_ops::number_padding(nibble_count);
-				let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
-				let value = input.take(count)?;
+				let value = decode_value::<H>(data, &mut input)?;
 				Ok(NodePlan::Leaf {
 					partial: NibbleSlicePlan::new(partial, partial_padding),
-					value: ValuePlan::Inline(value),
+					value,
 				})
 			},
 		}
@@ -272,13 +331,7 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
     // println!("leaf_node");
 		let mut output =
 			partial_from_iterator_to_key(partial, number_nibble, LEAF_NODE_OFFSET, LEAF_NODE_OVER);
-		match value {
-			Value::Inline(value) => {
-				Compact(value.len() as u32).encode_to(&mut output);
-				output.extend_from_slice(value);
-			},
-			_ => unimplemented!("unsupported"),
-		}
+		encode_value(value, &mut output);
 		output
 	}
 
@@ -310,13 +363,11 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 		let mut output = vec![0; BITMAP_LENGTH + 1];
 		let mut prefix: [u8; 3] = [0; 3];
 		let have_value = match maybe_value {
-			Some(Value::Inline(value)) => {
-				Compact(value.len() as u32).encode_to(&mut output);
-				output.extend_from_slice(value);
+			Some(value) => {
+				encode_value(value, &mut output);
 				true
 			},
 			None => false,
-			_ => unimplemented!("unsupported"),
 		};
 		let has_children = children.map(|maybe_child| match maybe_child.borrow() {
 			Some(ChildReference::Hash(h)) => {
@@ -343,3 +394,176 @@ impl<H: Hasher> NodeCodec for ReferenceNodeCodec<H> {
 		unreachable!("codec with extension branch")
 	}
 }
+
+/// A no-extension node header.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum NibbledHeader {
+	Null,
+	Branch(bool, usize),
+	Leaf(usize),
+}
+
+impl Encode for NibbledHeader {
+	fn encode_to<T: Output + ?Sized>(&self, output: &mut T) {
+		let (prefix, nibble_count) = match self {
+			NibbledHeader::Null => return output.push_byte(EMPTY_TRIE),
+			NibbledHeader::Branch(true, nibble_count) => (NIBBLED_BRANCH_WITH_VALUE, *nibble_count),
+			NibbledHeader::Branch(false, nibble_count) => (NIBBLED_BRANCH_NO_VALUE, *nibble_count),
+			NibbledHeader::Leaf(nibble_count) => (NIBBLED_LEAF, *nibble_count),
+		};
+		if nibble_count < NIBBLED_SIZE_MASK as usize {
+			return output.push_byte(prefix | nibble_count as u8)
+		}
+		output.push_byte(prefix | NIBBLED_SIZE_MASK);
+		let mut rest = nibble_count - NIBBLED_SIZE_MASK as usize;
+		while rest >= 255 {
+			output.push_byte(255);
+			rest -= 255;
+		}
+		output.push_byte(rest as u8);
+	}
+}
+
+impl Decode for NibbledHeader {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+		let byte = input.read_byte()?;
+		if byte == EMPTY_TRIE {
+			return Ok(NibbledHeader::Null)
+		}
+		let mut nibble_count = (byte & NIBBLED_SIZE_MASK) as usize;
+		if nibble_count == NIBBLED_SIZE_MASK as usize {
+			loop {
+				let next = input.read_byte()?;
+				nibble_count += next as usize;
+				if next != 255 {
+					break
+				}
+			}
+		}
+		match byte & !NIBBLED_SIZE_MASK {
+			NIBBLED_LEAF => Ok(NibbledHeader::Leaf(nibble_count)),
+			NIBBLED_BRANCH_NO_VALUE => Ok(NibbledHeader::Branch(false, nibble_count)),
+			NIBBLED_BRANCH_WITH_VALUE => Ok(NibbledHeader::Branch(true, nibble_count)),
+			_ => Err("invalid node header".into()),
+		}
+	}
+}
+
+fn decode_partial(input: &mut ByteSliceInput, nibble_count: usize) -> Result<NibbleSlicePlan, CodecError> {
+	let partial = input.take(
+		(nibble_count + (nibble_ops::NIBBLE_PER_BYTE - 1)) / nibble_ops::NIBBLE_PER_BYTE,
+	)?;
+	Ok(NibbleSlicePlan::new(partial, nibble_ops::number_padding(nibble_count)))
+}
+
+#[derive(Default, Clone)]
+pub struct NoExtensionNodeCodec<H>(PhantomData<H>);
+
+impl<H: Hasher> NodeCodec for NoExtensionNodeCodec<H> {
+	type Error = CodecError;
+	type HashOut = H::Out;
+
+	fn hashed_null_node() -> <H as Hasher>::Out {
+		H::hash(<Self as NodeCodec>::empty_node())
+	}
+
+	fn decode_plan(data: &[u8]) -> ::std::result::Result<NodePlan, Self::Error> {
+		let mut input = ByteSliceInput::new(data);
+
+		match NibbledHeader::decode(&mut input)? {
+			NibbledHeader::Null => Ok(NodePlan::Empty),
+			NibbledHeader::Branch(has_value, nibble_count) => {
+				let partial = decode_partial(&mut input, nibble_count)?;
+				let bitmap_range = input.take(BITMAP_LENGTH)?;
+				let bitmap = Bitmap::decode(&data[bitmap_range])?;
+
+				let value = if has_value {
+					Some(decode_value::<H>(data, &mut input)?)
+				} else {
+					None
+				};
+				let mut children = [
+					None, None, None, None, None, None, None, None, None, None, None, None, None,
+					None, None, None,
+				];
+				for i in 0..nibble_ops::NIBBLE_LENGTH {
+					if bitmap.value_at(i) {
+						let count = <Compact<u32>>::decode(&mut input)?.0 as usize;
+						let range = input.take(count)?;
+						children[i] = Some(if count == H::LENGTH {
+							NodeHandlePlan::Hash(range)
+						} else {
+							NodeHandlePlan::Inline(range)
+						});
+					}
+				}
+				Ok(NodePlan::NibbledBranch { partial, value, children })
+			},
+			NibbledHeader::Leaf(nibble_count) => {
+				let partial = decode_partial(&mut input, nibble_count)?;
+				let value = decode_value::<H>(data, &mut input)?;
+				Ok(NodePlan::Leaf { partial, value })
+			},
+		}
+	}
+
+	fn is_empty_node(data: &[u8]) -> bool {
+		data == <Self as NodeCodec>::empty_node()
+	}
+
+	fn empty_node() -> &'static [u8] {
+		&[EMPTY_TRIE]
+	}
+
+	fn leaf_node(partial: impl Iterator<Item = u8>, number_nibble: usize, value: Value) -> Vec<u8> {
+		let mut output = NibbledHeader::Leaf(number_nibble).encode();
+		output.extend(partial);
+		encode_value(value, &mut output);
+		output
+	}
+
+	fn extension_node(
+		_partial: impl Iterator<Item = u8>,
+		_number_nibble: usize,
+		_child: ChildReference<Self::HashOut>,
+	) -> Vec<u8> {
+		unreachable!("codec without extension")
+	}
+
+	fn branch_node(
+		_children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		_maybe_value: Option<Value>,
+	) -> Vec<u8> {
+		unreachable!("codec without extension")
+	}
+
+	fn branch_node_nibbled(
+		partial: impl Iterator<Item = u8>,
+		number_nibble: usize,
+		children: impl Iterator<Item = impl Borrow<Option<ChildReference<Self::HashOut>>>>,
+		maybe_value: Option<Value>,
+	) -> Vec<u8> {
+		let mut output = NibbledHeader::Branch(maybe_value.is_some(), number_nibble).encode();
+		output.extend(partial);
+		let bitmap_index = output.len();
+		output.extend_from_slice(&[0; BITMAP_LENGTH]);
+		if let Some(value) = maybe_value {
+			encode_value(value, &mut output);
+		}
+		let mut bitmap = [0u8; BITMAP_LENGTH];
+		let has_children = children.map(|maybe_child| match maybe_child.borrow() {
+			Some(ChildReference::Hash(h)) => {
+				h.as_ref().encode_to(&mut output);
+				true
+			},
+			&Some(ChildReference::Inline(inline_data, len)) => {
+				inline_data.as_ref()[..len].encode_to(&mut output);
+				true
+			},
+			None => false,
+		});
+		Bitmap::encode(has_children, &mut bitmap);
+		output[bitmap_index..bitmap_index + BITMAP_LENGTH].copy_from_slice(&bitmap);
+		output
+	}
+}
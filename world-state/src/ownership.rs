@@ -0,0 +1,258 @@
+use std::env;
+
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::{
+  auth::{signatures_from_args, signed_message, verify_owner},
+  db::WriteLock,
+  get_trie_results,
+  metacontract::MetaContract,
+  record::Record,
+  state::StateCommit,
+  types::TrieResult,
+  utils::now_ms,
+};
+
+/// One change of a program's owner, written by `transfer_program`. The
+/// latest one holds the program's current key.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
+pub struct Ownership {
+  pub program_id: String,
+  /// Number of the transfer from 1.
+  pub seq: u64,
+  pub public_key: String,
+  pub previous_key: String,
+  pub timestamp: u64,
+}
+
+impl Record for Ownership {
+  const CONFIG_PREFIX: &'static str = "OWNERSHIP";
+
+  /// `<program_id>/<seq>`, zero padded so a program's transfers sort in order.
+  fn primary_key(&self) -> Vec<u8> {
+    format!("{}/{:020}", self.program_id, self.seq).into_bytes()
+  }
+
+  /// History is only written by `transfer_program`.
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Ownership changes only through transfer_program".to_string())
+  }
+}
+
+/// A key authorised to write on behalf of `program_id`, see `Record::owner`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Owner {
+  pub program_id: String,
+  pub public_key: String,
+}
+
+impl Owner {
+  /// The key that owns the program now. A key the program was transferred
+  /// away from resolves to the current one, other keys are left as they are.
+  pub fn resolve(self) -> Owner {
+    let history = Ownership::history(&self.program_id);
+
+    match history.last() {
+      Some(latest) if history.iter().any(|o| o.previous_key == self.public_key) => Owner {
+        public_key: latest.public_key.clone(),
+        ..self
+      },
+      _ => self,
+    }
+  }
+}
+
+/// Keys owning a program together, any `threshold` of which sign for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+  pub public_keys: Vec<String>,
+  pub threshold: u64,
+}
+
+impl Policy {
+  const PREFIX: &'static str = "multisig:";
+
+  /// The key a policy is stored as wherever an owner's key is, so ownership
+  /// history and `Owner::resolve` treat it like any other key: the key
+  /// itself for a single key, `multisig:<threshold>:<key>,<key>...` with the
+  /// keys sorted otherwise.
+  pub fn id(&self) -> String {
+    if self.public_keys.len() == 1 && self.threshold == 1 {
+      return self.public_keys[0].clone();
+    }
+
+    let mut keys = self.public_keys.clone();
+    keys.sort();
+    format!("{}{}:{}", Self::PREFIX, self.threshold, keys.join(","))
+  }
+
+  /// The policy an owner's key stands for, a plain key being 1 of 1.
+  pub fn parse(key: &str) -> Result<Policy, String> {
+    let spec = match key.strip_prefix(Self::PREFIX) {
+      Some(spec) => spec,
+      None => {
+        let policy = Policy { public_keys: vec![key.to_string()], threshold: 1 };
+        policy.validate()?;
+        return Ok(policy);
+      },
+    };
+
+    let policy = match spec.split_once(':') {
+      Some((threshold, keys)) => Policy {
+        public_keys: keys.split(',').map(str::to_string).collect(),
+        threshold: threshold.parse().map_err(|_| format!("Invalid threshold in {}", key))?,
+      },
+      None => return Err(format!("Expected {}<threshold>:<key>,<key>... got {}", Self::PREFIX, key)),
+    };
+
+    policy.validate()?;
+    Ok(policy)
+  }
+
+  /// Every key must be one `crypto` verifies signatures for, listed once,
+  /// and the threshold between 1 and the number of keys.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.public_keys.is_empty() {
+      return Err("A policy needs at least one public key".to_string());
+    }
+    if self.threshold == 0 || self.threshold as usize > self.public_keys.len() {
+      return Err(format!("Threshold must be between 1 and {}", self.public_keys.len()));
+    }
+
+    for (i, key) in self.public_keys.iter().enumerate() {
+      if !crypto::valid_public_key(key) {
+        return Err(format!("Invalid public key {:?}", key));
+      }
+      if self.public_keys[..i].iter().any(|other| crypto::same_key(other, key)) {
+        return Err(format!("Public key {} is listed twice", key));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn contains(&self, key: &str) -> bool {
+    self.public_keys.iter().any(|k| crypto::same_key(k, key))
+  }
+}
+
+impl Ownership {
+  /// `transfer_program <program_id> <new_owner> --signature <sig> --new-signature <sig>`
+  ///
+  /// Both the current and the new owner sign `transfer_message`, a multisig
+  /// owner with `--signatures` or `--new-signatures`. `<new_owner>` is a key
+  /// or a `Policy::id`. The transfer and the metacontract's new owner are
+  /// stored in one commit.
+  pub fn transfer_program() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let (program_id, new_key) = match (args.get(2), args.get(3)) {
+      (Some(program_id), Some(new_key)) if !new_key.starts_with("--") => (program_id.clone(), new_key.clone()),
+      _ => return TrieResult { success: false, result: Some("Usage: transfer_program <program_id> <new_owner>".to_string()) },
+    };
+
+    let policy = match Policy::parse(&new_key) {
+      Ok(policy) => policy,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+    let new_key = policy.id();
+
+    let _lock = WriteLock::acquire();
+
+    let mut contract = match MetaContract::find(&program_id) {
+      Some(contract) => contract,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
+
+    let current = Owner { program_id: program_id.clone(), public_key: contract.public_key.clone() }.resolve();
+    if current.public_key == new_key {
+      return TrieResult { success: false, result: Some(format!("{} already owns {}", new_key, program_id)) };
+    }
+
+    let seq = Self::history(&program_id).last().map_or(1, |latest| latest.seq + 1);
+    let message = Self::transfer_message(&program_id, &new_key, seq);
+
+    for (prefix, key) in [("", &current.public_key), ("new-", &new_key)] {
+      let details = json!({ "signer": key, "signed_message": message });
+      let authorized = signatures_from_args(&args, prefix).and_then(|signed| verify_owner(key, &signed, &message, details));
+
+      if let Err(e) = authorized {
+        return e;
+      }
+    }
+
+    let transfer = Ownership {
+      program_id,
+      seq,
+      public_key: new_key.clone(),
+      previous_key: current.public_key,
+      timestamp: now_ms(),
+    };
+
+    let mut commit = StateCommit::new();
+    transfer.stage(&mut contract, policy, &mut commit);
+    if let Err(e) = commit.commit() {
+      return e;
+    }
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&transfer).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Stage the transfer with `contract`, its program's metacontract, made
+  /// owned by `policy`, the transfer's new key.
+  pub fn stage(&self, contract: &mut MetaContract, policy: Policy, commit: &mut StateCommit) {
+    contract.set_policy(policy);
+
+    commit.put(&[self.clone()]);
+    commit.put(&[contract.clone()]);
+  }
+
+  /// What both owners sign. The transfer number keeps a signature from being
+  /// replayed for a later transfer.
+  pub fn transfer_message(program_id: &str, new_key: &str, seq: u64) -> String {
+    signed_message(&json!({
+      "method": "transfer_program",
+      "program_id": program_id,
+      "public_key": new_key,
+      "seq": seq,
+    }))
+  }
+
+  /// Transfers of `program_id`, oldest first.
+  pub fn history(program_id: &str) -> Vec<Ownership> {
+    let mut history: Vec<Ownership> = get_trie_results(&Self::trie_name(), &Self::db_path(), Some(format!("{}/", program_id)))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .filter(|o| o.program_id == program_id)
+      .collect();
+    history.sort_by_key(|o| o.seq);
+    history
+  }
+}
+
+#[test]
+fn test_policy() {
+  let (k1, k2) = ("AKnL4NNf3DGWZJS6cPknBuEGnVsV4A4m5tgebLHaRSZ9", "9hSR6S7WPtxmTojgo6GG3k4yDPecgJY292j7xrsUGWBu");
+  let sig1 = "663kbM3QCzMLrWFSQck7YDqVFkJ4tauZWEuitSh7ZHri3pExbv4gZUx1ttZTh8mYkH7E3c6CRgpfaVKrAPcNAaDN";
+
+  let address = "0xAb00000000000000000000000000000000000000";
+  let policy = Policy { public_keys: vec![k1.to_string(), k2.to_string(), address.to_string()], threshold: 2 };
+  assert_eq!(policy.id(), format!("multisig:2:{},{},{}", address, k2, k1));
+  assert_eq!(Policy::parse(&policy.id()).unwrap().id(), policy.id());
+  assert_eq!(Policy::parse(k1).unwrap().id(), k1);
+  assert!(Policy::parse(&format!("multisig:3:{},{}", k1, k2)).is_err());
+  assert!(Policy::parse(&format!("multisig:0:{},{}", k1, k2)).is_err());
+  assert!(Policy::parse(&format!("multisig:1:{},{}", address, address.to_lowercase())).is_err());
+  for garbage in ["", "k1", "0xAb", &k1[..20], &format!("multisig:1:{},", k1)] {
+    assert!(Policy::parse(garbage).is_err(), "{:?}", garbage);
+  }
+
+  let signatures = [(k1.to_string(), sig1.to_string()), (k2.to_string(), sig1.to_string()), (k1.to_string(), sig1.to_string())];
+  let result = crypto::verify_threshold(&policy.public_keys, 2, &signatures, "m");
+  assert_eq!((result.valid, result.invalid, result.met), (vec![k1.to_string()], vec![k2.to_string()], false));
+  assert!(crypto::verify_threshold(&policy.public_keys, 1, &signatures, "m").met);
+}
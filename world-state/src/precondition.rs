@@ -0,0 +1,120 @@
+use keccak_hasher::keccak_256;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::{get_trie_value, record, types::TrieResult, utils::flag_value};
+
+/// Compare-and-swap condition on the stored leaf a write replaces. A write
+/// whose condition doesn't hold is rejected with a `conflict` error. Callers
+/// hold the `WriteLock` from the check until their commit.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Precondition {
+  /// Keccak-256 of the stored leaf in hex, empty when the leaf must not exist.
+  pub expected_value_hash: Option<String>,
+  /// `Record::revision` of the stored leaf, only for record types with one.
+  pub expected_version: Option<String>,
+}
+
+impl Precondition {
+  /// From `--expected-value-hash` and `--expected-version`, `None` when
+  /// neither is given.
+  pub fn from_args(args: &[String]) -> Option<Precondition> {
+    let precondition = Precondition {
+      expected_value_hash: flag_value(args, "--expected-value-hash"),
+      expected_version: flag_value(args, "--expected-version"),
+    };
+
+    if precondition.expected_value_hash.is_none() && precondition.expected_version.is_none() {
+      None
+    } else {
+      Some(precondition)
+    }
+  }
+
+  /// Check the condition against the leaf stored under `key` in `trie_key`.
+  pub fn check(&self, trie_key: &str, key: &[u8]) -> Result<(), TrieResult> {
+    let record = record::find(trie_key)
+      .ok_or(TrieResult { success: false, result: Some("Unknown trie".to_string()) })?;
+
+    if self.expected_version.is_some() && !record.has_revision() {
+      return Err(TrieResult::error(
+        "unsupported_precondition",
+        format!("{} records have no version, use expected_value_hash", trie_key),
+        json!({ "trie_key": trie_key }),
+      ));
+    }
+
+    let current = get_trie_value(trie_key, &record.db_path(), key)
+      .map_err(|e| TrieResult { success: false, result: Some(e) })?;
+    let current_hash = current.as_ref().map(|value| hex::encode(keccak_256(value)));
+
+    let current_version = match &current {
+      Some(value) => record.revision(value).map_err(|e| TrieResult {
+        success: false,
+        result: Some(format!("Error decoding {}: {}", trie_key, e)),
+      })?,
+      None => None,
+    };
+
+    let hash_matches = match &self.expected_value_hash {
+      Some(expected) if expected.is_empty() => current.is_none(),
+      Some(expected) => current_hash.as_deref() == Some(expected.trim_start_matches("0x").to_lowercase().as_str()),
+      None => true,
+    };
+    let version_matches = match &self.expected_version {
+      Some(expected) => current_version.as_ref() == Some(expected),
+      None => true,
+    };
+
+    if hash_matches && version_matches {
+      return Ok(());
+    }
+
+    Err(TrieResult::error(
+      "conflict",
+      format!("{} changed since it was read", String::from_utf8_lossy(key)),
+      json!({
+        "key": String::from_utf8_lossy(key),
+        "current_value_hash": current_hash,
+        "current_version": current_version,
+      }),
+    ))
+  }
+}
+
+/// Check the precondition given on the command line, if any.
+pub fn check_args(args: &[String], trie_key: &str, key: &[u8]) -> Result<(), TrieResult> {
+  match Precondition::from_args(args) {
+    Some(precondition) => precondition.check(trie_key, key),
+    None => Ok(()),
+  }
+}
+
+#[test]
+fn test_expected_version_needs_revision() {
+  use crate::{account::Account, db, metadata::Metadata, record::Record};
+
+  db::scratch(|| {
+    let precondition = Precondition { expected_value_hash: None, expected_version: Some("1".to_string()) };
+
+    let unsupported = precondition.check(&Account::trie_name(), b"key").unwrap_err();
+    assert_eq!(unsupported.code().as_deref(), Some("unsupported_precondition"));
+
+    let missing = precondition.check(&Metadata::trie_name(), b"key").unwrap_err();
+    assert_eq!(missing.code().as_deref(), Some("conflict"));
+  });
+}
+
+#[test]
+fn test_precondition_args() {
+  let args: Vec<String> = ["world-state", "insert_trie", "cron", "{}", "--expected-value-hash", ""].iter().map(|s| s.to_string()).collect();
+  let precondition = Precondition::from_args(&args).unwrap();
+  assert_eq!(precondition.expected_value_hash.as_deref(), Some(""));
+  assert!(precondition.expected_version.is_none());
+  assert!(Precondition::from_args(&args[..4]).is_none());
+
+  let error = TrieResult::error("conflict", "changed".to_string(), json!({ "key": "a" }));
+  let result: serde_json::Value = serde_json::from_str(&error.result.unwrap()).unwrap();
+  assert_eq!((result["code"].as_str(), result["key"].as_str()), (Some("conflict"), Some("a")));
+}
@@ -0,0 +1,256 @@
+use std::env;
+
+use hash_db::{AsHashDB, HashDB, Hasher, EMPTY_PREFIX};
+use keccak_hasher::KeccakHasher;
+use memory_db::{HashKey, MemoryDB};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use trie_db::{DBValue, NodeCodec, Recorder, Trie, TrieDBBuilder, TrieLayout};
+
+use crate::{
+  db::KVDatabase,
+  get_trie_root,
+  layout::{LayoutKind, with_layout},
+  record::{self, Record},
+  state::TrieRoot,
+  types::TrieResult,
+  utils::flag_value,
+};
+
+/// Merkle proof that each of `items` has its value, or with no value is
+/// absent, in the trie with `root`. `nodes` are the trie nodes read looking the
+/// keys up, as stored, so a proof is checked by repeating the lookups on them
+/// alone whatever the layout. Keys, values, the root and the nodes are hex
+/// encoded. `state` proves `root` in the state trie, chaining the items up
+/// to the global state root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Proof {
+  pub trie_key: String,
+  pub root: String,
+  /// `LayoutKind::tag` of the trie, which decides how the nodes are encoded.
+  pub layout: u8,
+  pub items: Vec<ProofItem>,
+  pub nodes: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub state: Option<Box<Proof>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProofItem {
+  pub key: String,
+  pub value: Option<String>,
+}
+
+impl Proof {
+  /// Prove the current value of each of `keys` in `trie_key`.
+  pub fn generate(trie_key: &str, keys: &[Vec<u8>]) -> Result<Proof, String> {
+    let record = record::find(trie_key).ok_or("Unknown trie".to_string())?;
+    let root = get_trie_root(trie_key)?;
+    let layout = LayoutKind::of_trie(trie_key);
+
+    let db = KVDatabase::open(&record.db_path());
+    let (values, nodes) = with_layout!(layout, L => prove::<L>(&db, &root, keys))?;
+
+    Ok(Proof {
+      trie_key: trie_key.to_string(),
+      root: hex::encode(root),
+      layout: layout.tag(),
+      items: keys
+        .iter()
+        .zip(values)
+        .map(|(key, value)| ProofItem { key: hex::encode(key), value: value.map(hex::encode) })
+        .collect(),
+      nodes: nodes.iter().map(hex::encode).collect(),
+      state: Self::generate_state(trie_key)?.map(Box::new),
+    })
+  }
+
+  /// Proof of the root of `trie_key` in the state trie, `None` for the state
+  /// trie itself or before it exists.
+  fn generate_state(trie_key: &str) -> Result<Option<Proof>, String> {
+    let state_key = TrieRoot::trie_name();
+    if trie_key == state_key || get_trie_root(&state_key)? == [0u8; 32] {
+      return Ok(None);
+    }
+    Self::generate(&state_key, &[trie_key.as_bytes().to_vec()]).map(Some)
+  }
+
+  /// Check the proof against its own root. Whether that root is the one
+  /// expected is up to the caller.
+  pub fn verify(&self) -> Result<(), String> {
+    let layout = LayoutKind::from_tag(self.layout).ok_or(format!("Unknown layout tag {}", self.layout))?;
+
+    let mut root = <KeccakHasher as Hasher>::Out::default();
+    hex::decode_to_slice(&self.root, &mut root).map_err(|e| format!("Invalid root: {}", e))?;
+
+    let nodes = self.nodes
+      .iter()
+      .map(hex::decode)
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| format!("Invalid node: {}", e))?;
+
+    let items = self.items
+      .iter()
+      .map(|item| Ok((hex::decode(&item.key)?, item.value.as_ref().map(hex::decode).transpose()?)))
+      .collect::<Result<Vec<_>, hex::FromHexError>>()
+      .map_err(|e| format!("Invalid item: {}", e))?;
+
+    with_layout!(layout, L => check::<L>(&root, &nodes, &items))?;
+
+    match &self.state {
+      Some(state) => self.verify_state(state),
+      None => Ok(()),
+    }
+  }
+
+  /// Check that `state` proves this proof's root in the state trie.
+  fn verify_state(&self, state: &Proof) -> Result<(), String> {
+    if state.trie_key != TrieRoot::trie_name() || state.state.is_some() {
+      return Err("Invalid proof: the state proof is not of the state trie".to_string());
+    }
+    state.verify()?;
+
+    let key = hex::encode(self.trie_key.as_bytes());
+    let proven = state.items
+      .iter()
+      .find(|item| item.key == key)
+      .and_then(|item| item.value.as_ref())
+      .and_then(|value| hex::decode(value).ok())
+      .and_then(|value| TrieRoot::decode_record(&value).ok());
+
+    match proven {
+      Some(proven) if proven.root == self.root => Ok(()),
+      _ => Err(format!("Invalid proof: {} root {} is not in the state trie", self.trie_key, self.root)),
+    }
+  }
+
+  /// The global state root the proof chains up to, if any.
+  pub fn state_root(&self) -> Option<&str> {
+    self.state.as_ref().map(|state| state.root.as_str())
+  }
+
+  /// `verify_proof <proof json> [--state-root <root>]`
+  ///
+  /// Returns the proven items with their values decoded as records of the
+  /// proof's trie, and the state root the proof chains up to. With
+  /// `--state-root` the proof must chain up to that root.
+  pub fn verify_proof() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let proof: Proof = match args.get(2).map(|arg| serde_json::from_str(arg)) {
+      Some(Ok(proof)) => proof,
+      Some(Err(e)) => return TrieResult { success: false, result: Some(format!("Error decoding proof: {}", e)) },
+      None => return TrieResult { success: false, result: Some("Missing proof".to_string()) },
+    };
+
+    if let Err(e) = proof.verify() {
+      return TrieResult { success: false, result: Some(e) };
+    }
+    if let Some(expected) = flag_value(&args, "--state-root") {
+      if proof.state_root() != Some(expected.as_str()) {
+        return TrieResult { success: false, result: Some(format!("Proof does not chain up to state root {}", expected)) };
+      }
+    }
+
+    let record = match record::find(&proof.trie_key) {
+      Some(record) => record,
+      None => return TrieResult { success: false, result: Some("Unknown trie".to_string()) },
+    };
+
+    let items: Vec<_> = proof.items
+      .iter()
+      .map(|item| {
+        let key = hex::decode(&item.key).unwrap_or_default();
+        let value = item.value
+          .as_ref()
+          .and_then(|value| hex::decode(value).ok())
+          .map(|value| record.decode_json(&value).unwrap_or(serde_json::Value::Null));
+        json!({ "key": String::from_utf8_lossy(&key), "record": value })
+      })
+      .collect();
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&json!({ "root": proof.root, "state_root": proof.state_root(), "items": items })).unwrap_or("".to_string())),
+    }
+  }
+}
+
+/// Values of `keys` and the nodes read looking them up.
+fn prove<L: TrieLayout<Hash = KeccakHasher>>(
+  db: &KVDatabase,
+  root: &<KeccakHasher as Hasher>::Out,
+  keys: &[Vec<u8>],
+) -> Result<(Vec<Option<Vec<u8>>>, Vec<Vec<u8>>), String> {
+  let hash_db = &db.as_hash_db();
+  let mut recorder = Recorder::<L>::new();
+
+  let values = {
+    let trie = TrieDBBuilder::<L>::new(hash_db, root).with_recorder(&mut recorder).build();
+    keys
+      .iter()
+      .map(|key| trie.get(key))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| format!("Error reading trie: {}", e))?
+  };
+
+  let mut nodes: Vec<Vec<u8>> = recorder.drain().into_iter().map(|record| record.data).collect();
+  nodes.sort();
+  nodes.dedup();
+
+  Ok((values, nodes))
+}
+
+fn check<L: TrieLayout<Hash = KeccakHasher>>(
+  root: &<KeccakHasher as Hasher>::Out,
+  nodes: &[Vec<u8>],
+  items: &[(Vec<u8>, Option<Vec<u8>>)],
+) -> Result<(), String> {
+  let mut memdb = MemoryDB::<KeccakHasher, HashKey<_>, DBValue>::new(L::Codec::empty_node());
+  for node in nodes {
+    memdb.insert(EMPTY_PREFIX, node);
+  }
+
+  let trie = TrieDBBuilder::<L>::new(&memdb, root).build();
+  for (key, value) in items {
+    match trie.get(key) {
+      Ok(found) if &found == value => (),
+      Ok(_) => return Err(format!("Invalid proof: wrong value for key {}", hex::encode(key))),
+      Err(e) => return Err(format!("Invalid proof: {}", e)),
+    }
+  }
+  Ok(())
+}
+
+#[test]
+fn test_proof_state() {
+  use crate::{apply::{test_tx, Apply}, auth::test_signature, db, metacontract::MetaContract, metadata::Metadata};
+
+  db::scratch(|| {
+    let (owner, _) = test_signature(1, "");
+    Apply::apply(test_tx("h1", "register_metacontract", &owner, "")).unwrap();
+    Apply::apply(test_tx("h2", "set_metadata", &owner, r#"{"cid":"c1"}"#)).unwrap();
+
+    let key = Metadata::chain("p", "d")[0].primary_key();
+    let proof = Proof::generate(&Metadata::trie_name(), &[key.clone()]).unwrap();
+    assert!(proof.items[0].value.is_some());
+    assert_eq!(proof.state_root(), Some(hex::encode(get_trie_root(&TrieRoot::trie_name()).unwrap()).as_str()));
+    assert!(proof.verify().is_ok());
+
+    // A state proof of another trie's root doesn't chain this one up.
+    let other = Proof::generate(&MetaContract::trie_name(), &[b"p".to_vec()]).unwrap();
+    let forged = Proof { state: other.state.clone(), ..proof.clone() };
+    assert!(forged.verify().unwrap_err().contains("is not in the state trie"));
+
+    let nested = Proof { state: Some(Box::new(proof.clone())), ..proof.clone() };
+    assert!(nested.verify().unwrap_err().contains("not of the state trie"));
+
+    let mut tampered = proof.clone();
+    tampered.state.as_mut().unwrap().root = hex::encode([1u8; 32]);
+    assert!(tampered.verify().is_err());
+
+    // The state trie itself has nothing to chain up to.
+    let state = Proof::generate(&TrieRoot::trie_name(), &[Metadata::trie_name().into_bytes()]).unwrap();
+    assert!(state.state.is_none() && state.verify().is_ok());
+  });
+}
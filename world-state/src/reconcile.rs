@@ -0,0 +1,358 @@
+use std::{collections::{BTreeMap, HashMap}, env};
+
+use keccak_hasher::KeccakHash;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+  commit_roots,
+  db::{KVDatabase, WriteLock},
+  get_trie_root,
+  read_layout_tag,
+  layout::{LayoutKind, with_layout},
+  record,
+  rqlite::RQLite,
+  state::StagedRoot,
+  trie_walk::walk_trie,
+  types::TrieResult,
+};
+
+/// Differences between the RocksDB nodes of a trie and its RQLite mirror.
+///
+/// `missing_in_*` lists nodes reachable from the other side's root that this
+/// side does not store, `extra_in_*` lists stored nodes that are not reachable
+/// from the other side's root and `mismatched` lists keys stored on both sides
+/// with different values. All keys are hex encoded.
+#[derive(Serialize, Debug, Default)]
+pub struct DriftReport {
+  pub trie_key: String,
+  pub rocksdb_root: String,
+  pub rqlite_root: String,
+  pub in_sync: bool,
+  pub missing_in_rqlite: Vec<String>,
+  pub missing_in_rocksdb: Vec<String>,
+  pub extra_in_rqlite: Vec<String>,
+  pub extra_in_rocksdb: Vec<String>,
+  pub mismatched: Vec<String>,
+  pub rocksdb_issues: Vec<String>,
+  pub rqlite_issues: Vec<String>,
+  pub fixed: Option<String>,
+  /// Why `--fix` left the trie as it was.
+  pub fix_error: Option<String>,
+}
+
+pub struct Reconcile;
+
+/// One side of a trie: its root, the layout tag recorded with it and every
+/// node stored, reachable or not.
+struct Side {
+  root: KeccakHash,
+  tag: Option<u8>,
+  layout: LayoutKind,
+  nodes: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Nodes to write to and delete from one side so it stores exactly the
+/// nodes reachable from the other side's root.
+#[derive(Debug, Default, PartialEq)]
+struct Repair {
+  put: Vec<(Vec<u8>, Vec<u8>)>,
+  delete: Vec<Vec<u8>>,
+}
+
+impl Repair {
+  fn of(nodes: &HashMap<Vec<u8>, Vec<u8>>, reachable: &BTreeMap<Vec<u8>, Vec<u8>>) -> Repair {
+    let mut delete: Vec<Vec<u8>> = nodes.keys().filter(|k| !reachable.contains_key(*k)).cloned().collect();
+    delete.sort();
+
+    Repair {
+      put: reachable.iter().filter(|(k, v)| nodes.get(*k) != Some(*v)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+      delete,
+    }
+  }
+}
+
+impl Reconcile {
+  /// `reconcile [trie_key] [--fix rocksdb|rqlite] [--force]`
+  ///
+  /// Without `--fix` only reports drift. `--fix rqlite` rewrites the mirror from
+  /// RocksDB, `--fix rocksdb` restores RocksDB from the mirror. Nothing is
+  /// reported or fixed when RQLite can't be read, and RocksDB is only emptied
+  /// to match a mirror without a root for the trie with `--force`.
+  pub fn reconcile() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let mut trie_key = None;
+    let mut fix = None;
+    let mut force = false;
+
+    let mut i = 2;
+    while i < args.len() {
+      if args[i] == "--fix" {
+        fix = args.get(i + 1).cloned();
+        i += 2;
+      } else if args[i] == "--force" {
+        force = true;
+        i += 1;
+      } else {
+        trie_key = Some(args[i].clone());
+        i += 1;
+      }
+    }
+
+    if let Some(side) = &fix {
+      if side != "rocksdb" && side != "rqlite" {
+        return TrieResult { success: false, result: Some("--fix expects rocksdb or rqlite".to_string()) };
+      }
+    }
+
+    let tries: Vec<(String, String)> = record::all()
+      .into_iter()
+      .map(|record| (record.trie_name(), record.db_path()))
+      .filter(|(key, _)| trie_key.is_none() || trie_key.as_ref() == Some(key))
+      .collect();
+
+    if tries.is_empty() {
+      return TrieResult { success: false, result: Some("Unknown trie".to_string()) };
+    }
+    if let Some(Err(e)) = tries.iter().map(|(key, _)| RQLite::table(key)).find(Result::is_err) {
+      return TrieResult { success: false, result: Some(e) };
+    }
+
+    let reports: Vec<DriftReport> = match tries
+      .iter()
+      .map(|(key, path)| Self::reconcile_trie(key, path, fix.as_deref(), force))
+      .collect()
+    {
+      Ok(reports) => reports,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&reports).unwrap_or("".to_string())),
+    }
+  }
+
+  fn reconcile_trie(trie_key: &str, db_path: &str, fix: Option<&str>, force: bool) -> Result<DriftReport, String> {
+    let KVDatabase { db, .. } = KVDatabase::open(db_path);
+
+    let rocksdb = Side {
+      root: get_trie_root(trie_key)?,
+      tag: read_layout_tag(trie_key),
+      layout: LayoutKind::of_trie(trie_key),
+      nodes: db.iter(0).filter_map(|item| item.ok()).map(|(key, value)| (key.to_vec(), value)).collect(),
+    };
+    let mirrored = Self::rqlite_root(trie_key)?;
+    let (root, tag) = mirrored.unwrap_or(([0u8; 32], None));
+    let rqlite = Side {
+      root,
+      tag,
+      layout: tag.and_then(LayoutKind::from_tag).unwrap_or(rocksdb.layout),
+      nodes: Self::rqlite_rows(trie_key)?,
+    };
+
+    let (mut report, rqlite_repair, rocksdb_repair) = Self::compare(trie_key, &rocksdb, &rqlite);
+
+    if report.in_sync {
+      return Ok(report);
+    }
+
+    match fix {
+      Some("rqlite") => {
+        RQLite::execute_batch(&Self::rqlite_statements(trie_key, &rocksdb, &rqlite_repair));
+        report.fixed = Some("rqlite".to_string());
+      },
+      Some("rocksdb") if mirrored.is_none() && rocksdb.root != [0u8; 32] && !force => {
+        report.fix_error = Some(format!("RQLite has no root for {}, pass --force to empty it in RocksDB", trie_key));
+      },
+      Some("rocksdb") => {
+        let _lock = WriteLock::acquire();
+
+        let mut transaction = db.transaction();
+        for (key, value) in rocksdb_repair.put.iter() {
+          transaction.put(0, key, value);
+        }
+        db.write(transaction).expect("Failed to write transaction");
+
+        // The mirror's root becomes current like any other commit, so the
+        // state trie follows it; unreachable nodes go once it is current.
+        let staged = StagedRoot {
+          trie_key: trie_key.to_string(),
+          db_path: db_path.to_string(),
+          layout: rqlite.layout,
+          base: rocksdb.root,
+          root: rqlite.root,
+          obsolete: rocksdb_repair.delete,
+          written: rocksdb_repair.put.into_iter().map(|(key, _)| key).collect(),
+        };
+
+        match commit_roots(vec![staged]) {
+          Ok(()) => report.fixed = Some("rocksdb".to_string()),
+          Err(e) => report.fix_error = e.result,
+        }
+      },
+      _ => (),
+    }
+
+    Ok(report)
+  }
+
+  /// Drift between the two sides of `trie_key`, with what repairs RQLite
+  /// from RocksDB and what repairs RocksDB from RQLite.
+  fn compare(trie_key: &str, rocksdb: &Side, rqlite: &Side) -> (DriftReport, Repair, Repair) {
+    let rocksdb_walk = with_layout!(rocksdb.layout, L => walk_trie::<L, _>(&rocksdb.root, |key| rocksdb.nodes.get(key).cloned()));
+    let rqlite_walk = with_layout!(rqlite.layout, L => walk_trie::<L, _>(&rqlite.root, |key| rqlite.nodes.get(key).cloned()));
+
+    let rocksdb_reachable: BTreeMap<Vec<u8>, Vec<u8>> = rocksdb_walk.nodes.into_iter().map(|n| (n.db_key, n.data)).collect();
+    let rqlite_reachable: BTreeMap<Vec<u8>, Vec<u8>> = rqlite_walk.nodes.into_iter().map(|n| (n.db_key, n.data)).collect();
+
+    let keys = |keys: Vec<&Vec<u8>>| -> Vec<String> {
+      let mut keys: Vec<String> = keys.into_iter().map(hex::encode).collect();
+      keys.sort();
+      keys
+    };
+
+    let mut report = DriftReport {
+      trie_key: trie_key.to_string(),
+      rocksdb_root: hex::encode(rocksdb.root),
+      rqlite_root: hex::encode(rqlite.root),
+      missing_in_rqlite: keys(rocksdb_reachable.keys().filter(|k| !rqlite.nodes.contains_key(*k)).collect()),
+      missing_in_rocksdb: keys(rqlite_reachable.keys().filter(|k| !rocksdb.nodes.contains_key(*k)).collect()),
+      extra_in_rqlite: keys(rqlite.nodes.keys().filter(|k| !rocksdb_reachable.contains_key(*k)).collect()),
+      extra_in_rocksdb: keys(rocksdb.nodes.keys().filter(|k| !rqlite_reachable.contains_key(*k)).collect()),
+      mismatched: keys(rqlite.nodes.iter().filter(|(k, v)| rocksdb.nodes.get(*k).map_or(false, |value| value != *v)).map(|(k, _)| k).collect()),
+      rocksdb_issues: rocksdb_walk.issues.iter().map(|issue| issue.to_string()).collect(),
+      rqlite_issues: rqlite_walk.issues.iter().map(|issue| issue.to_string()).collect(),
+      ..Default::default()
+    };
+
+    report.in_sync = rocksdb.root == rqlite.root
+      && rocksdb.tag == rqlite.tag
+      && report.missing_in_rqlite.is_empty()
+      && report.missing_in_rocksdb.is_empty()
+      && report.extra_in_rqlite.is_empty()
+      && report.extra_in_rocksdb.is_empty()
+      && report.mismatched.is_empty();
+
+    (report, Repair::of(&rqlite.nodes, &rocksdb_reachable), Repair::of(&rocksdb.nodes, &rqlite_reachable))
+  }
+
+  /// Statements making the mirror of `trie_key` hold `repair` and the root
+  /// of `rocksdb`. Keys and values are hex, the trie key a checked table
+  /// name, and every literal is quoted.
+  fn rqlite_statements(trie_key: &str, rocksdb: &Side, repair: &Repair) -> Vec<String> {
+    let mut statements: Vec<String> = repair.put
+      .iter()
+      .map(|(key, value)| format!("INSERT OR REPLACE INTO {} (trie_key, trie_value) VALUES ({}, {})",
+        trie_key,
+        RQLite::quote(&hex::encode(key)),
+        RQLite::quote(&hex::encode(value)),
+      ))
+      .collect();
+    for key in repair.delete.iter() {
+      statements.push(format!("DELETE FROM {} WHERE trie_key = {}", trie_key, RQLite::quote(&hex::encode(key))));
+    }
+
+    if rocksdb.root == [0u8; 32] {
+      statements.push(format!("DELETE FROM roots WHERE root_key = {}", RQLite::quote(trie_key)));
+    } else {
+      statements.push(format!("INSERT OR REPLACE INTO roots (root_key, root_value) VALUES ({}, {})",
+        RQLite::quote(trie_key),
+        RQLite::quote(&hex::encode(Self::root_entry(&rocksdb.root, rocksdb.tag))),
+      ));
+    }
+    statements
+  }
+
+  /// Root mirrored in RQLite and the layout tag recorded after it, `None`
+  /// without a row for `trie_key`.
+  fn rqlite_root(trie_key: &str) -> Result<Option<(KeccakHash, Option<u8>)>, String> {
+    let rows = RQLite::query_rows(&format!("SELECT root_value FROM roots WHERE root_key = {}", RQLite::quote(trie_key)))?;
+
+    let value = match rows.get(0) {
+      Some(row) => row.get("root_value").and_then(Value::as_str).unwrap_or_default(),
+      None => return Ok(None),
+    };
+
+    match hex::decode(value) {
+      Ok(bytes) if bytes.len() == 32 || bytes.len() == 33 => {
+        let mut root = [0u8; 32];
+        root.copy_from_slice(&bytes[..32]);
+        Ok(Some((root, bytes.get(32).copied())))
+      },
+      _ => Err(format!("RQLite root of {} is not a root: {:?}", trie_key, value)),
+    }
+  }
+
+  fn root_entry(root: &KeccakHash, tag: Option<u8>) -> Vec<u8> {
+    let mut entry = root.to_vec();
+    entry.extend(tag);
+    entry
+  }
+
+  fn rqlite_rows(trie_key: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, String> {
+    Ok(RQLite::query_rows(&format!("SELECT trie_key, trie_value FROM {}", trie_key))?
+      .into_iter()
+      .filter_map(|row| {
+        let key = hex::decode(row.get("trie_key")?.as_str()?).ok()?;
+        let value = hex::decode(row.get("trie_value")?.as_str()?).ok()?;
+        Some((key, value))
+      })
+      .collect())
+  }
+}
+
+#[test]
+fn test_compare() {
+  use crate::{node_codec::ExtensionLayout, trie_walk::test_nodes};
+
+  let value = [7u8; 40];
+  let (root, nodes) = test_nodes::<ExtensionLayout>(&[(b"alpha", &value), (b"beta", &value), (b"gamma", b"3")]);
+  let (old_root, old_nodes) = test_nodes::<ExtensionLayout>(&[(b"alpha", &value), (b"beta", b"0"), (b"Delta", b"4")]);
+
+  let mut rocksdb = Side { root, tag: Some(0), layout: LayoutKind::Extension, nodes: nodes.clone() };
+  rocksdb.nodes.insert(b"garbage".to_vec(), b"x".to_vec());
+  let mut rqlite = Side { root: old_root, tag: Some(0), layout: LayoutKind::Extension, nodes: old_nodes };
+  let corrupted = rqlite.nodes.keys().find(|key| nodes.contains_key(*key)).cloned().unwrap();
+  rqlite.nodes.insert(corrupted.clone(), b"corrupted".to_vec());
+
+  let (report, rqlite_repair, _) = Reconcile::compare("metadata", &rocksdb, &rqlite);
+  assert!(!report.in_sync);
+  assert!(!report.missing_in_rqlite.is_empty() && !report.extra_in_rqlite.is_empty());
+  assert_eq!(report.mismatched, vec![hex::encode(&corrupted)]);
+  assert!(report.extra_in_rocksdb.contains(&hex::encode(b"garbage")));
+  assert!(report.rocksdb_issues.is_empty());
+
+  let statements = Reconcile::rqlite_statements("metadata", &rocksdb, &rqlite_repair);
+  assert_eq!(statements.len(), rqlite_repair.put.len() + rqlite_repair.delete.len() + 1);
+  assert_eq!(statements.last().unwrap(), &format!("INSERT OR REPLACE INTO roots (root_key, root_value) VALUES ('metadata', '{}00')", hex::encode(root)));
+
+  let fix = |side: &mut Side, repair: Repair, root: KeccakHash| {
+    side.nodes.extend(repair.put);
+    for key in repair.delete {
+      side.nodes.remove(&key);
+    }
+    side.root = root;
+  };
+
+  // RocksDB to RQLite leaves the RocksDB garbage alone.
+  fix(&mut rqlite, rqlite_repair, root);
+  assert_eq!(rqlite.nodes, nodes);
+  let (report, _, rocksdb_repair) = Reconcile::compare("metadata", &rocksdb, &rqlite);
+  assert!(!report.in_sync);
+  assert_eq!(report.extra_in_rocksdb, vec![hex::encode(b"garbage")]);
+  assert_eq!(rocksdb_repair, Repair { put: Vec::new(), delete: vec![b"garbage".to_vec()] });
+
+  fix(&mut rocksdb, rocksdb_repair, root);
+  assert!(Reconcile::compare("metadata", &rocksdb, &rqlite).0.in_sync);
+
+  // RQLite to RocksDB, from a mirror a root behind.
+  let (old_root, old_nodes) = test_nodes::<ExtensionLayout>(&[(b"alpha", &value)]);
+  let rqlite = Side { root: old_root, tag: Some(0), layout: LayoutKind::Extension, nodes: old_nodes.clone() };
+  let (report, _, rocksdb_repair) = Reconcile::compare("metadata", &rocksdb, &rqlite);
+  assert!(!report.in_sync && !report.extra_in_rocksdb.is_empty());
+  fix(&mut rocksdb, rocksdb_repair, old_root);
+  assert_eq!(rocksdb.nodes, old_nodes);
+  assert!(Reconcile::compare("metadata", &rocksdb, &rqlite).0.in_sync);
+}
@@ -0,0 +1,339 @@
+use std::{collections::HashMap, marker::PhantomData};
+
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+  account::Account,
+  acl::Grant,
+  block::{BlockHeader, BlockIndex, BlockTip},
+  appconfig::CONFIG,
+  build_trie_db,
+  cron::Cron,
+  cron_run::CronRun,
+  genesis::Genesis,
+  metacontract::MetaContract,
+  metadata::Metadata,
+  ownership::{Owner, Ownership},
+  schema::{Schema, SCHEMA_TYPES},
+  state::TrieRoot,
+  transaction::Transaction,
+  transaction_receipt::TransactionReceipt,
+  types::TrieResult,
+};
+
+/// A record type stored in its own trie, keyed by `primary_key`.
+///
+/// Its trie name and node db path come from `<CONFIG_PREFIX>_KEY` and
+/// `<CONFIG_PREFIX>_DB_PATH`, defaulting to the lowercased prefix and
+/// `./db/<trie name>`.
+pub trait Record: Serialize + DeserializeOwned + Encodable + Decodable + Clone + Sync {
+  const CONFIG_PREFIX: &'static str;
+
+  /// Version written with every record. Bump it when the fields change and
+  /// keep decoding the previous layouts in `decode_version`.
+  const VERSION: u64 = 1;
+
+  fn primary_key(&self) -> Vec<u8>;
+
+  fn trie_name() -> String {
+    CONFIG
+      .get::<String>(&format!("{}_KEY", Self::CONFIG_PREFIX))
+      .unwrap_or(Self::CONFIG_PREFIX.to_lowercase())
+  }
+
+  fn db_path() -> String {
+    CONFIG
+      .get::<String>(&format!("{}_DB_PATH", Self::CONFIG_PREFIX))
+      .unwrap_or(format!("./db/{}", Self::trie_name()))
+  }
+
+  /// `[VERSION, record]`, see `record_version`.
+  fn encode_record(&self) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&Self::VERSION);
+    stream.append(self);
+    stream.out().to_vec()
+  }
+
+  fn decode_record(data: &[u8]) -> Result<Self, DecoderError> {
+    let rlp = Rlp::new(data);
+    match record_version(data)? {
+      0 => Self::decode_version(0, &rlp),
+      version => Self::decode_version(version, &rlp.at(1)?),
+    }
+  }
+
+  /// Decode the fields of a record written with `version`, 0 being the
+  /// unversioned lists stored before records carried a version.
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    if version == 0 || version == Self::VERSION {
+      Self::decode(rlp)
+    } else {
+      Err(DecoderError::Custom("unsupported record version"))
+    }
+  }
+
+  /// Validate a record given as JSON and fill in derived fields before it is
+  /// stored by `insert_trie`.
+  fn prepare(&mut self) -> Result<(), String> {
+    Ok(())
+  }
+
+  /// `prepare`, returning the key of the stored leaf this record supersedes,
+  /// which write preconditions are checked against.
+  fn prepare_replacing(&mut self) -> Result<Vec<u8>, String> {
+    self.prepare()?;
+    Ok(self.primary_key())
+  }
+
+  /// Whether `revision` is implemented. `expected_version` preconditions are
+  /// refused for record types without one.
+  const HAS_REVISION: bool = false;
+
+  /// Revision an `expected_version` precondition is compared with, `None` for
+  /// records without one.
+  fn revision(&self) -> Option<String> {
+    None
+  }
+
+  /// Key that must sign a write replacing this record, `None` when anyone may
+  /// replace it. Resolved to the program's current owner before checking.
+  fn owner(&self) -> Option<Owner> {
+    None
+  }
+
+  /// Fields by name, as matched by `filter_trie`.
+  fn fields(&self) -> HashMap<String, Value> {
+    match serde_json::to_value(self) {
+      Ok(Value::Object(map)) => map.into_iter().collect(),
+      _ => HashMap::new(),
+    }
+  }
+}
+
+/// Version of a stored record. Versioned records are a two item list of the
+/// version and the fields, unversioned ones are the bare list of fields, which
+/// always has more than two items.
+pub fn record_version(data: &[u8]) -> Result<u64, DecoderError> {
+  let rlp = Rlp::new(data);
+  if rlp.item_count()? == 2 && rlp.at(1)?.is_list() {
+    rlp.val_at(0)
+  } else {
+    Ok(0)
+  }
+}
+
+/// Field `index` of an older record version, or its default when that version
+/// predates the field. For records that only ever append fields.
+pub fn field_or_default<T: Decodable + Default>(rlp: &Rlp, index: usize) -> Result<T, DecoderError> {
+  if index < rlp.item_count()? {
+    rlp.val_at(index)
+  } else {
+    Ok(T::default())
+  }
+}
+
+/// `field_or_default` for a list field.
+pub fn list_or_default<T: Decodable>(rlp: &Rlp, index: usize) -> Result<Vec<T>, DecoderError> {
+  if index < rlp.item_count()? {
+    rlp.list_at(index)
+  } else {
+    Ok(Vec::new())
+  }
+}
+
+/// Insert `records` into their trie in a single write.
+pub fn insert_records<T: Record>(records: &[T]) -> Result<(), TrieResult> {
+  let pairs: Vec<(Vec<u8>, Vec<u8>)> = records
+    .iter()
+    .map(|record| (record.primary_key(), record.encode_record()))
+    .collect();
+
+  build_trie_db(&T::trie_name(), &T::db_path(), &pairs).map(|_| ())
+}
+
+/// Type erased `Record`, what commands dispatch on by trie name.
+pub trait RecordType: Sync {
+  fn config_prefix(&self) -> &str;
+
+  fn trie_name(&self) -> String;
+
+  fn db_path(&self) -> String;
+
+  /// A record given as JSON, validated and encoded for writing.
+  fn prepare_json(&self, value: Value) -> Result<PreparedRecord, String>;
+
+  /// Trie key and encoded value of a record given as JSON.
+  fn pair_from_json(&self, value: Value) -> Result<(Vec<u8>, Vec<u8>), String> {
+    self.prepare_json(value).map(|prepared| (prepared.key, prepared.value))
+  }
+
+  /// A stored value as JSON.
+  fn decode_json(&self, data: &[u8]) -> Result<Value, DecoderError>;
+
+  /// A stored value's fields by name.
+  fn decode_fields(&self, data: &[u8]) -> Result<HashMap<String, Value>, DecoderError>;
+
+  /// A stored value re-encoded with the current version.
+  fn reencode(&self, data: &[u8]) -> Result<Vec<u8>, DecoderError>;
+
+  /// See `Record::HAS_REVISION`.
+  fn has_revision(&self) -> bool;
+
+  /// `Record::revision` of a stored value.
+  fn revision(&self, data: &[u8]) -> Result<Option<String>, DecoderError>;
+
+  /// `Record::owner` of a stored value.
+  fn owner(&self, data: &[u8]) -> Result<Option<Owner>, DecoderError>;
+}
+
+/// A record ready to be written under `key`.
+pub struct PreparedRecord {
+  pub key: Vec<u8>,
+  pub value: Vec<u8>,
+  /// See `Record::replaces`.
+  pub replaces: Vec<u8>,
+}
+
+pub struct RecordKind<T>(PhantomData<T>);
+
+impl<T: Record> RecordType for RecordKind<T> {
+  fn config_prefix(&self) -> &str {
+    T::CONFIG_PREFIX
+  }
+
+  fn trie_name(&self) -> String {
+    T::trie_name()
+  }
+
+  fn db_path(&self) -> String {
+    T::db_path()
+  }
+
+  fn prepare_json(&self, value: Value) -> Result<PreparedRecord, String> {
+    let mut record: T = serde_json::from_value(value)
+      .map_err(|e| format!("Error decoding {}: {}", T::trie_name(), e))?;
+    let replaces = record.prepare_replacing()?;
+
+    Ok(PreparedRecord {
+      key: record.primary_key(),
+      value: record.encode_record(),
+      replaces,
+    })
+  }
+
+  fn decode_json(&self, data: &[u8]) -> Result<Value, DecoderError> {
+    T::decode_record(data).map(|record| serde_json::to_value(&record).unwrap_or(Value::Null))
+  }
+
+  fn decode_fields(&self, data: &[u8]) -> Result<HashMap<String, Value>, DecoderError> {
+    T::decode_record(data).map(|record| record.fields())
+  }
+
+  fn reencode(&self, data: &[u8]) -> Result<Vec<u8>, DecoderError> {
+    T::decode_record(data).map(|record| record.encode_record())
+  }
+
+  fn has_revision(&self) -> bool {
+    T::HAS_REVISION
+  }
+
+  fn revision(&self, data: &[u8]) -> Result<Option<String>, DecoderError> {
+    T::decode_record(data).map(|record| record.revision())
+  }
+
+  fn owner(&self, data: &[u8]) -> Result<Option<Owner>, DecoderError> {
+    T::decode_record(data).map(|record| record.owner())
+  }
+}
+
+/// Every built-in record type with a trie. Registering a type here is all
+/// that's needed for `init`, `insert_trie`, `insert_trie_batch`, `filter_trie`,
+/// `verify_db` and `reconcile` to handle it.
+pub static REGISTRY: &[&dyn RecordType] = &[
+  &RecordKind::<Transaction>(PhantomData),
+  &RecordKind::<TransactionReceipt>(PhantomData),
+  &RecordKind::<Cron>(PhantomData),
+  &RecordKind::<CronRun>(PhantomData),
+  &RecordKind::<Metadata>(PhantomData),
+  &RecordKind::<MetaContract>(PhantomData),
+  &RecordKind::<Ownership>(PhantomData),
+  &RecordKind::<Grant>(PhantomData),
+  &RecordKind::<Account>(PhantomData),
+  &RecordKind::<Schema>(PhantomData),
+  &RecordKind::<Genesis>(PhantomData),
+  &RecordKind::<BlockHeader>(PhantomData),
+  &RecordKind::<BlockIndex>(PhantomData),
+  &RecordKind::<BlockTip>(PhantomData),
+  &RecordKind::<TrieRoot>(PhantomData),
+];
+
+/// Built-in record types followed by the runtime schemas.
+pub fn all() -> Vec<&'static dyn RecordType> {
+  let mut records = REGISTRY.to_vec();
+  records.extend(SCHEMA_TYPES.iter().map(|schema| schema as &dyn RecordType));
+  records
+}
+
+/// The record type stored in `trie_name`. Built-in types are looked up first
+/// so that loading the schemas never depends on them.
+pub fn find(trie_name: &str) -> Option<&'static dyn RecordType> {
+  REGISTRY
+    .iter()
+    .find(|record| record.trie_name() == trie_name)
+    .copied()
+    .or_else(|| SCHEMA_TYPES.iter().find(|schema| schema.trie_name() == trie_name).map(|schema| schema as &dyn RecordType))
+}
+
+#[test]
+fn test_record_registry() {
+  use crate::child_filter;
+
+  for record in REGISTRY.iter() {
+    assert_eq!(find(&record.trie_name()).map(|r| r.config_prefix()), Some(record.config_prefix()));
+  }
+
+  let tx: Transaction = serde_json::from_str(r#"{"hash":"h","method":"m","program_id":"p","data_key":"dk","data":"d","public_key":"pk","alias":"a","timestamp":1,"chain_id":"c","token_address":"t","token_id":"1","version":"v","mcdata":"","status":0}"#).unwrap();
+  let fields = find("tx").unwrap().decode_fields(&tx.encode_record()).unwrap();
+  assert_eq!(fields.get("data_key"), Some(&Value::String("dk".to_string())));
+  assert!(child_filter(&fields, &serde_json::from_str::<Vec<Value>>(r#"[{"data_key":"dk"},{"status":0}]"#).unwrap()));
+}
+
+#[test]
+fn test_record_versions() {
+  let receipt = TransactionReceipt {
+    hash: "h".into(),
+    program_id: "p".into(),
+    status: 1,
+    timestamp: 2,
+    error_text: "".into(),
+    data: "d".into(),
+  };
+
+  let legacy = rlp::encode(&receipt).to_vec();
+  let current = receipt.encode_record();
+
+  assert_eq!(record_version(&legacy).unwrap(), 0);
+  assert_eq!(record_version(&current).unwrap(), TransactionReceipt::VERSION);
+  assert_eq!(TransactionReceipt::decode_record(&legacy).unwrap().data, "d");
+  assert_eq!(TransactionReceipt::decode_record(&current).unwrap().data, "d");
+  assert_eq!(find("receipt").unwrap().reencode(&legacy).unwrap(), current);
+
+  // A version 1 transaction, before nonces.
+  let mut stream = rlp::RlpStream::new_list(2);
+  stream.append(&1u64);
+  stream.begin_list(14);
+  for field in ["h", "m", "p", "k", "d", "pk", "a"] {
+    stream.append(&field);
+  }
+  stream.append(&3u64);
+  for field in ["c", "ta", "ti", "v", "mc"] {
+    stream.append(&field);
+  }
+  stream.append(&0u64);
+
+  let tx = Transaction::decode_record(&stream.out()).unwrap();
+  assert_eq!((tx.public_key.as_str(), tx.chain_id.as_str(), tx.nonce), ("pk", "c", 0));
+}
@@ -0,0 +1,326 @@
+use std::{collections::HashMap, env, fs};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+  acl::Grant,
+  apply::Apply,
+  auth::signed_message,
+  block::BlockHeader,
+  cron::Cron,
+  db::{self, Redirect},
+  genesis::Genesis,
+  get_trie_results,
+  get_trie_root,
+  metacontract::MetaContract,
+  metadata::Metadata,
+  ownership::{Ownership, Policy},
+  record::{self, Record},
+  state::StateCommit,
+  transaction::{Transaction, TX_APPLIED, TX_PENDING},
+  transaction_receipt::TransactionReceipt,
+  types::TrieResult,
+  utils::now_ms,
+};
+
+/// A trie as stored and as rebuilt by `replay`. Roots are hex encoded, the
+/// records only one side holds are given as JSON.
+#[derive(Serialize, Debug)]
+pub struct TrieComparison {
+  pub trie_key: String,
+  pub root: String,
+  pub replayed_root: String,
+  pub matches: bool,
+  pub only_stored: Vec<Value>,
+  pub only_replayed: Vec<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReplayReport {
+  pub transactions: usize,
+  /// Receipts of the transactions that failed when replayed.
+  pub failed: Vec<TransactionReceipt>,
+  pub tries: Vec<TrieComparison>,
+  pub matches: bool,
+}
+
+pub struct Replay;
+
+/// Transfers and grants as they were when each transaction was applied,
+/// written to the replay's own tries before it is applied again.
+struct AsOf {
+  /// By time, those before `next` are written.
+  transfers: Vec<Ownership>,
+  next: usize,
+  /// With the version last written of each.
+  grants: Vec<(Grant, Option<Grant>)>,
+}
+
+impl Replay {
+  /// `replay --from-genesis`
+  ///
+  /// Builds the genesis tries in a scratch directory, or starts from empty
+  /// tries without a genesis, applies every applied transaction again in the
+  /// order it was applied, by receipt time and then block position, and
+  /// compares the metadata, metacontract and cron tries with the stored ones.
+  /// Ownership and grants don't come from transactions, each transaction
+  /// sees the transfers, with the metacontract owners they set, and the
+  /// grants of its time; only the latest version of a grant is kept, so one
+  /// changed since counts as absent until its change. Records written
+  /// outside transactions, by `insert_trie` or cron runs, show up as
+  /// differences.
+  pub fn replay() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    if !args.iter().any(|arg| arg == "--from-genesis") {
+      return TrieResult { success: false, result: Some("Usage: replay --from-genesis".to_string()) };
+    }
+
+    Self::from_genesis()
+  }
+
+  fn from_genesis() -> TrieResult {
+    let compared = [Metadata::trie_name(), MetaContract::trie_name(), Cron::trie_name()];
+
+    let log = Self::applied();
+    let mut as_of = AsOf::stored();
+
+    let stored: Vec<([u8; 32], Vec<String>)> = match compared.iter().map(|trie_key| Self::snapshot(trie_key)).collect() {
+      Ok(stored) => stored,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let genesis = Genesis::find();
+
+    let dir = env::temp_dir().join(format!("world-state-replay-{}-{}", std::process::id(), now_ms()));
+    let previous = db::redirect(Some(Redirect { dir: dir.to_string_lossy().to_string(), shared: Vec::new() }));
+    let restore = |previous| {
+      db::redirect(previous);
+      fs::remove_dir_all(&dir).ok();
+    };
+
+    if let Some(Err(e)) = genesis.map(|genesis| genesis.restore()) {
+      restore(previous);
+      return e;
+    }
+
+    let transactions = log.len();
+    let mut failed: Vec<TransactionReceipt> = Vec::new();
+    for (tx, applied_at) in log {
+      let replayed = as_of.write(applied_at).and_then(|_| Apply::apply(Transaction { status: TX_PENDING, ..tx }));
+      match replayed {
+        Ok(receipt) if receipt.status != TX_APPLIED => failed.push(receipt),
+        Ok(_) => (),
+        Err(e) => {
+          restore(previous);
+          return e;
+        },
+      }
+    }
+
+    // The transfers made since the last transaction.
+    if let Err(e) = as_of.write(u64::MAX) {
+      restore(previous);
+      return e;
+    }
+
+    let replayed: Result<Vec<([u8; 32], Vec<String>)>, String> = compared.iter().map(|trie_key| Self::snapshot(trie_key)).collect();
+
+    restore(previous);
+
+    let replayed = match replayed {
+      Ok(replayed) => replayed,
+      Err(e) => return TrieResult { success: false, result: Some(e) },
+    };
+
+    let tries: Vec<TrieComparison> = compared
+      .iter()
+      .zip(stored.into_iter().zip(replayed))
+      .map(|(trie_key, ((root, records), (replayed_root, replayed_records)))| {
+        let as_json = |records: Vec<&String>| -> Vec<Value> {
+          records.into_iter().filter_map(|r| serde_json::from_str(r).ok()).collect()
+        };
+
+        TrieComparison {
+          trie_key: trie_key.clone(),
+          root: hex::encode(root),
+          replayed_root: hex::encode(replayed_root),
+          matches: root == replayed_root,
+          only_stored: as_json(records.iter().filter(|r| !replayed_records.contains(r)).collect()),
+          only_replayed: as_json(replayed_records.iter().filter(|r| !records.contains(r)).collect()),
+        }
+      })
+      .collect();
+
+    let report = ReplayReport {
+      transactions,
+      matches: failed.is_empty() && tries.iter().all(|trie| trie.matches),
+      failed,
+      tries,
+    };
+
+    TrieResult {
+      success: true,
+      result: Some(serde_json::to_string(&report).unwrap_or("".to_string())),
+    }
+  }
+
+  /// Root of `trie_key` and its records as canonical JSON.
+  fn snapshot(trie_key: &str) -> Result<([u8; 32], Vec<String>), String> {
+    let record = record::find(trie_key).unwrap();
+
+    let records = get_trie_results(trie_key, &record.db_path(), None)
+      .iter()
+      .filter_map(|val| record.decode_json(val).ok())
+      .map(|value| signed_message(&value))
+      .collect();
+
+    Ok((get_trie_root(trie_key)?, records))
+  }
+
+  /// The applied transactions with the time they were applied at, in the
+  /// order they were applied.
+  fn applied() -> Vec<(Transaction, u64)> {
+    let mut positions = HashMap::new();
+    for block in BlockHeader::all() {
+      for (i, hash) in block.transactions.iter().enumerate() {
+        positions.insert(hash.clone(), (block.height, i));
+      }
+    }
+
+    let mut log: Vec<(Transaction, u64)> = get_trie_results(&Transaction::trie_name(), &Transaction::db_path(), None)
+      .iter()
+      .filter_map(|val| Transaction::decode_record(val).ok())
+      .filter(|tx| tx.status == TX_APPLIED)
+      .map(|tx| {
+        let applied_at = TransactionReceipt::find(&tx.hash).map_or(tx.timestamp, |receipt| receipt.timestamp);
+        (tx, applied_at)
+      })
+      .collect();
+    log.sort_by_key(|(tx, applied_at)| (*applied_at, positions.get(&tx.hash).copied(), tx.timestamp, tx.nonce));
+    log
+  }
+}
+
+impl AsOf {
+  fn stored() -> AsOf {
+    let mut transfers: Vec<Ownership> = get_trie_results(&Ownership::trie_name(), &Ownership::db_path(), None)
+      .iter()
+      .filter_map(|val| Ownership::decode_record(val).ok())
+      .collect();
+    transfers.sort_by_key(|transfer| (transfer.timestamp, transfer.seq));
+
+    let grants = get_trie_results(&Grant::trie_name(), &Grant::db_path(), None)
+      .iter()
+      .filter_map(|val| Grant::decode_record(val).ok())
+      .map(|grant| (grant, None))
+      .collect();
+
+    AsOf { transfers, next: 0, grants }
+  }
+
+  /// Write the transfers made by `time` and the grants as they were then.
+  /// A grant active then is written without its expiry, so that it holds
+  /// whenever the replay runs, any other one revoked.
+  fn write(&mut self, time: u64) -> Result<(), TrieResult> {
+    let mut commit = StateCommit::new();
+    let mut changed = false;
+
+    let made = self.transfers[self.next..].iter().take_while(|transfer| transfer.timestamp <= time).count();
+    let mut contracts: HashMap<String, MetaContract> = HashMap::new();
+    for transfer in &self.transfers[self.next..self.next + made] {
+      // A transfer moves the replayed metacontract along, as `transfer_program`
+      // does, building on one staged by an earlier transfer in this commit.
+      let contract = contracts.remove(&transfer.program_id).or_else(|| MetaContract::find(&transfer.program_id));
+      match (contract, Policy::parse(&transfer.public_key)) {
+        (Some(mut contract), Ok(policy)) => {
+          transfer.stage(&mut contract, policy, &mut commit);
+          contracts.insert(transfer.program_id.clone(), contract);
+        },
+        _ => commit.put(&[transfer.clone()]),
+      }
+      changed = true;
+    }
+    self.next += made;
+
+    let mut grants = Vec::new();
+    for (grant, written) in self.grants.iter_mut() {
+      let as_of = match grant.updated_at <= time && grant.is_active(time) {
+        true => Grant { expires_at: 0, ..grant.clone() },
+        false => Grant { role: String::new(), ..grant.clone() },
+      };
+      if written.as_ref().map(|w| (&w.role, w.expires_at)) != Some((&as_of.role, as_of.expires_at)) {
+        *written = Some(as_of.clone());
+        grants.push(as_of);
+      }
+    }
+    if !grants.is_empty() {
+      commit.put(&grants);
+      changed = true;
+    }
+
+    match changed {
+      true => commit.commit(),
+      false => Ok(()),
+    }
+  }
+}
+
+#[test]
+fn test_replay() {
+  use std::{thread, time::Duration};
+
+  use crate::{apply::test_tx, auth::test_signature, db, metadata::Metadata};
+
+  db::scratch(|| {
+    let (owner, _) = test_signature(1, "");
+    let (grantee, _) = test_signature(2, "");
+    let (next_owner, _) = test_signature(3, "");
+    let tick = || thread::sleep(Duration::from_millis(2));
+
+    let apply = |tx: Transaction| assert_eq!(Apply::apply(tx).unwrap().status, TX_APPLIED);
+    apply(test_tx("h1", "register_metacontract", &owner, ""));
+
+    let mut grant = Grant {
+      program_id: "p".to_string(),
+      grantee: grantee.clone(),
+      role: "metadata".to_string(),
+      expires_at: 0,
+      seq: 1,
+      updated_at: now_ms(),
+    };
+    let mut commit = StateCommit::new();
+    commit.put(&[grant.clone()]);
+    commit.commit().unwrap();
+    tick();
+
+    apply(Transaction { timestamp: 2, ..test_tx("h2", "set_metadata", &grantee, r#"{"cid":"c1"}"#) });
+    tick();
+    apply(Transaction { timestamp: 3, data_key: "d2".into(), ..test_tx("h3", "set_metadata", &owner, r#"{"cid":"c2"}"#) });
+
+    // Since then the grant lapsed and the program moved to another key.
+    grant.expires_at = now_ms();
+    let transfer = Ownership {
+      program_id: "p".to_string(),
+      seq: 1,
+      public_key: next_owner.clone(),
+      previous_key: owner,
+      timestamp: now_ms() + 1,
+    };
+    let mut commit = StateCommit::new();
+    commit.put(&[grant]);
+    transfer.stage(&mut MetaContract::find("p").unwrap(), Policy::parse(&next_owner).unwrap(), &mut commit);
+    commit.commit().unwrap();
+    tick();
+    assert!(!Grant::allows("p", &grantee, &Metadata::trie_name()));
+
+    let result = Replay::from_genesis();
+    assert!(result.success);
+    let report: Value = serde_json::from_str(&result.result.unwrap()).unwrap();
+    assert_eq!(report["transactions"], 3);
+    assert_eq!(report["failed"], serde_json::json!([]));
+    assert_eq!(report["matches"], true);
+    assert_eq!(MetaContract::find("p").unwrap().public_key, next_owner);
+  });
+}
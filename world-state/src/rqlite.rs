@@ -1,32 +1,30 @@
-use crate::{utils::curl, appconfig::CONFIG};
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{utils::curl, appconfig::CONFIG, db, record};
 
 
 pub struct RQLite;
 
 impl RQLite {
   pub fn create_tables() {
-    let tx_table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", CONFIG.get::<String>("TX_KEY").unwrap());
-
-    let receipt_table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", CONFIG.get::<String>("TX_RECEIPT_KEY").unwrap());
-
-    let metacontract_table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", CONFIG.get::<String>("METACONTRACT_KEY").unwrap());
-
-    let metadata_table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", CONFIG.get::<String>("METADATA_KEY").unwrap());
-
-    let cron_table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", CONFIG.get::<String>("CRON_KEY").unwrap());
+    for record in record::all() {
+      let table = format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", record.trie_name());
+      Self::execute(table.as_str());
+    }
 
     let roots_table = "CREATE TABLE IF NOT EXISTS roots (root_key TEXT PRIMARY KEY UNIQUE, root_value TEXT NULL)";
 
-    Self::execute(tx_table.as_str());
-    Self::execute(receipt_table.as_str());
-    Self::execute(metacontract_table.as_str());
-    Self::execute(metadata_table.as_str());
-    Self::execute(cron_table.as_str());
     Self::execute(roots_table);
-  
   }
 
+  /// Nothing is mirrored while the dbs are redirected.
   pub fn execute(statement: &str) {
+    if db::redirected() {
+      return;
+    }
+
     let args = vec![
             "-s".to_string(),
             "-XPOST".to_string(),
@@ -34,7 +32,7 @@ impl RQLite {
             "-H".to_string(),
             "Content-Type: application/json".to_string(),
             "-d".to_string(),
-            format!(r#"["{}"]"#, statement),
+            serde_json::to_string(&[statement]).unwrap(),
         ];
     // println!("args: {:?}", args);
     curl(args);
@@ -50,7 +48,7 @@ impl RQLite {
             "-H".to_string(),
             "Content-Type: application/json".to_string(),
             "-d".to_string(),
-            format!(r#"["{}"]"#, statement),
+            serde_json::to_string(&[statement]).unwrap(),
         ];
 
     let result = curl(args);
@@ -59,4 +57,87 @@ impl RQLite {
 
     result
   }
-}
\ No newline at end of file
+
+  /// Run several statements in a single request, atomically on the RQLite side.
+  pub fn execute_batch(statements: &[String]) {
+    if statements.is_empty() {
+      return;
+    }
+
+    let args = vec![
+            "-s".to_string(),
+            "-XPOST".to_string(),
+            format!("{}?transaction", CONFIG.get::<String>("SQL_EXECUTE").unwrap()),
+            "-H".to_string(),
+            "Content-Type: application/json".to_string(),
+            "-d".to_string(),
+            serde_json::to_string(statements).unwrap(),
+        ];
+    curl(args);
+  }
+
+  /// `name` if it can be used as a table name as is: ASCII letters, digits
+  /// and `_`, not starting with a digit.
+  pub fn table(name: &str) -> Result<&str, String> {
+    match name.chars().next() {
+      Some(first) if !first.is_ascii_digit() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Ok(name),
+      _ => Err(format!("{:?} can't be used as an RQLite table name", name)),
+    }
+  }
+
+  /// `value` as an SQL string literal.
+  pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+  }
+
+  /// Query rows as column -> value maps, using the associative query endpoint.
+  /// Errs when RQLite can't be reached or rejects the statement, rather than
+  /// reading as no rows.
+  pub fn query_rows(statement: &str) -> Result<Vec<HashMap<String, Value>>, String> {
+    Self::rows(&Self::query(statement))
+  }
+
+  fn rows(response: &str) -> Result<Vec<HashMap<String, Value>>, String> {
+    let parsed: Value = serde_json::from_str(response).map_err(|_| format!("Unexpected RQLite response {:?}", response))?;
+
+    let result = &parsed["results"][0];
+    if let Some(error) = result["error"].as_str().or(parsed["error"].as_str()) {
+      return Err(format!("RQLite error: {}", error));
+    }
+    if !result.is_object() {
+      return Err(format!("Unexpected RQLite response {:?}", response));
+    }
+
+    Ok(result["rows"]
+      .as_array()
+      .map(|rows| {
+        rows
+          .iter()
+          .filter_map(|row| serde_json::from_value(row.clone()).ok())
+          .collect()
+      })
+      .unwrap_or_default())
+  }
+}
+
+#[test]
+fn test_sql_names() {
+  assert_eq!(RQLite::table("cron_run"), Ok("cron_run"));
+  for name in ["", "1tx", "tx; DROP TABLE roots", "tx'", "ac-l"] {
+    assert!(RQLite::table(name).is_err());
+  }
+
+  assert_eq!(RQLite::quote("tx"), "'tx'");
+  assert_eq!(RQLite::quote("a' OR '1'='1"), "'a'' OR ''1''=''1'");
+}
+
+#[test]
+fn test_rows() {
+  let rows = RQLite::rows(r#"{"results":[{"types":{"trie_key":"text"},"rows":[{"trie_key":"ab"}]}]}"#).unwrap();
+  assert_eq!(rows[0]["trie_key"], "ab");
+  assert!(RQLite::rows(r#"{"results":[{}]}"#).unwrap().is_empty());
+
+  for response in ["", "curl: (7) Failed to connect to localhost port 4001", r#"{"results":[{"error":"no such table: tx"}]}"#, r#"{"error":"unauthorized"}"#] {
+    assert!(RQLite::rows(response).is_err());
+  }
+}
@@ -0,0 +1,268 @@
+/// When a cron job runs, parsed from `Cron.schedule`.
+///
+/// - `"*/5 * * * *"`, `"0 */5 * * * *"`: 5 field (minute hour day month
+///   weekday) or 6 field (second first) cron expression, evaluated in UTC.
+///   `@yearly`, `@monthly`, `@weekly`, `@daily` and `@hourly` are accepted.
+/// - `"@every 90s"`: fixed interval, in `ms`, `s`, `m`, `h` or `d`, ms when
+///   the unit is left out.
+/// - `"@at 1700000000000"`, `"@at 2024-05-01T12:00:00Z"`: run once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+  Interval(u64),
+  Once(u64),
+  Cron(CronExpr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+  seconds: u64,
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  /// Day of month and weekday were both restricted, a day matches either.
+  either_day: bool,
+}
+
+/// Give up looking for a matching time this many years ahead (`0 0 30 2 *`).
+const SEARCH_YEARS: i64 = 5;
+
+impl Schedule {
+  pub fn parse(text: &str) -> Result<Schedule, String> {
+    let text = text.trim();
+
+    if let Some(interval) = text.strip_prefix("@every ") {
+      return match parse_duration(interval.trim()) {
+        Some(0) | None => Err(format!("Invalid interval {:?}", interval)),
+        Some(ms) => Ok(Schedule::Interval(ms)),
+      };
+    }
+
+    if let Some(at) = text.strip_prefix("@at ") {
+      let at = at.trim();
+      return at
+        .parse::<u64>()
+        .ok()
+        .or_else(|| parse_utc(at))
+        .map(Schedule::Once)
+        .ok_or(format!("Invalid timestamp {:?}", at));
+    }
+
+    let expression = match text {
+      "@yearly" | "@annually" => "0 0 1 1 *",
+      "@monthly" => "0 0 1 * *",
+      "@weekly" => "0 0 * * 0",
+      "@daily" | "@midnight" => "0 0 * * *",
+      "@hourly" => "0 * * * *",
+      _ => text,
+    };
+
+    CronExpr::parse(expression).map(Schedule::Cron)
+  }
+
+  /// First run strictly after `after`. `previous` is the last time the job was
+  /// due, intervals are counted from it so runs don't drift, 0 when it never ran.
+  pub fn next_run(&self, previous: u64, after: u64) -> Option<u64> {
+    match self {
+      Schedule::Interval(interval) => {
+        let anchor = if previous == 0 { after } else { previous };
+        let missed = after.saturating_sub(anchor) / interval;
+        Some(anchor + (missed + 1) * interval)
+      },
+      Schedule::Once(at) => Some(*at).filter(|at| *at > after),
+      Schedule::Cron(expr) => expr.next_after(after),
+    }
+  }
+}
+
+impl CronExpr {
+  pub fn parse(expression: &str) -> Result<CronExpr, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let fields = match fields.len() {
+      5 => [&["0"][..], &fields[..]].concat(),
+      6 => fields,
+      n => return Err(format!("Cron expression needs 5 or 6 fields, got {}", n)),
+    };
+
+    let mut weekdays = parse_field(fields[5], 0, 7, &WEEKDAYS)?;
+    if weekdays & (1 << 7) != 0 {
+      weekdays = (weekdays | 1) & !(1 << 7);
+    }
+
+    Ok(CronExpr {
+      seconds: parse_field(fields[0], 0, 59, &[])?,
+      minutes: parse_field(fields[1], 0, 59, &[])?,
+      hours: parse_field(fields[2], 0, 23, &[])?,
+      days: parse_field(fields[3], 1, 31, &[])?,
+      months: parse_field(fields[4], 1, 12, &MONTHS)?,
+      weekdays,
+      either_day: !fields[3].starts_with('*') && !fields[5].starts_with('*'),
+    })
+  }
+
+  /// First matching time strictly after `after`, both in ms.
+  pub fn next_after(&self, after: u64) -> Option<u64> {
+    let mut t = (after / 1000) as i64 + 1;
+    let limit = t + SEARCH_YEARS * 366 * 86400;
+
+    while t < limit {
+      let days = t.div_euclid(86400);
+      let (year, month, day) = civil_from_days(days);
+
+      if self.months & (1 << month) == 0 {
+        let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+        t = days_from_civil(year, month, 1) * 86400;
+        continue;
+      }
+
+      let weekday = (days + 4).rem_euclid(7);
+      let day_of_month = self.days & (1 << day) != 0;
+      let day_of_week = self.weekdays & (1 << weekday) != 0;
+      let day_matches = if self.either_day { day_of_month || day_of_week } else { day_of_month && day_of_week };
+      if !day_matches {
+        t = (days + 1) * 86400;
+        continue;
+      }
+
+      let seconds_of_day = t.rem_euclid(86400);
+      if self.hours & (1 << (seconds_of_day / 3600)) == 0 {
+        t = (t / 3600 + 1) * 3600;
+        continue;
+      }
+      if self.minutes & (1 << (seconds_of_day / 60 % 60)) == 0 {
+        t = (t / 60 + 1) * 60;
+        continue;
+      }
+      if self.seconds & (1 << (seconds_of_day % 60)) == 0 {
+        t += 1;
+        continue;
+      }
+
+      return Some(t as u64 * 1000);
+    }
+
+    None
+  }
+}
+
+const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// Bitmask of the values matched by a comma separated list of `*`, `n`,
+/// `a-b`, each optionally followed by `/step`. `names` are the values from
+/// `min` onwards.
+fn parse_field(field: &str, min: u64, max: u64, names: &[&str]) -> Result<u64, String> {
+  let value = |text: &str| -> Result<u64, String> {
+    let upper = text.to_uppercase();
+    let value = match names.iter().position(|name| *name == upper) {
+      Some(i) => min + i as u64,
+      None => text.parse::<u64>().map_err(|_| format!("Invalid cron value {:?}", text))?,
+    };
+    if value < min || value > max {
+      return Err(format!("Cron value {} is out of range {}-{}", value, min, max));
+    }
+    Ok(value)
+  };
+
+  let mut mask = 0u64;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u64>().ok().filter(|s| *s > 0).ok_or(format!("Invalid cron step {:?}", step))?),
+      None => (part, 1),
+    };
+
+    let (start, end) = match range.split_once('-') {
+      _ if range == "*" => (min, max),
+      Some((start, end)) => (value(start)?, value(end)?),
+      None if step > 1 => (value(range)?, max),
+      None => (value(range)?, value(range)?),
+    };
+    if start > end {
+      return Err(format!("Invalid cron range {:?}", range));
+    }
+
+    for v in (start..=end).step_by(step as usize) {
+      mask |= 1 << v;
+    }
+  }
+
+  Ok(mask)
+}
+
+fn parse_duration(text: &str) -> Option<u64> {
+  let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+  let (number, unit) = text.split_at(split);
+  let number = number.parse::<u64>().ok()?;
+
+  let factor = match unit {
+    "" | "ms" => 1,
+    "s" => 1000,
+    "m" => 60 * 1000,
+    "h" => 60 * 60 * 1000,
+    "d" => 24 * 60 * 60 * 1000,
+    _ => return None,
+  };
+  number.checked_mul(factor)
+}
+
+/// `YYYY-MM-DDTHH:MM:SS` with an optional trailing `Z`, in ms.
+fn parse_utc(text: &str) -> Option<u64> {
+  let text = text.strip_suffix('Z').unwrap_or(text);
+  let (date, time) = text.split_once('T')?;
+
+  let date: Vec<i64> = date.split('-').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+  let time: Vec<i64> = time.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+  if date.len() != 3 || time.len() != 3 || !(1..=12).contains(&date[1]) || !(1..=31).contains(&date[2]) {
+    return None;
+  }
+
+  let seconds = days_from_civil(date[0], date[1], date[2]) * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+  u64::try_from(seconds).ok().map(|s| s * 1000)
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` of a time in ms.
+pub fn format_utc(ms: u64) -> String {
+  let seconds = (ms / 1000) as i64;
+  let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+  let time = seconds.rem_euclid(86400);
+
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let year_of_era = year - era * 400;
+  let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+  let days = days + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days - era * 146097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+#[test]
+fn test_schedule() {
+  let new_year = 1704067200000; // 2024-01-01T00:00:00Z, a Monday
+  let next = |text: &str, after: u64| Schedule::parse(text).unwrap().next_run(0, after).map(format_utc);
+
+  assert_eq!(next("@weekly", new_year).unwrap(), "2024-01-07T00:00:00Z");
+  assert_eq!(next("*/15 * * * * *", new_year).unwrap(), "2024-01-01T00:00:15Z");
+  assert_eq!(next("0 0 13 * FRI", new_year).unwrap(), "2024-01-05T00:00:00Z");
+  assert_eq!(next("0 0 29 2 *", new_year + 60 * 86400000).unwrap(), "2028-02-29T00:00:00Z");
+  assert_eq!(next("@at 2024-01-02T00:00:00Z", new_year).unwrap(), "2024-01-02T00:00:00Z");
+  assert_eq!(next("0 0 30 2 *", new_year), None);
+  assert_eq!(Schedule::parse("@every 90s").unwrap().next_run(1000, 200000), Some(271000));
+  assert!(Schedule::parse("* * * *").is_err());
+}
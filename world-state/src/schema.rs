@@ -0,0 +1,309 @@
+use std::{collections::HashMap, env};
+
+use lazy_static::lazy_static;
+use rlp::{DecoderError, Rlp, RlpStream};
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Number, Value};
+
+use crate::{
+  appconfig::CONFIG,
+  get_trie_results,
+  ownership::Owner,
+  record::{self, insert_records, PreparedRecord, Record, RecordType},
+  rqlite::RQLite,
+  types::TrieResult,
+};
+
+const FIELD_TYPES: [&str; 3] = ["string", "u64", "bool"];
+
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone, PartialEq)]
+pub struct SchemaField {
+  pub name: String,
+  #[serde(rename = "type")]
+  pub kind: String,
+}
+
+/// A record type defined at runtime, either under `[[schemas]]` in
+/// `Config.toml` or with `register_schema`. Its records are JSON objects with
+/// exactly the declared fields, stored as an RLP list ordered by field name.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone, PartialEq)]
+pub struct Schema {
+  pub name: String,
+  pub primary_key: String,
+  pub fields: Vec<SchemaField>,
+}
+
+impl Record for Schema {
+  const CONFIG_PREFIX: &'static str = "SCHEMA";
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.name.as_bytes().to_vec()
+  }
+
+  /// Hold schemas written with `insert_trie` to the checks of
+  /// `register_schema`: valid, not redefining a registered schema, not named
+  /// after a built-in trie or the RQLite `roots` table, and with a database
+  /// of its own.
+  fn prepare(&mut self) -> Result<(), String> {
+    self.validate()?;
+
+    match SCHEMA_TYPES.iter().find(|s| s.schema.name == self.name) {
+      Some(s) if s.schema == *self => return Ok(()),
+      Some(_) => return Err(format!("Schema {} is already registered", self.name)),
+      None => (),
+    }
+    if record::REGISTRY.iter().any(|r| r.trie_name() == self.name) {
+      return Err(format!("{} is a built-in trie", self.name));
+    }
+    if self.name == "roots" {
+      return Err("roots is the RQLite table of the roots".to_string());
+    }
+
+    let record = SchemaRecord::new(self.clone());
+    let path_key = format!("{}_DB_PATH", record.prefix);
+    let db_path = record.db_path();
+    if CONFIG.get::<String>(&path_key).is_ok() {
+      return Err(format!("{} would share the database {} configured as {}", self.name, db_path, path_key));
+    }
+    if CONFIG.get::<String>("ROOT_DB_PATH").ok().as_ref() == Some(&db_path) || record::all().iter().any(|r| r.db_path() == db_path) {
+      return Err(format!("{} would share the database {} of another trie", self.name, db_path));
+    }
+    Ok(())
+  }
+}
+
+lazy_static! {
+  /// Schemas from `Config.toml` followed by the registered ones.
+  pub static ref SCHEMA_TYPES: Vec<SchemaRecord> = load_schemas();
+}
+
+fn load_schemas() -> Vec<SchemaRecord> {
+  let mut schemas: Vec<Schema> = CONFIG.get::<Vec<Schema>>("schemas").unwrap_or_default();
+
+  for val in get_trie_results(&Schema::trie_name(), &Schema::db_path(), None).iter() {
+    match Schema::decode_record(val) {
+      Ok(schema) if !schemas.iter().any(|s| s.name == schema.name) => schemas.push(schema),
+      Ok(_) => (),
+      Err(e) => eprintln!("Skipping undecodable schema: {}", e),
+    }
+  }
+
+  schemas
+    .into_iter()
+    .filter_map(|schema| match schema.validate() {
+      Ok(_) => Some(SchemaRecord::new(schema)),
+      Err(e) => {
+        eprintln!("Skipping schema {}: {}", schema.name, e);
+        None
+      },
+    })
+    .collect()
+}
+
+impl Schema {
+  /// `register_schema <schema json>`
+  pub fn register_schema() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let mut schema: Schema = match args.get(2).map(|arg| serde_json::from_str(arg)) {
+      Some(Ok(schema)) => schema,
+      Some(Err(e)) => return TrieResult { success: false, result: Some(format!("Error decoding schema: {}", e)) },
+      None => return TrieResult { success: false, result: Some("Missing schema".to_string()) },
+    };
+
+    if let Err(e) = schema.prepare() {
+      return TrieResult { success: false, result: Some(e) };
+    }
+
+    if SCHEMA_TYPES.iter().any(|s| s.schema == schema) {
+      return TrieResult { success: true, result: Some(args[2].clone()) };
+    }
+
+    if let Err(e) = insert_records(&[schema.clone()]) {
+      return e;
+    }
+    RQLite::execute(&format!("CREATE TABLE IF NOT EXISTS {} (trie_key TEXT PRIMARY KEY UNIQUE, trie_value TEXT NULL)", schema.name));
+
+    TrieResult { success: true, result: Some(serde_json::to_string(&schema).unwrap_or("".to_string())) }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+      return Err(format!("Schema name {:?} must be lowercase letters, digits or _", self.name));
+    }
+
+    for (i, field) in self.fields.iter().enumerate() {
+      if !FIELD_TYPES.contains(&field.kind.as_str()) {
+        return Err(format!("Field {} has unknown type {:?}, use one of {:?}", field.name, field.kind, FIELD_TYPES));
+      }
+      if self.fields[..i].iter().any(|f| f.name == field.name) {
+        return Err(format!("Field {} is declared twice", field.name));
+      }
+    }
+
+    match self.fields.iter().find(|f| f.name == self.primary_key) {
+      Some(field) if field.kind == "bool" => Err(format!("Primary key {} cannot be a bool", field.name)),
+      Some(_) => Ok(()),
+      None => Err(format!("Primary key {} is not a field", self.primary_key)),
+    }
+  }
+}
+
+/// `RecordType` of a runtime schema.
+pub struct SchemaRecord {
+  schema: Schema,
+  prefix: String,
+  /// Fields in storage order.
+  fields: Vec<SchemaField>,
+}
+
+impl SchemaRecord {
+  pub fn new(schema: Schema) -> Self {
+    let mut fields = schema.fields.clone();
+    fields.sort_by(|a, b| a.name.cmp(&b.name));
+
+    SchemaRecord {
+      prefix: schema.name.to_uppercase(),
+      schema,
+      fields,
+    }
+  }
+
+  fn decode_map(&self, data: &[u8]) -> Result<Map<String, Value>, DecoderError> {
+    let rlp = Rlp::new(data);
+    if rlp.item_count()? != self.fields.len() {
+      return Err(DecoderError::RlpIncorrectListLen);
+    }
+
+    let mut map = Map::new();
+    for (i, field) in self.fields.iter().enumerate() {
+      let value = match field.kind.as_str() {
+        "u64" => Value::Number(Number::from(rlp.val_at::<u64>(i)?)),
+        "bool" => Value::Bool(rlp.val_at::<bool>(i)?),
+        _ => Value::String(rlp.val_at::<String>(i)?),
+      };
+      map.insert(field.name.clone(), value);
+    }
+    Ok(map)
+  }
+}
+
+impl RecordType for SchemaRecord {
+  fn config_prefix(&self) -> &str {
+    &self.prefix
+  }
+
+  fn trie_name(&self) -> String {
+    self.schema.name.clone()
+  }
+
+  fn db_path(&self) -> String {
+    CONFIG
+      .get::<String>(&format!("{}_DB_PATH", self.prefix))
+      .unwrap_or(format!("./db/{}", self.schema.name))
+  }
+
+  fn prepare_json(&self, value: Value) -> Result<PreparedRecord, String> {
+    let object = match value {
+      Value::Object(object) => object,
+      _ => return Err(format!("Error decoding {}: expected an object", self.schema.name)),
+    };
+
+    if let Some(unknown) = object.keys().find(|k| !self.fields.iter().any(|f| &f.name == *k)) {
+      return Err(format!("Error decoding {}: unknown field `{}`", self.schema.name, unknown));
+    }
+
+    let mut stream = RlpStream::new_list(self.fields.len());
+    let mut key = Vec::new();
+
+    for field in self.fields.iter() {
+      let value = object
+        .get(&field.name)
+        .ok_or(format!("Error decoding {}: missing field `{}`", self.schema.name, field.name))?;
+      let invalid = || format!("Error decoding {}: field `{}` must be a {}", self.schema.name, field.name, field.kind);
+
+      let bytes = match (field.kind.as_str(), value) {
+        ("string", Value::String(s)) => {
+          stream.append(s);
+          s.as_bytes().to_vec()
+        },
+        ("u64", Value::Number(n)) => {
+          let n = n.as_u64().ok_or_else(invalid)?;
+          stream.append(&n);
+          n.to_string().into_bytes()
+        },
+        ("bool", Value::Bool(b)) => {
+          stream.append(b);
+          Vec::new()
+        },
+        _ => return Err(invalid()),
+      };
+
+      if field.name == self.schema.primary_key {
+        key = bytes;
+      }
+    }
+
+    Ok(PreparedRecord { replaces: key.clone(), key, value: stream.out().to_vec() })
+  }
+
+  fn decode_json(&self, data: &[u8]) -> Result<Value, DecoderError> {
+    self.decode_map(data).map(Value::Object)
+  }
+
+  fn decode_fields(&self, data: &[u8]) -> Result<HashMap<String, Value>, DecoderError> {
+    self.decode_map(data).map(|map| map.into_iter().collect())
+  }
+
+  /// Schema records are not versioned, the schema itself describes them.
+  fn reencode(&self, data: &[u8]) -> Result<Vec<u8>, DecoderError> {
+    self.decode_map(data).map(|_| data.to_vec())
+  }
+
+  fn has_revision(&self) -> bool {
+    false
+  }
+
+  fn revision(&self, data: &[u8]) -> Result<Option<String>, DecoderError> {
+    self.decode_map(data).map(|_| None)
+  }
+
+  fn owner(&self, data: &[u8]) -> Result<Option<Owner>, DecoderError> {
+    self.decode_map(data).map(|_| None)
+  }
+}
+
+#[test]
+fn test_schema_record() {
+  let schema: Schema = serde_json::from_str(r#"{"name":"settings","primary_key":"program_id","fields":[{"name":"program_id","type":"string"},{"name":"limit","type":"u64"},{"name":"enabled","type":"bool"}]}"#).unwrap();
+  let mut reordered = schema.clone();
+  reordered.fields.reverse();
+  assert!(schema.validate().is_ok());
+
+  let value: Value = serde_json::from_str(r#"{"program_id":"p1","limit":5,"enabled":true}"#).unwrap();
+  let (key, data) = SchemaRecord::new(schema).pair_from_json(value.clone()).unwrap();
+  let (_, reordered_data) = SchemaRecord::new(reordered.clone()).pair_from_json(value.clone()).unwrap();
+
+  assert_eq!(key, b"p1".to_vec());
+  assert_eq!(data, reordered_data);
+  assert_eq!(SchemaRecord::new(reordered).decode_json(&data).unwrap(), value);
+}
+
+#[test]
+fn test_schema_prepare() {
+  let schema = |name: &str, primary_key: &str| -> Schema {
+    serde_json::from_value(serde_json::json!({ "name": name, "primary_key": primary_key, "fields": [{ "name": "id", "type": "string" }] })).unwrap()
+  };
+
+  assert!(schema("settings_v2", "id").prepare().is_ok());
+  assert_eq!(schema("tx", "id").prepare(), Err("tx is a built-in trie".to_string()));
+  assert_eq!(schema("settings_v2", "key").prepare(), Err("Primary key key is not a field".to_string()));
+  assert!(schema("Settings", "id").prepare().is_err());
+
+  // Names whose database or RQLite table is already taken.
+  assert_eq!(schema("roots", "id").prepare(), Err("roots is the RQLite table of the roots".to_string()));
+  for name in ["root", "tx_receipt", "tx_test"] {
+    assert!(schema(name, "id").prepare().unwrap_err().contains("would share the database"));
+  }
+}
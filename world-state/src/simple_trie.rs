@@ -16,14 +16,14 @@ pub struct SimpleTrie<'a> {
 }
 
 impl<'a> SimpleTrie<'a> {
-	/// Create a new instance of `Self`.
-	pub fn new(db: KVDB, overlay: &'a mut HashMap<Vec<u8>, Option<Vec<u8>>>) -> Self {
+	/// Create a new instance of `Self` for a codec whose empty node is `null_node_data`.
+	pub fn new(db: KVDB, overlay: &'a mut HashMap<Vec<u8>, Option<Vec<u8>>>, null_node_data: &[u8]) -> Self {
 
     SimpleTrie {
       db,
       overlay,
-      hashed_null_node: KeccakHasher::hash(&[0u8]),
-      null_node_data: [0u8][..].into()
+      hashed_null_node: KeccakHasher::hash(null_node_data),
+      null_node_data: null_node_data.into()
     }
 	}
 }
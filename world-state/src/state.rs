@@ -0,0 +1,120 @@
+use keccak_hasher::KeccakHasher;
+use hash_db::Hasher;
+use rlp_derive::{RlpEncodable, RlpDecodable};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::{commit_roots, get_trie_results, get_trie_root, layout::LayoutKind, record::Record, stage_trie_db, types::TrieResult};
+
+/// The root of a trie. Stored in the state trie, whose root commits to every
+/// other trie, keyed by the trie name.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone, PartialEq)]
+pub struct TrieRoot {
+  pub trie_key: String,
+  pub root: String,
+}
+
+impl Record for TrieRoot {
+  const CONFIG_PREFIX: &'static str = "STATE";
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.trie_key.as_bytes().to_vec()
+  }
+
+  /// Roots are only written by `commit_roots`.
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("The state trie changes only with the tries it holds".to_string())
+  }
+}
+
+impl TrieRoot {
+  /// `get_state_root`
+  pub fn get_state_root() -> TrieResult {
+    let tries: Vec<TrieRoot> = get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .collect();
+
+    match get_trie_root(&Self::trie_name()) {
+      Ok(root) => TrieResult { success: true, result: Some(json!({ "root": hex::encode(root), "tries": tries }).to_string()) },
+      Err(e) => TrieResult { success: false, result: Some(e) },
+    }
+  }
+}
+
+/// A trie whose new nodes `stage_trie_db` wrote but whose new root isn't
+/// current yet. `obsolete` are the nodes only the previous root references,
+/// which `commit_roots` deletes after making `root` current.
+pub struct StagedRoot {
+  pub trie_key: String,
+  pub db_path: String,
+  pub layout: LayoutKind,
+  /// The root the new nodes were built on, which must still be current.
+  pub base: <KeccakHasher as Hasher>::Out,
+  pub root: <KeccakHasher as Hasher>::Out,
+  pub obsolete: Vec<Vec<u8>>,
+  /// The nodes written for `root`, which an earlier staged root of the same
+  /// trie may have made obsolete, see `defer_commits`.
+  pub written: Vec<Vec<u8>>,
+}
+
+/// Records of several tries written together. The new nodes of every trie
+/// are written first, next to the current ones, and the new roots in a
+/// single write of the root db, so either all of the records become visible
+/// or none do. Nodes the old roots alone referenced are deleted last.
+#[derive(Default)]
+pub struct StateCommit {
+  tries: Vec<(String, String, Vec<(Vec<u8>, Vec<u8>)>)>,
+}
+
+impl StateCommit {
+  pub fn new() -> StateCommit {
+    StateCommit::default()
+  }
+
+  pub fn put<T: Record>(&mut self, records: &[T]) {
+    let (trie_name, db_path) = (T::trie_name(), T::db_path());
+    let pairs = records.iter().map(|record| (record.primary_key(), record.encode_record()));
+
+    match self.tries.iter_mut().find(|(name, ..)| *name == trie_name) {
+      Some((_, _, staged)) => staged.extend(pairs),
+      None => self.tries.push((trie_name, db_path, pairs.collect())),
+    }
+  }
+
+  pub fn commit(self) -> Result<(), TrieResult> {
+    commit_roots(self.stage().map_err(|e| TrieResult { success: false, result: Some(e) })?)
+  }
+
+  /// Write the new nodes of every trie and return their new roots, which
+  /// only become current with `commit_roots`. Nothing is deleted yet.
+  pub fn stage(self) -> Result<Vec<StagedRoot>, String> {
+    self
+      .tries
+      .iter()
+      .filter(|(_, _, pairs)| !pairs.is_empty())
+      .map(|(trie_name, db_path, pairs)| stage_trie_db(trie_name, db_path, pairs))
+      .collect()
+  }
+}
+
+#[test]
+fn test_commit_conflict() {
+  use crate::{account::Account, db, record::insert_records};
+
+  db::scratch(|| {
+    let account = |nonce| Account { public_key: "key".to_string(), nonce };
+
+    let mut commit = StateCommit::new();
+    commit.put(&[account(1)]);
+    let staged = commit.stage().unwrap();
+
+    insert_records(&[account(2)]).unwrap();
+    let root = get_trie_root(&Account::trie_name());
+
+    let conflict = commit_roots(staged).unwrap_err();
+    assert_eq!(conflict.code().as_deref(), Some("conflict"));
+    assert_eq!(get_trie_root(&Account::trie_name()), root);
+    assert_eq!(Account::find("key").unwrap().nonce, 2);
+  });
+}
@@ -1,15 +1,32 @@
-use std::{env, collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use std::env;
 
-use rlp::{encode, Decodable, Rlp};
+use rlp::{Decodable, DecoderError, Rlp};
 use rlp_derive::{RlpEncodable, RlpDecodable};
 use serde::{Serialize, Deserialize};
-use serde_json::{Value, Number};
+use serde_json::{json, Value};
 
 use crate::{
+  account::Account,
+  apply::Apply,
+  appconfig::CONFIG,
+  auth,
+  db::WriteLock,
+  genesis::Genesis,
   types::TrieResult, 
-  node_codec::ExtensionLayout, 
-  build_trie_db, 
-  get_trie_results, appconfig::CONFIG, transaction_receipt::TransactionReceipt};
+  record::{field_or_default, Record},
+  schedule::format_utc,
+  state::StateCommit,
+  utils::{flag_value, now_ms},
+  get_trie_results, precondition, transaction_receipt::TransactionReceipt};
+
+/// Waiting to be processed, see `get_pending_tx`.
+pub const TX_PENDING: u64 = 0;
+/// Applied by its handler, see `Apply`.
+pub const TX_APPLIED: u64 = 1;
+/// Rejected by its handler, the receipt says why.
+pub const TX_FAILED: u64 = 2;
+/// Not processed before its `expires_at`, set by `expire_pending`.
+pub const TX_EXPIRED: u64 = 3;
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
 pub struct Transaction {
@@ -27,6 +44,18 @@ pub struct Transaction {
     pub version: String,
     pub mcdata: String,
     pub status: u64,
+    /// One more than the last nonce of the key that signed it, 0 for
+    /// transactions stored before nonces were required.
+    #[serde(default)]
+    pub nonce: u64,
+    /// When the transaction expires if still pending in ms, 0 for never.
+    #[serde(default)]
+    pub expires_at: u64,
+    /// The key `insert_tx` verified the signatures for, which `Apply`
+    /// authorises the writes against. Empty for transactions stored before
+    /// signers were recorded.
+    #[serde(default)]
+    pub signer: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,38 +65,133 @@ pub struct TrieTransaction {
 }
 
 impl Transaction {
+  /// `insert_tx <json> [--signature <sig>] [--signatures <json>] [--replace-pending]`
+  ///
+  /// Submitting a stored transaction again returns it with its receipt. A
+  /// different transaction under a stored hash is a conflict, unless the
+  /// stored one is pending and `--replace-pending` is given; the replacement
+  /// keeps its `public_key`, signer and `nonce`.
+  ///
+  /// Every transaction needs signatures of `transaction`: meeting the
+  /// owner's policy or by a grantee for a program with a metacontract, by
+  /// its `public_key` otherwise. Unnamed signatures are by its `public_key`.
+  /// The signatures cover `nonce` and `chain_id`, which must be the next
+  /// nonce of the key they were verified for and, when `CHAIN_ID` is
+  /// configured, this chain. `timestamp` must be within `TX_MAX_SKEW_MS` of
+  /// now when that is configured. The transaction is stored pending, with
+  /// its nonce in one commit; receipts are only written by applying or
+  /// expiring it, so a request giving one is rejected.
   pub fn insert_tx() -> TrieResult {
-    // println!("insert tx");
     let args: Vec<String> = env::args().collect();
+
+    Self::insert(&args, now_ms())
+  }
+
+  fn insert(args: &[String], now: u64) -> TrieResult {
     let mut success = false;
     let mut result = None;
   
-    let serde_tx: Result<TrieTransaction, _> = serde_json::from_str(args[2].clone().as_str());
+    let serde_tx: Result<(TrieTransaction, Value), _> = serde_json::from_str::<Value>(args.get(2).map_or("", String::as_str))
+      .and_then(|value| Ok((serde_json::from_value(value.clone())?, value)));
   
     match serde_tx {
-      Ok(tx) => {
-        let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
-          (tx.transaction.hash.as_bytes().to_vec(), encode(&tx.transaction).to_vec()),
-        ];
-        // println!("tries: {:?}", tx);
-        build_trie_db::<ExtensionLayout>(
-          &CONFIG.get::<String>("TX_KEY").unwrap(), 
-          &CONFIG.get::<String>("TX_DB_PATH").unwrap(), 
-          &pairs
-        );
-
+      Ok((mut tx, value)) => {
         if tx.receipt.is_some() {
-          let receipt = tx.receipt.unwrap();
-          let receipt_pairs = vec![
-            (receipt.hash.as_bytes().to_vec(), encode(&receipt).to_vec()),
-          ];
-
-          build_trie_db::<ExtensionLayout>(
-            &CONFIG.get::<String>("TX_RECEIPT_KEY").unwrap(), 
-            &CONFIG.get::<String>("TX_RECEIPT_DB_PATH").unwrap(), 
-            &receipt_pairs
+          return TrieResult::error(
+            "receipt_not_allowed",
+            "Receipts are only written by applying or expiring a transaction".to_string(),
+            json!({ "hash": tx.transaction.hash }),
           );
         }
+        tx.transaction.status = TX_PENDING;
+
+        let _lock = WriteLock::acquire();
+
+        let existing = Transaction::find(&tx.transaction.hash);
+
+        if let Some(existing) = &existing {
+          if existing.same_submission(&tx.transaction) {
+            let duplicate = json!({
+              "transaction": existing,
+              "receipt": TransactionReceipt::find(&existing.hash),
+              "duplicate": true,
+            });
+            return TrieResult { success: true, result: Some(duplicate.to_string()) };
+          }
+
+          let replace_pending = args.iter().any(|arg| arg == "--replace-pending");
+          if !replace_pending || existing.status != TX_PENDING {
+            let message = if replace_pending {
+              format!("Transaction {} is no longer pending", existing.hash)
+            } else {
+              format!("Transaction {} already exists with different content", existing.hash)
+            };
+            return TrieResult::error("conflict", message, json!({ "hash": existing.hash, "current_status": existing.status }));
+          }
+        }
+
+        let authorized = auth::signatures_from_args(args, "").and_then(|signed| auth::authorize_program(
+          &Transaction::trie_name(),
+          &tx.transaction.program_id,
+          &value["transaction"],
+          &tx.transaction.public_key,
+          &signed,
+        ));
+        let signer = match authorized {
+          Ok(signer) => signer,
+          Err(e) => return e,
+        };
+
+        let chain_id = CONFIG.get::<String>("CHAIN_ID").ok().or_else(|| Genesis::find().map(|genesis| genesis.chain_id));
+        if let Some(chain_id) = chain_id {
+          if tx.transaction.chain_id != chain_id {
+            return TrieResult::error(
+              "wrong_chain",
+              format!("Transaction is for chain {:?}, not {:?}", tx.transaction.chain_id, chain_id),
+              json!({ "chain_id": tx.transaction.chain_id, "expected_chain_id": chain_id }),
+            );
+          }
+        }
+
+        if let Err(e) = tx.transaction.check_time(now, CONFIG.get::<u64>("TX_MAX_SKEW_MS").ok()) {
+          return e;
+        }
+
+        let account = match &existing {
+          Some(existing) if existing.nonce != 0 => {
+            if existing.public_key != tx.transaction.public_key
+              || existing.nonce != tx.transaction.nonce
+              || (!existing.signer.is_empty() && existing.signer != signer)
+            {
+              return TrieResult::error(
+                "conflict",
+                format!("A replacement of {} must keep its public key, signer and nonce", existing.hash),
+                json!({
+                  "hash": existing.hash,
+                  "public_key": existing.public_key,
+                  "signer": existing.signer,
+                  "expected_nonce": existing.nonce,
+                }),
+              );
+            }
+            None
+          },
+          _ => match Account::advance(&signer, tx.transaction.nonce) {
+            Ok(account) => Some(account),
+            Err(e) => return e,
+          },
+        };
+
+        tx.transaction.signer = signer;
+
+        let mut commit = StateCommit::new();
+        commit.put(&[tx.transaction.clone()]);
+        if let Some(account) = account {
+          commit.put(&[account]);
+        }
+        if let Err(e) = commit.commit() {
+          return e;
+        }
   
         success = true;
         result = Some(serde_json::to_string(&tx.transaction).unwrap_or("".to_string()));
@@ -87,15 +211,15 @@ impl Transaction {
     let mut success = false;
     let mut result = None;
   
-    let trie_results = get_trie_results(&CONFIG.get::<String>("TX_KEY").unwrap(), &CONFIG.get::<String>("TX_DB_PATH").unwrap(), None);
+    let trie_results = get_trie_results(&Transaction::trie_name(), &Transaction::db_path(), None);
   
     let mut new_results = Vec::new();
     for val in trie_results.iter() {
-      let dec_tx = Transaction::decode(&Rlp::new(&val));
+      let dec_tx = Transaction::decode_record(val);
   
       match dec_tx {
         Ok(tx) => {
-          if tx.status == 0 {
+          if tx.status == TX_PENDING && !tx.expired(now_ms()) {
             new_results.push(tx);
           }
         },
@@ -112,97 +236,339 @@ impl Transaction {
     }
   }
 
+  /// `update_tx_status <hash> <status> [--expected-value-hash <hash>]`
+  ///
+  /// Statuses only change from pending: to `TX_APPLIED` by applying the
+  /// transaction, which may leave it `TX_FAILED`, or to `TX_EXPIRED` once it
+  /// is past its `expires_at`.
   pub fn update_tx_status() -> TrieResult {
     let args: Vec<String> = env::args().collect();
-    let mut success = false;
-    let mut result = None;
 
-    let trie_key = args[2].clone();
-    let status = args[3].clone().parse::<u64>().unwrap();
+    Self::change_status(&args, now_ms())
+  }
 
-    let trie_results = get_trie_results(
-      &CONFIG.get::<String>("TX_KEY").unwrap(), 
-      &CONFIG.get::<String>("TX_DB_PATH").unwrap(), 
-      Some(trie_key),
-    );
+  fn change_status(args: &[String], now: u64) -> TrieResult {
+    let (hash, status) = match (args.get(2), args.get(3).map(|status| status.parse::<u64>())) {
+      (Some(hash), Some(Ok(status))) => (hash, status),
+      _ => return TrieResult { success: false, result: Some("Usage: update_tx_status <hash> <status>".to_string()) },
+    };
 
-    if trie_results.len() > 0 {
-      if let Some(val) = trie_results.get(0) {
-        if let Ok(mut dec_tx) = Transaction::decode(&Rlp::new(&val)) {
-          dec_tx.status = status;
+    let _lock = WriteLock::acquire();
 
-          let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
-            (dec_tx.hash.as_bytes().to_vec(), encode(&dec_tx).to_vec()),
-          ];
+    let tx = match Self::find(hash) {
+      Some(tx) => tx,
+      None => return TrieResult { success: false, result: Some("Record not found".to_string()) },
+    };
 
-          build_trie_db::<ExtensionLayout>(
-            &CONFIG.get::<String>("TX_KEY").unwrap(), 
-            &CONFIG.get::<String>("TX_DB_PATH").unwrap(), 
-            &pairs
-          );
+    if let Err(e) = precondition::check_args(args, &Self::trie_name(), &tx.primary_key()) {
+      return e;
+    }
 
-          let mut error_text = "".to_string();
-  
-          if args.len() > 4 {
-            error_text = args[4].clone();
-          }
+    if tx.status != TX_PENDING {
+      return TrieResult::error(
+        "conflict",
+        format!("Transaction {} is no longer pending", tx.hash),
+        json!({ "hash": tx.hash, "current_status": tx.status }),
+      );
+    }
 
-          let now = SystemTime::now();
-          let timestamp = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-
-          let receipt = TransactionReceipt {
-            hash: dec_tx.hash.clone(),
-            program_id: dec_tx.program_id.clone(),
-            status,
-            timestamp: timestamp.as_millis() as u64,
-            error_text,
-            data: dec_tx.data.clone(),
-          };
-
-          let receipt_pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
-            (dec_tx.hash.as_bytes().to_vec(), encode(&receipt).to_vec()),
-          ];
-
-          build_trie_db::<ExtensionLayout>(
-            &CONFIG.get::<String>("TX_RECEIPT_KEY").unwrap(), 
-            &CONFIG.get::<String>("TX_RECEIPT_DB_PATH").unwrap(), 
-            &receipt_pairs
-          );
+    let updated = match status {
+      TX_APPLIED if !tx.expired(now) => Apply::apply(tx).map(|receipt| receipt.hash),
+      TX_EXPIRED if tx.expired(now) => {
+        let hash = tx.hash.clone();
+        let (tx, receipt) = tx.expire(now);
 
-          success = true;
-          result = Some(serde_json::to_string(&dec_tx).unwrap_or("".to_string()));
-        }
-      }
-    } else {
-      result = Some("Record not found".to_string());
+        let mut commit = StateCommit::new();
+        commit.put(&[tx]);
+        commit.put(&[receipt]);
+        commit.commit().map(|_| hash)
+      },
+      TX_APPLIED | TX_EXPIRED => Err(TrieResult::error(
+        if status == TX_APPLIED { "expired" } else { "not_expired" },
+        format!("Transaction {} expires at {}", tx.hash, format_utc(tx.expires_at)),
+        json!({ "hash": tx.hash, "expires_at": tx.expires_at, "now": now }),
+      )),
+      _ => Err(TrieResult::error(
+        "invalid_status",
+        format!("Status {} is only set by applying or expiring a transaction", status),
+        json!({ "hash": tx.hash, "status": status }),
+      )),
+    };
+
+    match updated {
+      Ok(hash) => TrieResult {
+        success: true,
+        result: Some(serde_json::to_string(&Self::find(&hash)).unwrap_or("".to_string())),
+      },
+      Err(e) => e,
     }
+  }
 
-    TrieResult { 
-      success, 
-      result, 
+  /// `expire_pending [--now <ms>]`
+  ///
+  /// Moves pending transactions past their `expires_at` to `TX_EXPIRED`,
+  /// each with a receipt saying when it expired.
+  pub fn expire_pending() -> TrieResult {
+    let args: Vec<String> = env::args().collect();
+
+    let now = match flag_value(&args, "--now").map(|now| now.parse::<u64>()) {
+      Some(Ok(now)) => now,
+      Some(Err(_)) => return TrieResult { success: false, result: Some("--now expects milliseconds".to_string()) },
+      None => now_ms(),
+    };
+
+    match Self::expire_due(now) {
+      Ok(expired) => TrieResult {
+        success: true,
+        result: Some(serde_json::to_string(&expired).unwrap_or("".to_string())),
+      },
+      Err(e) => e,
     }
   }
 
+  fn expire_due(now: u64) -> Result<Vec<Transaction>, TrieResult> {
+    let _lock = WriteLock::acquire();
+
+    let (expired, receipts): (Vec<Transaction>, Vec<TransactionReceipt>) = get_trie_results(&Self::trie_name(), &Self::db_path(), None)
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .filter(|tx| tx.status == TX_PENDING && tx.expired(now))
+      .map(|tx| tx.expire(now))
+      .unzip();
+
+    if !expired.is_empty() {
+      let mut commit = StateCommit::new();
+      commit.put(&expired);
+      commit.put(&receipts);
+      commit.commit()?;
+    }
+
+    Ok(expired)
+  }
+
+  /// The transaction expired at `now` and its receipt.
+  fn expire(self, now: u64) -> (Transaction, TransactionReceipt) {
+    let receipt = TransactionReceipt {
+      hash: self.hash.clone(),
+      program_id: self.program_id.clone(),
+      status: TX_EXPIRED,
+      timestamp: now,
+      error_text: format!("Expired at {} before it was processed", format_utc(self.expires_at)),
+      data: self.data.clone(),
+    };
+
+    (Transaction { status: TX_EXPIRED, ..self }, receipt)
+  }
+
+  pub fn expired(&self, now: u64) -> bool {
+    self.expires_at != 0 && self.expires_at <= now
+  }
+
+  /// Reject a transaction that already expired or whose `timestamp` is
+  /// further than `max_skew`, the configured `TX_MAX_SKEW_MS`, from `now`.
+  fn check_time(&self, now: u64, max_skew: Option<u64>) -> Result<(), TrieResult> {
+    if self.expired(now) {
+      return Err(TrieResult::error(
+        "expired",
+        format!("Transaction expired at {}", format_utc(self.expires_at)),
+        json!({ "expires_at": self.expires_at, "now": now }),
+      ));
+    }
+
+    match max_skew {
+      Some(max_skew) if self.timestamp.abs_diff(now) > max_skew => Err(TrieResult::error(
+        "timestamp_out_of_window",
+        format!("Timestamp {} is more than {} ms from now", self.timestamp, max_skew),
+        json!({ "timestamp": self.timestamp, "now": now, "max_skew": max_skew }),
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn find(hash: &str) -> Option<Transaction> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(hash.to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|tx| tx.hash == hash)
+  }
+
+  /// Whether `other` is this transaction submitted again, `status` and
+  /// `signer` being ours to set after it was stored.
+  fn same_submission(&self, other: &Transaction) -> bool {
+    Transaction { status: other.status, signer: other.signer.clone(), ..self.clone() }.encode_record() == other.encode_record()
+  }
+
 }
 
-impl From<Transaction> for HashMap<String, Value> {
-  fn from(tx: Transaction) -> Self {
-    let mut map = HashMap::new();
-    map.insert("hash".to_string(), Value::String(tx.hash));
-    map.insert("method".to_string(), Value::String(tx.method));
-    map.insert("program_id".to_string(), Value::String(tx.program_id));
-    // map.insert("data_key".to_string(), Value::String(tx.data_key));
-    map.insert("data".to_string(), Value::String(tx.data));
-    map.insert("public_key".to_string(), Value::String(tx.public_key));
-    map.insert("alias".to_string(), Value::String(tx.alias));
-    map.insert("timestamp".to_string(), Value::Number(Number::from(tx.timestamp)));
-    map.insert("chain_id".to_string(), Value::String(tx.chain_id));
-    map.insert("token_address".to_string(), Value::String(tx.token_address));
-    map.insert("token_id".to_string(), Value::String(tx.token_id));
-    map.insert("version".to_string(), Value::String(tx.version));
-    map.insert("mcdata".to_string(), Value::String(tx.mcdata));
-    map.insert("status".to_string(), Value::Number(Number::from(tx.status)));
-
-    map
+impl Record for Transaction {
+  const CONFIG_PREFIX: &'static str = "TX";
+  const VERSION: u64 = 4;
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.hash.as_bytes().to_vec()
+  }
+
+  /// Transactions are only stored by `insert_tx`, which verifies them.
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Transactions are only stored by insert_tx".to_string())
+  }
+
+  fn decode_version(version: u64, rlp: &Rlp) -> Result<Self, DecoderError> {
+    match version {
+      0..=3 => Ok(Transaction {
+        hash: rlp.val_at(0)?,
+        method: rlp.val_at(1)?,
+        program_id: rlp.val_at(2)?,
+        data_key: rlp.val_at(3)?,
+        data: rlp.val_at(4)?,
+        public_key: rlp.val_at(5)?,
+        alias: rlp.val_at(6)?,
+        timestamp: rlp.val_at(7)?,
+        chain_id: rlp.val_at(8)?,
+        token_address: rlp.val_at(9)?,
+        token_id: rlp.val_at(10)?,
+        version: rlp.val_at(11)?,
+        mcdata: rlp.val_at(12)?,
+        status: rlp.val_at(13)?,
+        nonce: field_or_default(rlp, 14)?,
+        expires_at: field_or_default(rlp, 15)?,
+        signer: field_or_default(rlp, 16)?,
+      }),
+      Self::VERSION => Self::decode(rlp),
+      _ => Err(DecoderError::Custom("unsupported record version")),
+    }
   }
-}
\ No newline at end of file
+}
+#[test]
+fn test_check_time() {
+  use crate::{apply::test_tx, auth::test_signature};
+
+  let (signer, _) = test_signature(1, "");
+  let now = now_ms();
+  let max_skew = 300_000;
+  let at = |timestamp: u64, expires_at: u64| Transaction { timestamp, expires_at, ..test_tx("h1", "set_metadata", &signer, "c1") };
+  let code = |tx: Transaction| tx.check_time(now, Some(max_skew)).unwrap_err().code();
+
+  assert!(at(now, 0).check_time(now, Some(max_skew)).is_ok());
+  assert!(at(now - max_skew, 0).check_time(now, Some(max_skew)).is_ok());
+  assert!(at(now + max_skew, now + 1).check_time(now, Some(max_skew)).is_ok());
+  assert_eq!(code(at(now + max_skew + 1, 0)).as_deref(), Some("timestamp_out_of_window"));
+  assert_eq!(code(at(now - max_skew - 1, 0)).as_deref(), Some("timestamp_out_of_window"));
+  assert_eq!(code(at(now, now)).as_deref(), Some("expired"));
+
+  // Without a configured window any timestamp goes.
+  assert!(at(1, 0).check_time(now, None).is_ok());
+}
+
+#[test]
+fn test_tx_status() {
+  use crate::{apply::test_tx, auth::test_signature, db};
+
+  db::scratch(|| {
+    let (signer, _) = test_signature(1, "");
+    let tx = |hash: &str, expires_at: u64| Transaction { expires_at, ..test_tx(hash, "set_metadata", &signer, r#"{"cid":"c1","loose":1}"#) };
+
+    let mut commit = StateCommit::new();
+    commit.put(&[tx("h1", 100), tx("h2", 0), tx("h3", 100)]);
+    commit.commit().unwrap();
+
+    let change = |hash: &str, status: u64, now: u64| {
+      let args: Vec<String> = vec!["world-state".into(), "update_tx_status".into(), hash.into(), status.to_string()];
+      Transaction::change_status(&args, now)
+    };
+    let status = |hash: &str| Transaction::find(hash).unwrap().status;
+
+    for (hash, to, code) in [("h2", TX_PENDING, "invalid_status"), ("h2", TX_FAILED, "invalid_status"), ("h2", TX_EXPIRED, "not_expired"), ("h1", TX_APPLIED, "expired")] {
+      assert_eq!(change(hash, to, 200).code().as_deref(), Some(code));
+    }
+    assert_eq!((status("h1"), status("h2")), (TX_PENDING, TX_PENDING));
+
+    // Nothing expires before its time, then each expires once.
+    assert!(Transaction::expire_due(50).unwrap().is_empty());
+    assert!(change("h3", TX_EXPIRED, 200).success);
+    let expired: Vec<String> = Transaction::expire_due(200).unwrap().into_iter().map(|tx| tx.hash).collect();
+    assert_eq!(expired, vec!["h1".to_string()]);
+    assert!(Transaction::expire_due(300).unwrap().is_empty());
+    assert_eq!(status("h1"), TX_EXPIRED);
+    assert_eq!(TransactionReceipt::find("h3").unwrap().status, TX_EXPIRED);
+
+    // An expired or applied transaction can't be made pending again.
+    assert_eq!(change("h1", TX_PENDING, 200).code().as_deref(), Some("conflict"));
+    assert!(change("h2", TX_APPLIED, 200).success);
+    assert_eq!(status("h2"), TX_APPLIED);
+    assert_eq!(change("h2", TX_PENDING, 200).code().as_deref(), Some("conflict"));
+  });
+}
+
+#[cfg(test)]
+fn insert_args(tx: &Transaction, seed: u8, flags: &[&str]) -> Vec<String> {
+  let value = serde_json::to_value(tx).unwrap();
+  let (_, signature) = auth::test_signature(seed, &auth::signed_message(&value));
+
+  let mut args: Vec<String> = vec!["world-state".into(), "insert_tx".into(), json!({ "transaction": value }).to_string(), "--signature".into(), signature];
+  args.extend(flags.iter().map(|flag| flag.to_string()));
+  args
+}
+
+#[test]
+fn test_insert_tx() {
+  use crate::{apply::test_tx, auth::test_signature, db};
+
+  db::scratch(|| {
+    let (signer, _) = test_signature(1, "");
+    let (other, _) = test_signature(2, "");
+    let now = now_ms();
+    let tx = |hash: &str, nonce: u64, data: &str| Transaction { nonce, timestamp: now, ..test_tx(hash, "set_metadata", &signer, data) };
+    let insert = |tx: &Transaction, seed: u8, flags: &[&str]| Transaction::insert(&insert_args(tx, seed, flags), now);
+    let code = |result: TrieResult| result.code();
+
+    // Whatever status it names, a transaction is stored pending.
+    let first = Transaction { status: TX_APPLIED, signer: String::new(), ..tx("h1", 1, "c1") };
+    assert!(insert(&first, 1, &[]).success);
+    let stored = Transaction::find("h1").unwrap();
+    assert_eq!((stored.status, stored.signer.as_str()), (TX_PENDING, signer.as_str()));
+
+    let result = insert(&first, 1, &[]);
+    assert!(result.success);
+    assert_eq!(serde_json::from_str::<Value>(&result.result.unwrap()).unwrap()["duplicate"], true);
+    assert_eq!(code(insert(&tx("h1", 1, "c2"), 1, &[])).as_deref(), Some("conflict"));
+
+    for (nonce, expected) in [(0, "nonce_required"), (1, "nonce_replayed"), (3, "nonce_gap")] {
+      assert_eq!(code(insert(&tx("h2", nonce, "c1"), 1, &[])).as_deref(), Some(expected));
+    }
+    assert!(Transaction::find("h2").is_none());
+
+    // A replacement keeps the key, signer and nonce of the pending one.
+    assert_eq!(code(insert(&tx("h1", 2, "c2"), 1, &["--replace-pending"])).as_deref(), Some("conflict"));
+    let by_other = Transaction { public_key: other.clone(), ..tx("h1", 1, "c2") };
+    assert_eq!(code(insert(&by_other, 2, &["--replace-pending"])).as_deref(), Some("conflict"));
+    assert!(insert(&tx("h1", 1, "c2"), 1, &["--replace-pending"]).success);
+    assert_eq!(Transaction::find("h1").unwrap().data, "c2");
+
+    let mut commit = StateCommit::new();
+    commit.put(&[Transaction { status: TX_APPLIED, ..Transaction::find("h1").unwrap() }]);
+    commit.commit().unwrap();
+    assert_eq!(code(insert(&tx("h1", 1, "c3"), 1, &["--replace-pending"])).as_deref(), Some("conflict"));
+
+    // Receipts are never taken from the request.
+    let receipt = TransactionReceipt {
+      hash: "h1".into(),
+      program_id: "p".into(),
+      status: TX_FAILED,
+      timestamp: now,
+      error_text: String::new(),
+      data: String::new(),
+    };
+    let mut args = insert_args(&tx("h3", 2, "c1"), 1, &[]);
+    args[2] = json!({ "transaction": tx("h3", 2, "c1"), "receipt": receipt }).to_string();
+    assert_eq!(code(Transaction::insert(&args, now)).as_deref(), Some("receipt_not_allowed"));
+    assert!(Transaction::find("h3").is_none());
+    assert_eq!(TransactionReceipt::find("h1").map(|receipt| receipt.status), None);
+
+    let mut commit = StateCommit::new();
+    commit.put(&[Genesis { hash: "g".into(), chain_id: "7".into(), timestamp: 1, roots: Vec::new(), spec: "{}".into() }]);
+    commit.commit().unwrap();
+    assert_eq!(code(insert(&tx("h3", 2, "c1"), 1, &[])).as_deref(), Some("wrong_chain"));
+    assert!(insert(&Transaction { chain_id: "7".into(), ..tx("h3", 2, "c1") }, 1, &[]).success);
+  });
+}
@@ -1,8 +1,7 @@
-use std::collections::HashMap;
-
 use rlp_derive::{RlpEncodable, RlpDecodable};
 use serde::{Serialize, Deserialize};
-use serde_json::{Value, Number};
+
+use crate::{get_trie_results, record::Record};
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Debug, Clone)]
 pub struct TransactionReceipt {
@@ -14,16 +13,23 @@ pub struct TransactionReceipt {
     pub data: String,
 }
 
-impl From<TransactionReceipt> for HashMap<String, Value> {
-  fn from(r: TransactionReceipt) -> Self {
-    let mut map = HashMap::new();
-    map.insert("hash".to_string(), Value::String(r.hash));
-    map.insert("program_id".to_string(), Value::String(r.program_id));
-    map.insert("error_text".to_string(), Value::String(r.error_text));
-    map.insert("data".to_string(), Value::String(r.data));
-    map.insert("timestamp".to_string(), Value::Number(Number::from(r.timestamp)));
-    map.insert("status".to_string(), Value::Number(Number::from(r.status)));
+impl Record for TransactionReceipt {
+  const CONFIG_PREFIX: &'static str = "TX_RECEIPT";
+
+  fn primary_key(&self) -> Vec<u8> {
+    self.hash.as_bytes().to_vec()
+  }
+
+  fn prepare(&mut self) -> Result<(), String> {
+    Err("Receipts are only written with their transaction".to_string())
+  }
+}
 
-    map
+impl TransactionReceipt {
+  pub fn find(hash: &str) -> Option<TransactionReceipt> {
+    get_trie_results(&Self::trie_name(), &Self::db_path(), Some(hash.to_string()))
+      .iter()
+      .filter_map(|val| Self::decode_record(val).ok())
+      .find(|receipt| receipt.hash == hash)
   }
-}
\ No newline at end of file
+}
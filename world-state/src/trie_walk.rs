@@ -0,0 +1,202 @@
+use std::fmt;
+
+use hash_db::Hasher;
+use keccak_hasher::{KeccakHasher, KeccakHash};
+use trie_db::{
+  NibbleVec, NodeCodec, TrieLayout,
+  node::{NodeHandlePlan, NodePlan, ValuePlan},
+};
+
+use crate::simple_trie::prefixed_key;
+
+/// A node reached while walking a trie, keyed the way `SimpleTrie` stores it.
+#[derive(Debug, Clone)]
+pub struct TrieNode {
+  pub db_key: Vec<u8>,
+  pub data: Vec<u8>,
+}
+
+/// Something wrong found on the way down from the root.
+#[derive(Debug, Clone)]
+pub enum NodeIssue {
+  Missing { db_key: Vec<u8>, hash: KeccakHash },
+  HashMismatch { db_key: Vec<u8>, expected: KeccakHash, actual: KeccakHash },
+  Undecodable { db_key: Vec<u8>, error: String },
+}
+
+impl fmt::Display for NodeIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NodeIssue::Missing { db_key, hash } =>
+        write!(f, "missing node {} (hash {})", hex::encode(db_key), hex::encode(hash)),
+      NodeIssue::HashMismatch { db_key, expected, actual } =>
+        write!(f, "hash mismatch at {}: expected {}, got {}", hex::encode(db_key), hex::encode(expected), hex::encode(actual)),
+      NodeIssue::Undecodable { db_key, error } =>
+        write!(f, "undecodable node {}: {}", hex::encode(db_key), error),
+    }
+  }
+}
+
+/// Everything reachable from a root: stored nodes, leaf values and issues.
+#[derive(Debug, Default)]
+pub struct TrieWalk {
+  pub nodes: Vec<TrieNode>,
+  pub values: Vec<(Vec<u8>, Vec<u8>)>,
+  pub issues: Vec<NodeIssue>,
+}
+
+enum Handle {
+  Hash(NibbleVec, KeccakHash),
+  Inline(NibbleVec, Vec<u8>),
+}
+
+/// Walk every node reachable from `root`, fetching stored nodes through `fetch`.
+///
+/// Unlike `TrieDBNodeIterator` this never stops at the first bad node: missing,
+/// corrupted and undecodable nodes are collected and their subtrees skipped.
+pub fn walk_trie<L, F>(root: &KeccakHash, fetch: F) -> TrieWalk
+where
+  L: TrieLayout<Hash = KeccakHasher>,
+  F: Fn(&[u8]) -> Option<Vec<u8>>,
+{
+  let mut walk = TrieWalk::default();
+
+  if root == &[0u8; 32] || root == &L::Codec::hashed_null_node() {
+    return walk;
+  }
+
+  let mut stack = vec![Handle::Hash(NibbleVec::new(), *root)];
+
+  while let Some(handle) = stack.pop() {
+    let (prefix, data, db_key) = match handle {
+      Handle::Hash(prefix, hash) => {
+        let db_key = prefixed_key::<KeccakHasher>(&hash, prefix.as_prefix());
+
+        let data = match fetch(&db_key) {
+          Some(data) => data,
+          None => {
+            walk.issues.push(NodeIssue::Missing { db_key, hash });
+            continue;
+          },
+        };
+
+        let actual = KeccakHasher::hash(&data);
+        if actual != hash {
+          walk.issues.push(NodeIssue::HashMismatch { db_key: db_key.clone(), expected: hash, actual });
+        }
+
+        walk.nodes.push(TrieNode { db_key: db_key.clone(), data: data.clone() });
+        (prefix, data, db_key)
+      },
+      Handle::Inline(prefix, data) => (prefix, data, Vec::new()),
+    };
+
+    let plan = match L::Codec::decode_plan(&data) {
+      Ok(plan) => plan,
+      Err(e) => {
+        walk.issues.push(NodeIssue::Undecodable { db_key, error: format!("{:?}", e) });
+        continue;
+      },
+    };
+
+    let mut key = prefix.clone();
+    let (value, children) = match &plan {
+      NodePlan::Empty => (None, None),
+      NodePlan::Leaf { partial, value } => {
+        key.append_partial(partial.build(&data).right());
+        (Some(value), None)
+      },
+      NodePlan::Extension { partial, child } => {
+        key.append_partial(partial.build(&data).right());
+        push_child(&mut stack, &mut walk, &data, &db_key, key.clone(), child);
+        (None, None)
+      },
+      NodePlan::Branch { value, children } => (value.as_ref(), Some(children)),
+      NodePlan::NibbledBranch { partial, value, children } => {
+        key.append_partial(partial.build(&data).right());
+        (value.as_ref(), Some(children))
+      },
+    };
+
+    if let Some(children) = children {
+      for (i, child) in children.iter().enumerate().rev() {
+        if let Some(child) = child {
+          let mut child_prefix = key.clone();
+          child_prefix.push(i as u8);
+          push_child(&mut stack, &mut walk, &data, &db_key, child_prefix, child);
+        }
+      }
+    }
+
+    if let Some(value) = value {
+      let (key_bytes, _) = key.as_prefix();
+      match value {
+        ValuePlan::Inline(range) => walk.values.push((key_bytes.to_vec(), data[range.clone()].to_vec())),
+        ValuePlan::Node(range) => {
+          let mut hash = [0u8; 32];
+          hash.copy_from_slice(&data[range.clone()]);
+          let value_key = prefixed_key::<KeccakHasher>(&hash, (key_bytes, None));
+
+          match fetch(&value_key) {
+            Some(value) => {
+              let actual = KeccakHasher::hash(&value);
+              if actual != hash {
+                walk.issues.push(NodeIssue::HashMismatch { db_key: value_key.clone(), expected: hash, actual });
+              }
+              walk.nodes.push(TrieNode { db_key: value_key, data: value.clone() });
+              walk.values.push((key_bytes.to_vec(), value));
+            },
+            None => walk.issues.push(NodeIssue::Missing { db_key: value_key, hash }),
+          }
+        },
+      }
+    }
+  }
+
+  walk
+}
+
+fn push_child(
+  stack: &mut Vec<Handle>,
+  walk: &mut TrieWalk,
+  data: &[u8],
+  db_key: &[u8],
+  prefix: NibbleVec,
+  child: &NodeHandlePlan,
+) {
+  match child {
+    NodeHandlePlan::Hash(range) => {
+      if range.len() != 32 {
+        walk.issues.push(NodeIssue::Undecodable {
+          db_key: db_key.to_vec(),
+          error: format!("child hash of {} bytes", range.len()),
+        });
+        return;
+      }
+      let mut hash = [0u8; 32];
+      hash.copy_from_slice(&data[range.clone()]);
+      stack.push(Handle::Hash(prefix, hash));
+    },
+    NodeHandlePlan::Inline(range) => stack.push(Handle::Inline(prefix, data[range.clone()].to_vec())),
+  }
+}
+
+/// Root and nodes, keyed the way `SimpleTrie` stores them, of a trie with
+/// `pairs` built in memory.
+#[cfg(test)]
+pub fn test_nodes<L: TrieLayout<Hash = KeccakHasher>>(pairs: &[(&[u8], &[u8])]) -> (KeccakHash, std::collections::HashMap<Vec<u8>, Vec<u8>>) {
+  use memory_db::{MemoryDB, PrefixedKey};
+  use trie_db::{DBValue, TrieDBMutBuilder, TrieMut};
+
+  let mut memdb = MemoryDB::<KeccakHasher, PrefixedKey<_>, DBValue>::new(L::Codec::empty_node());
+  let mut root = Default::default();
+  {
+    let mut trie = TrieDBMutBuilder::<L>::new(&mut memdb, &mut root).build();
+    for (key, value) in pairs {
+      trie.insert(key, value).expect("trie insertion failed");
+    }
+  }
+
+  let nodes = memdb.drain().into_iter().filter(|(_, (_, rc))| *rc > 0).map(|(key, (value, _))| (key, value)).collect();
+  (root, nodes)
+}
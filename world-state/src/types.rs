@@ -1,7 +1,5 @@
-use rlp::{Rlp, Decodable, DecoderError};
 use serde::{Serialize, Deserialize};
-
-use crate::{transaction::Transaction, cron::Cron, metadata::Metadata, metacontract::MetaContract, transaction_receipt::TransactionReceipt};
+use serde_json::{json, Value};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TrieResult {
@@ -9,23 +7,24 @@ pub struct TrieResult {
   pub result: Option<String>,
 }
 
-pub enum DecodableEnum {
-  Transaction(Result<Transaction, DecoderError>),
-  Cron(Result<Cron, DecoderError>),
-  Metadata(Result<Metadata, DecoderError>),
-  MetaContract(Result<MetaContract, DecoderError>),
-  Receipt(Result<TransactionReceipt, DecoderError>),
-}
-
-impl DecodableEnum {
-  pub fn decode(trie_key: &str, rlp: &Rlp) -> Option<Self> {
-    match trie_key {
-      "tx" => Some(DecodableEnum::Transaction(Transaction::decode(rlp))),
-      "cron" => Some(DecodableEnum::Cron(Cron::decode(rlp))),
-      "metadata" => Some(DecodableEnum::Metadata(Metadata::decode(rlp))),
-      "metacontract" => Some(DecodableEnum::MetaContract(MetaContract::decode(rlp))),
-      "receipt" => Some(DecodableEnum::Receipt(TransactionReceipt::decode(rlp))),
-      _ => None,
+impl TrieResult {
+  /// Failure with a machine readable `code`. `result` is a JSON object with the
+  /// code, a message and the fields of `details`, which can't replace either.
+  pub fn error(code: &str, message: String, details: Value) -> TrieResult {
+    let mut result = json!({ "code": code, "message": message });
+    if let (Value::Object(result), Value::Object(details)) = (&mut result, details) {
+      for (key, value) in details {
+        result.entry(key).or_insert(value);
+      }
     }
+
+    TrieResult { success: false, result: Some(result.to_string()) }
   }
-}
\ No newline at end of file
+
+  /// The `code` of an `error`.
+  #[cfg(test)]
+  pub fn code(&self) -> Option<String> {
+    let result: Value = serde_json::from_str(self.result.as_deref()?).ok()?;
+    result["code"].as_str().map(str::to_string)
+  }
+}
@@ -1,4 +1,4 @@
-use std::process::Command;
+use std::{process::Command, time::{SystemTime, UNIX_EPOCH}};
 
 pub fn curl(args: Vec<String>) -> String {
   let output = Command::new("curl")
@@ -30,4 +30,13 @@ pub fn trimmer(text: String) -> String {
   .trim_start()       // Trim leading whitespace
   .trim_end()
   .to_string()
-}
\ No newline at end of file
+}
+/// Milliseconds since the unix epoch.
+pub fn now_ms() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
+}
+
+/// Value following `flag` in `args`, e.g. `--now 1700000000000`.
+pub fn flag_value(args: &[String], flag: &str) -> Option<String> {
+  args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}